@@ -0,0 +1,97 @@
+//! Users and groups looked up through the system's name service, which covers accounts from
+//! LDAP, SSSD or Directory Services as well as the ones in `/etc/passwd` and `/etc/group`.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+use anyhow::{anyhow, Result};
+
+// Starting size of the buffer the entries' strings are written to, it grows when it's too small.
+const BUFFER_SIZE: usize = 1024;
+
+pub(crate) struct User {
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// The user named `name`, or None when there's no such user.
+pub(crate) fn user(name: &str) -> Result<Option<User>> {
+    let c_name = c_name(name)?;
+    let mut entry = MaybeUninit::<libc::passwd>::uninit();
+    let found = lookup(name, |buffer, result| unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), entry.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len(), result.cast())
+    })?;
+    Ok(found.map(|_| {
+        let entry = unsafe { entry.assume_init() };
+        let home = unsafe { CStr::from_ptr(entry.pw_dir) };
+        User {
+            uid: entry.pw_uid,
+            gid: entry.pw_gid,
+            home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+        }
+    }))
+}
+
+/// The id of the group named `name`, or None when there's no such group.
+pub(crate) fn group(name: &str) -> Result<Option<u32>> {
+    let c_name = c_name(name)?;
+    let mut entry = MaybeUninit::<libc::group>::uninit();
+    let found = lookup(name, |buffer, result| unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), entry.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len(), result.cast())
+    })?;
+    Ok(found.map(|_| unsafe { entry.assume_init() }.gr_gid))
+}
+
+/// Every group the user named `name` is a member of, including its primary group `gid`.
+pub(crate) fn groups(name: &str, gid: u32) -> Result<Vec<libc::gid_t>> {
+    let c_name = c_name(name)?;
+    let mut groups: Vec<GroupId> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe { libc::getgrouplist(c_name.as_ptr(), gid as GroupId, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups.into_iter().map(|group| group as libc::gid_t).collect());
+        }
+        // The count is set to the number of groups when the list is too short, on some systems.
+        let needed = (count as usize).max(groups.len() * 2);
+        if needed > 65536 {
+            return Err(anyhow!("Couldn't list the groups of '{}'", name));
+        }
+        groups.resize(needed, 0);
+    }
+}
+
+// macOS lists groups as plain ints.
+#[cfg(target_vendor = "apple")]
+type GroupId = libc::c_int;
+#[cfg(not(target_vendor = "apple"))]
+type GroupId = libc::gid_t;
+
+fn c_name(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| anyhow!("'{}' isn't a valid account name", name))
+}
+
+// Runs a reentrant lookup, retrying with a larger buffer for as long as the entry doesn't fit.
+// Returns None when the name service has no entry of that name.
+fn lookup<F>(name: &str, mut call: F) -> Result<Option<()>>
+where
+    F: FnMut(&mut [libc::c_char], *mut *mut libc::c_void) -> libc::c_int,
+{
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut result: *mut libc::c_void = ptr::null_mut();
+        match call(&mut buffer, &mut result) {
+            0 => return Ok(Some(()).filter(|_| !result.is_null())),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            // Some systems report a missing entry as an error instead of a null result.
+            libc::ENOENT | libc::ESRCH | libc::EBADF | libc::EPERM => return Ok(None),
+            code => return Err(anyhow!("Couldn't look up '{}': {}", name, io::Error::from_raw_os_error(code))),
+        }
+    }
+}
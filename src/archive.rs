@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::Deserialize;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::copy::CopyOptions;
+use crate::parser::*;
+use crate::utils::{staging_path, sync_parent};
+use crate::ChecksumAlgorithm;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Packs files into dated archives in the rule's destination instead of moving them there,
+/// such as `Logs/2024-05.tar.zst`, and removes the originals once the archive checks out.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivePolicy {
+    /// Only files last modified at least this many days ago are archived.
+    #[serde(default)]
+    pub older_than_days: u64,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Name of the archive, formatted from the file's modification date.
+    #[serde(default = "default_archive_name")]
+    pub name: String,
+}
+
+impl ArchivePolicy {
+    pub fn new(older_than_days: u64, format: ArchiveFormat) -> ArchivePolicy {
+        ArchivePolicy {
+            older_than_days,
+            format,
+            name: default_archive_name(),
+        }
+    }
+
+    /// Whether the file was last modified long enough ago to be archived, and its age in days.
+    pub fn is_old_enough(&self, file: &Path) -> Result<(bool, u64)> {
+        let age = self.age_in_days(file)?;
+        Ok((age >= self.older_than_days, age))
+    }
+
+    /// Whole days since the file was last modified.
+    pub fn age_in_days(&self, file: &Path) -> Result<u64> {
+        let age = SystemTime::now()
+            .duration_since(modified(file)?)
+            .unwrap_or_default();
+        Ok(age.as_secs() / SECONDS_PER_DAY)
+    }
+
+    /// Path of the file inside the archive it belongs in, the archive standing in for the
+    /// folder the rule would've moved it to.
+    pub fn archive_target(&self, source: &Path, target: &Path) -> Result<PathBuf> {
+        let date: DateTime<Local> = modified(source)?.into();
+        let mut name = String::new();
+        write!(name, "{}.{}", date.format(&self.name), self.format.extension())
+            .map_err(|_| anyhow!("Invalid archive name '{}'", self.name))?;
+        let filename = target.file_name().ok_or(anyhow!("No filename found"))?;
+        Ok(target.with_file_name(name).join(filename))
+    }
+
+    /// Adds the entries, each a file and the name it's stored under, to `archive`. The archive
+    /// is written anew once under a staging name and only replaces the old one after every entry
+    /// could be read back and the new ones match their source's hash. Removing the sources is
+    /// left to the caller.
+    pub fn add(&self, archive: &Path, entries: &[(PathBuf, String)], copy_options: &CopyOptions) -> Result<()> {
+        let existing = Some(archive).filter(|archive| archive.is_file());
+        let staging = staging_path(archive);
+
+        let written = match self.format {
+            ArchiveFormat::TarZst => write_tar_zst(existing, &staging, entries),
+            ArchiveFormat::Zip => write_zip(existing, &staging, entries),
+        }
+        .and_then(|_| self.verify(&staging, entries));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&staging);
+            return Err(err.context(format!("Couldn't add {} to {}", describe(entries), archive.display())));
+        }
+        if copy_options.fsync {
+            File::open(&staging)?.sync_all()?;
+        }
+        fs::rename(&staging, archive)
+            .and_then(|_| sync_parent(archive, copy_options))
+            .with_context(|| format!("Couldn't move {} into place", staging.display()))
+    }
+
+    // Reading every entry to the end checks the compressed stream and the zip checksums.
+    fn verify(&self, archive: &Path, entries: &[(PathBuf, String)]) -> Result<()> {
+        let algorithm = ChecksumAlgorithm::Sha256;
+        let mut expected = HashMap::new();
+        for (source, entry) in entries {
+            expected.insert(entry.as_str(), algorithm.hash_file(source)?);
+        }
+        let mut found = HashMap::new();
+        match self.format {
+            ArchiveFormat::TarZst => {
+                let mut reader = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+                for item in reader.entries()? {
+                    let mut item = item?;
+                    let path = item.path()?.to_string_lossy().into_owned();
+                    match expected.contains_key(path.as_str()) {
+                        true => found.insert(path, algorithm.hash_reader(&mut item)?),
+                        false => io::copy(&mut item, &mut io::sink()).map(|_| None)?,
+                    };
+                }
+            }
+            ArchiveFormat::Zip => {
+                let mut reader = ZipArchive::new(File::open(archive)?)?;
+                for index in 0..reader.len() {
+                    let mut item = reader.by_index(index)?;
+                    let name = item.name().to_string();
+                    match expected.contains_key(name.as_str()) {
+                        true => found.insert(name, algorithm.hash_reader(&mut item)?),
+                        false => io::copy(&mut item, &mut io::sink()).map(|_| None)?,
+                    };
+                }
+            }
+        }
+        for (entry, hash) in expected {
+            match found.get(entry) {
+                Some(found) if *found == hash => {}
+                Some(_) => return Err(anyhow!("{} in the archive doesn't match the original", entry)),
+                None => return Err(anyhow!("{} is missing from the archive", entry)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_tar_zst(existing: Option<&Path>, staging: &Path, entries: &[(PathBuf, String)]) -> Result<()> {
+    let mut builder = tar::Builder::new(zstd::Encoder::new(File::create(staging)?, 0)?);
+    if let Some(existing) = existing {
+        let mut reader = tar::Archive::new(zstd::Decoder::new(File::open(existing)?)?);
+        for item in reader.entries()? {
+            let mut item = item?;
+            let path = item.path()?.into_owned();
+            if let Some((_, entry)) = entries.iter().find(|(_, entry)| path == Path::new(entry)) {
+                return Err(anyhow!("The archive already holds a file named {}", entry));
+            }
+            let mut header = item.header().clone();
+            builder.append_data(&mut header, path, &mut item)?;
+        }
+    }
+    for (source, entry) in entries {
+        builder.append_path_with_name(source, entry)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn write_zip(existing: Option<&Path>, staging: &Path, entries: &[(PathBuf, String)]) -> Result<()> {
+    let mut writer = ZipWriter::new(File::create(staging)?);
+    if let Some(existing) = existing {
+        let mut reader = ZipArchive::new(File::open(existing)?)?;
+        for index in 0..reader.len() {
+            let item = reader.by_index_raw(index)?;
+            if let Some((_, entry)) = entries.iter().find(|(_, entry)| item.name() == entry) {
+                return Err(anyhow!("The archive already holds a file named {}", entry));
+            }
+            writer.raw_copy_file(item)?;
+        }
+    }
+    for (source, entry) in entries {
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(source.metadata()?.len() >= u32::MAX as u64);
+        let date: DateTime<Local> = modified(source)?.into();
+        // Zip dates start in 1980, older files keep the archive's default date.
+        if let Ok(date) = zip::DateTime::from_date_and_time(
+            date.year() as u16, date.month() as u8, date.day() as u8,
+            date.hour() as u8, date.minute() as u8, date.second() as u8,
+        ) {
+            options = options.last_modified_time(date);
+        }
+        writer.start_file(entry.as_str(), options)?;
+        io::copy(&mut File::open(source)?, &mut writer)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn describe(entries: &[(PathBuf, String)]) -> String {
+    match entries {
+        [(source, _)] => source.display().to_string(),
+        _ => format!("{} files", entries.len()),
+    }
+}
+
+fn modified(file: &Path) -> Result<SystemTime> {
+    file.metadata()
+        .and_then(|metadata| metadata.modified())
+        .map_err(|err| anyhow!("Couldn't read the modification time of {}: {}", file.display(), err))
+}
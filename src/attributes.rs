@@ -0,0 +1,144 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::Rule;
+
+const FINDER_TAGS_ATTRIBUTE: &str = "com.apple.metadata:_kMDItemUserTags";
+const FINDER_COLORS: [&str; 7] = ["Gray", "Green", "Purple", "Blue", "Yellow", "Red", "Orange"];
+// Linux only accepts attribute names in one of its namespaces.
+#[cfg(target_os = "linux")]
+const ATTRIBUTE_NAMESPACES: [&str; 4] = ["user.", "trusted.", "security.", "system."];
+const MAX_ATTRIBUTE_NAME_LENGTH: usize = 255;
+
+/// Extended attributes read from a file, so they can be put back on a copy of it.
+#[derive(Debug, Clone, Default)]
+pub struct ExtendedAttributes(Vec<(OsString, Vec<u8>)>);
+
+impl ExtendedAttributes {
+    /// Reads every extended attribute of `file`. Platforms and file systems without
+    /// extended attributes give an empty list.
+    pub fn read(file: &Path) -> Result<ExtendedAttributes> {
+        let mut attributes = vec![];
+        if let Ok(names) = sys::list(file) {
+            for name in names {
+                if let Some(value) = sys::get(file, &name)? {
+                    attributes.push((name, value));
+                }
+            }
+        }
+        Ok(ExtendedAttributes(attributes))
+    }
+
+    pub fn write(&self, file: &Path) -> Result<()> {
+        for (name, value) in &self.0 {
+            sys::set(file, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `name` can be used as the name of an extended attribute here.
+pub fn validate_attribute_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('\0') || name.len() > MAX_ATTRIBUTE_NAME_LENGTH {
+        return Err(anyhow!("'{}' isn't a valid extended attribute name", name));
+    }
+    #[cfg(target_os = "linux")]
+    if !ATTRIBUTE_NAMESPACES.iter().any(|namespace| name.len() > namespace.len() && name.starts_with(namespace)) {
+        return Err(anyhow!("The extended attribute '{}' has to start with a namespace such as user.", name));
+    }
+    Ok(())
+}
+
+/// Checks the names of the rule's attributes, and warns once about the ones this platform
+/// can't set, which the rule's files then go without.
+pub fn validate_rule_attributes(rule: &Rule) -> Result<()> {
+    for name in rule.xattrs.keys() {
+        validate_attribute_name(name)?;
+    }
+    if cfg!(not(unix)) && (rule.preserve_xattrs || !rule.xattrs.is_empty()) {
+        warning!("Extended attributes are only supported on Unix, rule '{}' doesn't set them", rule.title);
+    }
+    if cfg!(not(target_os = "macos")) && !rule.finder_tags.is_empty() {
+        warning!("Finder tags are only supported on macOS, rule '{}' doesn't tag its files", rule.title);
+    }
+    Ok(())
+}
+
+/// Sets the rule's custom attributes and Finder tags on a file that has reached its destination.
+pub fn set_rule_attributes(rule: &Rule, target: &Path) -> Result<()> {
+    for (name, value) in &rule.xattrs {
+        sys::set(target, &OsString::from(name), value.as_bytes())?;
+    }
+    if cfg!(target_os = "macos") && !rule.finder_tags.is_empty() {
+        sys::set(target, &OsString::from(FINDER_TAGS_ATTRIBUTE), finder_tags_plist(&rule.finder_tags).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Finder stores tags as `Name\n<color index>`, with the index only present for the color tags.
+fn finder_tags_plist(tags: &[String]) -> String {
+    let entries = tags.iter()
+        .map(|tag| match FINDER_COLORS.iter().position(|color| color.eq_ignore_ascii_case(tag)) {
+            Some(idx) => format!("{}\n{}", FINDER_COLORS[idx], idx + 1),
+            None => tag.clone(),
+        })
+        .map(|tag| format!("<string>{}</string>", escape_xml(&tag)))
+        .collect::<String>();
+    format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#,
+            r#"<plist version="1.0"><array>{}</array></plist>"#,
+        ),
+        entries
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::{OsStr, OsString};
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    pub fn list(file: &Path) -> Result<Vec<OsString>> {
+        Ok(xattr::list(file)?.collect())
+    }
+
+    pub fn get(file: &Path, name: &OsStr) -> Result<Option<Vec<u8>>> {
+        xattr::get(file, name)
+            .map_err(|err| anyhow!("Couldn't read attribute {:?} of {}: {}", name, file.display(), err))
+    }
+
+    pub fn set(file: &Path, name: &OsStr, value: &[u8]) -> Result<()> {
+        xattr::set(file, name, value)
+            .map_err(|err| anyhow!("Couldn't set attribute {:?} on {}: {}", name, file.display(), err))
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::ffi::{OsStr, OsString};
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    pub fn list(_file: &Path) -> Result<Vec<OsString>> {
+        Ok(vec![])
+    }
+
+    pub fn get(_file: &Path, _name: &OsStr) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    // Rules setting attributes were warned about when the configuration was read.
+    pub fn set(_file: &Path, _name: &OsStr, _value: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
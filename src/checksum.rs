@@ -0,0 +1,73 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn hash_file(&self, file: &Path) -> Result<String> {
+        let mut reader = File::open(file)
+            .map_err(|err| anyhow!("Couldn't open {} for hashing: {}", file.display(), err))?;
+        self.hash_reader(&mut reader)
+    }
+
+    pub fn hash_reader(&self, reader: &mut dyn Read) -> Result<String> {
+        match self {
+            ChecksumAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(reader, &mut hasher)?;
+                Ok(to_hex(&hasher.finalize()))
+            }
+        }
+    }
+
+    pub fn sidecar_path(&self, file: &Path) -> PathBuf {
+        let mut sidecar = file.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(self.extension());
+        PathBuf::from(sidecar)
+    }
+
+    /// Writes a `sha256sum`-compatible sidecar next to the file.
+    pub fn write_sidecar(&self, file: &Path, hash: &str) -> Result<PathBuf> {
+        let sidecar = self.sidecar_path(file);
+        let filename = file.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(anyhow!("No filename found"))?;
+        fs::write(&sidecar, format!("{}  {}\n", hash, filename))?;
+        Ok(sidecar)
+    }
+}
+
+/// Whether `target` exists and has the same size and SHA-256 hash as `source`.
+pub fn files_identical(source: &Path, target: &Path) -> Result<bool> {
+    if source == target {
+        return Ok(source.is_file());
+    }
+    let (Ok(source_metadata), Ok(target_metadata)) = (fs::metadata(source), fs::metadata(target)) else {
+        return Ok(false);
+    };
+    if !source_metadata.is_file() || !target_metadata.is_file() || source_metadata.len() != target_metadata.len() {
+        return Ok(false);
+    }
+    let algorithm = ChecksumAlgorithm::Sha256;
+    Ok(algorithm.hash_file(source)? == algorithm.hash_file(target)?)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
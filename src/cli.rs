@@ -1,23 +1,114 @@
 use anyhow::Result;
+
+use crate::output::{is_quiet, set_color, set_quiet};
 use atty::Stream;
-use clap::{Arg, ArgMatches, command, crate_authors, crate_description, crate_name, crate_version};
+use clap::{Arg, ArgAction, ArgMatches, Command, command, crate_authors, crate_description, crate_name, crate_version, value_parser};
 
 pub fn check_for_stdout_stream() {
-    if atty::is(Stream::Stdout) {
+    if atty::is(Stream::Stdout) && !is_quiet() {
         dont_disappear::enter_to_continue::default();
     }
 }
 
 pub fn get_configuration_file_option() -> Result<ArgMatches> {
     let argument_matches = get_matches()?;
+    apply_output_options(&argument_matches);
     argument_matches.get_one::<String>("config").unwrap();
     Ok(argument_matches)
 }
 
+// `colored` already honours NO_COLOR, the flag only forces it off.
+fn apply_output_options(argument_matches: &ArgMatches) {
+    set_quiet(argument_matches.get_flag("quiet"));
+    if argument_matches.get_flag("no-color") {
+        set_color(false);
+    }
+}
+
 const CONFIG: &str = "Read from a specific config file";
+const PROFILE: &str = "Read the named configuration from the profiles folder";
 const DRY: &str = "Run without moving any files";
 const ENTER: &str = "Don't wait for keypress after finishing";
+const PAUSE: &str = "Wait for a keypress after finishing";
+const QUIET: &str = "Only print errors";
+const NO_COLOR: &str = "Don't use colors in the output";
+const TIMING: &str = "Show how long each phase of the run and matching each rule took";
+const EXPLAIN_MATCHES: &str = "Show every rule checked against every file and why it did or didn't match";
+const WITH_PRESETS: &str = "Add the rules of these built-in presets (documents, images, audio, video, archives, installers)";
+const OUTPUT: &str = "How to show the planned operations: a log line per file, a tree of destination folders, or a diff of the renamed filenames";
+const NO_LOCK: &str = "Don't take the lock that prevents concurrent runs";
+const MIGRATE: &str = "Convert a legacy comic_sort configuration to the current format";
+const MIGRATE_INPUT: &str = "Legacy configuration file";
+const MIGRATE_OUTPUT: &str = "Where to write the migrated configuration";
+const QUARANTINE: &str = "Inspect files that failed too many times";
+const QUARANTINE_LIST: &str = "List quarantined files";
+const QUARANTINE_RELEASE: &str = "Allow a quarantined file to be processed again";
+const QUARANTINE_FILE: &str = "Original or quarantined path of the file";
+const ADD_RULE: &str = "Append a rule to the configuration";
+const RULE_TITLE: &str = "Title of the rule, also the default target folder";
+const RULE_PATTERN: &str = "Pattern matching the files, with the new filename marked by <>";
+const RULE_DIR: &str = "Target folder relative to the root, e.g. Documents/Bank";
+const RULE_ROOT: &str = "Index or name of the root";
+const RULE_COPY: &str = "Copy the files instead of moving them";
+const RULE_RANGE: &str = "Folder for a range of numbers, e.g. 1-50:Vol1, 101+:Vol3 or default:Misc";
+const RULE_RANGE_GROUP: &str = "Number or name of the pattern's group holding the number [default: 1]";
+const ONLY_TAGS: &str = "Only apply rules with at least one of these tags (comma separated)";
+const SKIP_TAGS: &str = "Don't apply rules with any of these tags (comma separated)";
+const ONLY_RULE: &str = "Only apply the rule with this title, can be repeated";
+const SINGLE: &str = "Sort one file or directory and exit, e.g. from a download manager's completion hook";
+const SINGLE_PATH: &str = "File or directory to sort";
+const SINGLE_CATEGORY: &str = "Only apply rules tagged with this category";
+const PROFILE_COMMAND: &str = "Work with the configurations in the profiles folder";
+const PROFILE_LIST: &str = "List the available profiles";
+const VERIFY: &str = "Report files in the roots that aren't named the way their rules would name them";
+const REORGANIZE: &str = "Apply the current rules to files already sorted into the roots";
+const REORGANIZE_ROOT: &str = "Only reorganize this root, by name or index";
+const REORGANIZE_APPLY: &str = "Move the files instead of only showing the plan";
+const LIST_RULES: &str = "Show every rule after patterns have been expanded";
+const TEST_CORPUS: &str = "Run a list of example filenames through the rules and report the ones that don't end up where expected";
+const TEST_CORPUS_FILE: &str = "File with one filename per line, optionally followed by ` -> ` and the expected destination (`-` for no match)";
+const TEST_CORPUS_JSON: &str = "Print the results as JSON";
+const EXPLAIN: &str = "Show how a rule would rename and move an example file";
+const EXPLAIN_RULE: &str = "Title of the rule";
+const EXPLAIN_FILENAME: &str = "Example filename";
+const RUN: &str = "Sort the download folder with a single rule given on the command line, without a configuration";
+const RUN_PATTERN: &str = "Pattern matching the files, optionally with the new filename marked by <>";
+const RUN_DEST: &str = "Folder to move the files into";
+const RUN_FROM: &str = "Folder to sort instead of the downloads folder";
+const EXPORT_RULES: &str = "Write rules to a rule pack that can be shared and imported elsewhere";
+const EXPORT_TAGS: &str = "Only export rules with at least one of these tags (comma separated)";
+const EXPORT_RULE: &str = "Only export the rule with this title, can be repeated";
+const EXPORT_OUTPUT: &str = "Where to write the rule pack instead of the standard output";
+const IMPORT_RULES: &str = "Merge the rules of a rule pack into the configuration";
+const IMPORT_FILE: &str = "Rule pack to import";
+const IMPORT_REPLACE: &str = "Replace existing rules with the same title without asking";
+const IMPORT_SKIP_EXISTING: &str = "Keep existing rules with the same title without asking";
+const SYSTEM: &str = "Sort the download folders of every user listed in the system configuration, as run by a service";
+const SERVICE: &str = "Set fsort up to run as a service";
+const SERVICE_INSTALL: &str = "Write a systemd unit and timer or a Windows Scheduled Task running fsort --system";
+const SERVICE_FORMAT: &str = "Kind of service to write";
+const SERVICE_INTERVAL: &str = "Minutes between runs";
+const SERVICE_OUTPUT: &str = "Folder to write the files into, the systemd units folder or the current folder by default";
+const SERVICE_PRINT: &str = "Print the files instead of writing them";
+const SERVICE_SYSTEM: &str = "System configuration the service reads";
+const REGISTER_SHELL: &str = "Add \"Sort with fsort\" to the context menu of files and folders, using the given configuration or profile";
+const UNREGISTER_SHELL: &str = "Remove \"Sort with fsort\" from the context menu";
+const WHENCE: &str = "Show where a sorted file came from and which rule moved it, as recorded with `provenance`";
+const WHENCE_FILE: &str = "Sorted file to look up";
 const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+#[cfg(windows)]
+pub(crate) const DEFAULT_SYSTEM_CONFIG_PATH: &str = r"C:\ProgramData\fsort\system.yaml";
+#[cfg(not(windows))]
+pub(crate) const DEFAULT_SYSTEM_CONFIG_PATH: &str = "/etc/fsort/system.yaml";
+#[cfg(windows)]
+const DEFAULT_SERVICE_FORMAT: &str = SERVICE_TASK;
+#[cfg(not(windows))]
+const DEFAULT_SERVICE_FORMAT: &str = SERVICE_SYSTEMD;
+pub(crate) const SERVICE_SYSTEMD: &str = "systemd";
+pub(crate) const SERVICE_TASK: &str = "task";
+const OUTPUT_LOG: &str = "log";
+pub(crate) const OUTPUT_TREE: &str = "tree";
+pub(crate) const OUTPUT_DIFF: &str = "diff";
 
 pub fn get_matches() -> Result<ArgMatches> {
 
@@ -26,8 +117,16 @@ pub fn get_matches() -> Result<ArgMatches> {
         .short('c')
         .long("config")
         .help(CONFIG)
+        .global(true)
         .default_value(DEFAULT_CONFIG_PATH);
 
+    // define arg for reading a named config from the profiles folder
+    let arg_profile = Arg::new("profile")
+        .short('p')
+        .long("profile")
+        .help(PROFILE)
+        .global(true);
+
     // define arg for dry run
     let arg_dry = Arg::new("dry")
         .short('n')
@@ -35,20 +134,224 @@ pub fn get_matches() -> Result<ArgMatches> {
         .help(DRY)
         .num_args(0);
 
+    // kept for existing scripts, not waiting is the default now
     let arg_key = Arg::new("key")
         .short('k')
         .long("key")
         .help(ENTER)
+        .hide(true)
+        .num_args(0);
+
+    let arg_pause = Arg::new("pause")
+        .long("pause")
+        .help(PAUSE)
+        .num_args(0)
+        .conflicts_with("key");
+
+    let arg_quiet = Arg::new("quiet")
+        .short('q')
+        .long("quiet")
+        .help(QUIET)
+        .global(true)
+        .num_args(0);
+
+    let arg_no_color = Arg::new("no-color")
+        .long("no-color")
+        .help(NO_COLOR)
+        .global(true)
         .num_args(0);
 
+    let arg_no_lock = Arg::new("no-lock")
+        .long("no-lock")
+        .help(NO_LOCK)
+        .num_args(0);
+
+    let arg_timing = Arg::new("timing")
+        .long("timing")
+        .help(TIMING)
+        .num_args(0);
+
+    let arg_explain_matches = Arg::new("explain-matches")
+        .long("explain-matches")
+        .help(EXPLAIN_MATCHES)
+        .num_args(0);
+
+    let arg_system = Arg::new("system")
+        .long("system")
+        .help(SYSTEM)
+        .num_args(0..=1)
+        .default_missing_value(DEFAULT_SYSTEM_CONFIG_PATH)
+        .conflicts_with_all(["config", "profile"]);
+
+    let arg_output = Arg::new("output")
+        .long("output")
+        .help(OUTPUT)
+        .value_parser([OUTPUT_LOG, OUTPUT_TREE, OUTPUT_DIFF])
+        .default_value(OUTPUT_LOG);
+
+    let arg_with_presets = Arg::new("with-presets")
+        .long("with-presets")
+        .help(WITH_PRESETS)
+        .value_delimiter(',');
+
+    let arg_only_tags = Arg::new("only-tags")
+        .long("only-tags")
+        .help(ONLY_TAGS)
+        .value_delimiter(',');
+
+    let arg_skip_tags = Arg::new("skip-tags")
+        .long("skip-tags")
+        .help(SKIP_TAGS)
+        .value_delimiter(',');
+
+    let arg_only_rule = Arg::new("only-rule")
+        .long("only-rule")
+        .help(ONLY_RULE)
+        .action(ArgAction::Append);
+
+    let migrate_command = Command::new("migrate-config")
+        .about(MIGRATE)
+        .arg(Arg::new("input").help(MIGRATE_INPUT).required(true))
+        .arg(Arg::new("output").short('o').long("output").help(MIGRATE_OUTPUT));
+
+    let quarantine_command = Command::new("quarantine")
+        .about(QUARANTINE)
+        .subcommand(Command::new("list").about(QUARANTINE_LIST))
+        .subcommand(Command::new("release")
+            .about(QUARANTINE_RELEASE)
+            .arg(Arg::new("file").help(QUARANTINE_FILE).required(true)));
+
+    let add_rule_command = Command::new("add-rule")
+        .about(ADD_RULE)
+        .arg(Arg::new("title").long("title").help(RULE_TITLE).required(true))
+        .arg(Arg::new("pattern").long("pattern").help(RULE_PATTERN).required(true))
+        .arg(Arg::new("dir").long("dir").help(RULE_DIR))
+        .arg(Arg::new("root").long("root").help(RULE_ROOT))
+        .arg(Arg::new("copy").long("copy").help(RULE_COPY).num_args(0))
+        .arg(Arg::new("range").long("range").help(RULE_RANGE).action(ArgAction::Append))
+        .arg(Arg::new("range-group").long("range-group").help(RULE_RANGE_GROUP).requires("range"));
+
+    let single_command = Command::new("single")
+        .about(SINGLE)
+        .arg(Arg::new("path").help(SINGLE_PATH).required(true))
+        .arg(Arg::new("category").long("category").help(SINGLE_CATEGORY))
+        .arg(arg_dry.clone())
+        .arg(arg_no_lock.clone())
+        .arg(arg_timing.clone())
+        .arg(arg_explain_matches.clone())
+        .arg(arg_with_presets.clone())
+        .arg(arg_output.clone());
+
+    let run_command = Command::new("run")
+        .about(RUN)
+        .arg(Arg::new("pattern").long("pattern").help(RUN_PATTERN).required(true))
+        .arg(Arg::new("dest").long("dest").help(RUN_DEST).required(true))
+        .arg(Arg::new("from").long("from").help(RUN_FROM))
+        .arg(Arg::new("copy").long("copy").help(RULE_COPY).num_args(0))
+        .arg(arg_dry.clone())
+        .arg(arg_no_lock.clone());
+
+    let profile_command = Command::new("profile")
+        .about(PROFILE_COMMAND)
+        .subcommand(Command::new("list").about(PROFILE_LIST));
+
+    let service_command = Command::new("service")
+        .about(SERVICE)
+        .subcommand_required(true)
+        .subcommand(Command::new("install")
+            .about(SERVICE_INSTALL)
+            .arg(Arg::new("format")
+                .long("format")
+                .help(SERVICE_FORMAT)
+                .value_parser([SERVICE_SYSTEMD, SERVICE_TASK])
+                .default_value(DEFAULT_SERVICE_FORMAT))
+            .arg(Arg::new("interval")
+                .long("interval")
+                .help(SERVICE_INTERVAL)
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("15"))
+            .arg(Arg::new("system").long("system").help(SERVICE_SYSTEM).default_value(DEFAULT_SYSTEM_CONFIG_PATH))
+            .arg(Arg::new("output").short('o').long("output").help(SERVICE_OUTPUT))
+            .arg(Arg::new("print").long("print").help(SERVICE_PRINT).num_args(0)));
+
+    let register_shell_command = Command::new("register-shell").about(REGISTER_SHELL);
+
+    let unregister_shell_command = Command::new("unregister-shell").about(UNREGISTER_SHELL);
+
+    let verify_command = Command::new("verify").about(VERIFY);
+
+    let whence_command = Command::new("whence")
+        .about(WHENCE)
+        .arg(Arg::new("file").help(WHENCE_FILE).required(true));
+
+    let reorganize_command = Command::new("reorganize")
+        .about(REORGANIZE)
+        .arg(Arg::new("root").long("root").help(REORGANIZE_ROOT))
+        .arg(Arg::new("apply").long("apply").help(REORGANIZE_APPLY).num_args(0))
+        .arg(arg_no_lock.clone());
+
+    let export_rules_command = Command::new("export-rules")
+        .about(EXPORT_RULES)
+        .arg(Arg::new("tags").long("tags").help(EXPORT_TAGS).value_delimiter(','))
+        .arg(Arg::new("rule").long("rule").help(EXPORT_RULE).action(ArgAction::Append))
+        .arg(Arg::new("output").short('o').long("output").help(EXPORT_OUTPUT));
+
+    let import_rules_command = Command::new("import-rules")
+        .about(IMPORT_RULES)
+        .arg(Arg::new("file").help(IMPORT_FILE).required(true))
+        .arg(Arg::new("replace").long("replace").help(IMPORT_REPLACE).num_args(0))
+        .arg(Arg::new("skip-existing").long("skip-existing").help(IMPORT_SKIP_EXISTING).num_args(0).conflicts_with("replace"));
+
+    let list_rules_command = Command::new("list-rules").about(LIST_RULES);
+
+    let test_corpus_command = Command::new("test-corpus")
+        .about(TEST_CORPUS)
+        .arg(Arg::new("file").help(TEST_CORPUS_FILE).required(true))
+        .arg(Arg::new("json").long("json").help(TEST_CORPUS_JSON).num_args(0));
+
+    let explain_command = Command::new("explain")
+        .about(EXPLAIN)
+        .arg(Arg::new("rule").help(EXPLAIN_RULE).required(true))
+        .arg(Arg::new("filename").help(EXPLAIN_FILENAME).required(true));
+
     let matches = command!()
         .author(crate_authors!())
         .about(crate_description!())
         .name(crate_name!())
         .version(crate_version!())
         .arg(arg_config)
+        .arg(arg_profile)
         .arg(arg_dry)
         .arg(arg_key)
+        .arg(arg_pause)
+        .arg(arg_quiet)
+        .arg(arg_no_color)
+        .arg(arg_no_lock)
+        .arg(arg_timing)
+        .arg(arg_explain_matches)
+        .arg(arg_system)
+        .arg(arg_with_presets)
+        .arg(arg_output)
+        .arg(arg_only_tags)
+        .arg(arg_skip_tags)
+        .arg(arg_only_rule)
+        .subcommand(migrate_command)
+        .subcommand(quarantine_command)
+        .subcommand(add_rule_command)
+        .subcommand(export_rules_command)
+        .subcommand(import_rules_command)
+        .subcommand(list_rules_command)
+        .subcommand(explain_command)
+        .subcommand(single_command)
+        .subcommand(run_command)
+        .subcommand(profile_command)
+        .subcommand(service_command)
+        .subcommand(register_shell_command)
+        .subcommand(unregister_shell_command)
+        .subcommand(verify_command)
+        .subcommand(whence_command)
+        .subcommand(test_corpus_command)
+        .subcommand(reorganize_command)
         .get_matches();
 
     Ok(matches)
@@ -1,139 +1,755 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::fs::create_dir_all;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::ArgMatches;
 use colored::Colorize;
 use directories::ProjectDirs;
 use glob::glob;
-use regex::Regex;
+use regex::{RegexSet, SetMatches};
 use serde::Deserialize;
 use serde_yaml::from_str;
 
-use crate::cli::check_for_stdout_stream;
+use crate::cli::{check_for_stdout_stream, OUTPUT_DIFF, OUTPUT_TREE};
 use crate::parser::*;
-use crate::utils::generate_target;
-use crate::{Processor, Rule, RulesList, APPLICATION, ORGANIZATION, QUALIFIER, WILDCARD};
+use crate::directory::{directory_size, ignore_patterns, is_hidden_file, IGNORE_FILE};
+use crate::copy::{CopyOptions, CopyStrategy, DEFAULT_COPY_BUFFER_SIZE};
+use crate::utils::{cached_regex, generate_target, next_free_name, same_file, shard_target, validate_path_components, Sanitizer};
+use crate::report::{print_diff, print_tree};
+use crate::workflow::{process_with_config, target_directory, ActionKind, PlannedAction, SilentObserver};
+use crate::{
+    configuration_path, exit_code_for_run, set_quiet, ConflictStrategy, files_identical, ConfigurationError, DirectoryCreation, ExecutionPlan, FailedRule, FileOperation, HiddenFiles, LogLevel, ManifestPolicy, MissingConfiguration, OperationType, Phase, Plugin, Plugins, Preset, ProcessingOptions, ProvenanceStore, ProcessingOrder, QuarantinePolicy, RunBudget, Processor, StabilityCheck, RootReference, Rule, RuleFilter, RulesList, SanitizeMode, Timings, UnmatchedFiles, WorkflowObserver, MatchCheck,
+    APPLICATION, ORGANIZATION, QUALIFIER, WILDCARD,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Root {
+    pub name: Option<String>,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Roots(pub Vec<Root>);
+
+impl Roots {
+    pub fn push(&mut self, name: Option<String>, path: PathBuf) {
+        self.0.push(Root { name, path })
+    }
+
+    pub fn resolve(&self, reference: &RootReference) -> Result<&PathBuf> {
+        match reference {
+            RootReference::Index(idx) => self.0.get(*idx)
+                .map(|root| &root.path)
+                .ok_or(anyhow!("Root index {} is out of range, only {} roots are defined", idx, self.0.len())),
+            RootReference::Name(name) => self.0.iter()
+                .find(|root| root.name.as_ref() == Some(name))
+                .map(|root| &root.path)
+                .ok_or(anyhow!("No root named '{}' is defined", name)),
+        }
+    }
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    #[serde(deserialize_with = "deserialize_from_arrays_to_pathbuf_vec")]
-    pub root: Vec<PathBuf>,
+    #[serde(alias = "roots")]
+    #[serde(deserialize_with = "deserialize_roots")]
+    pub root: Roots,
     #[serde(deserialize_with = "deserialize_from_array_to_pathbuf")]
     pub download: PathBuf,
     #[serde(deserialize_with = "parse_rules")]
     pub rules: RulesList,
+    #[serde(default)]
+    pub sanitize: Option<SanitizeMode>,
+    #[serde(default = "default_sanitize_replacement")]
+    pub sanitize_replacement: String,
+    #[serde(default)]
+    pub max_filename_length: Option<usize>,
+    pub quarantine: Option<QuarantinePolicy>,
+    pub max_files_per_run: Option<usize>,
+    pub max_bytes_per_run: Option<u64>,
+    /// Remove directories in the download folder that are left empty after the run.
+    #[serde(default)]
+    pub cleanup_empty_dirs: bool,
+    #[serde(default)]
+    pub on_conflict: ConflictStrategy,
+    /// Wait for files that are still being written before sorting them.
+    pub stability_check: Option<StabilityCheck>,
+    /// Which missing destination folders a run creates.
+    #[serde(default)]
+    pub create_missing_dirs: DirectoryCreation,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_hidden_files")]
+    pub include_hidden: HiddenFiles,
+    pub processing_order: Option<ProcessingOrder>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_unmatched")]
+    pub unmatched: UnmatchedFiles,
+    /// Where and how a list of the files each run touched is written.
+    pub manifest: Option<ManifestPolicy>,
+    /// Records on each sorted file where it came from, for `fsort whence`.
+    pub provenance: Option<ProvenanceStore>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_plugins")]
+    pub plugins: Plugins,
+    /// Built-in rule sets added after the configured rules.
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+    #[serde(default)]
+    pub copy_strategy: CopyStrategy,
+    /// Bytes read and written at a time when copying.
+    pub copy_buffer_size: Option<usize>,
+    /// Flush copied and moved files to disk before they count as done.
+    #[serde(default)]
+    pub fsync: bool,
     #[serde(skip_deserializing)]
     pub files: Vec<PathBuf>,
+    /// Every rule's pattern in one set, so a file is only checked against the rules it can match.
+    #[serde(skip_deserializing)]
+    pub rule_patterns: Option<RegexSet>,
 }
 
 impl Config {
-    pub fn get_files(&mut self) -> Result<()> {
-        for file_path in glob(self.download.join(WILDCARD).to_str().unwrap())? {
-            self.files.insert(0, file_path?);
+    pub fn new<P: Into<PathBuf>>(download: P) -> Config {
+        Config {
+            root: Roots::default(),
+            download: download.into(),
+            rules: vec![],
+            sanitize: None,
+            sanitize_replacement: default_sanitize_replacement(),
+            max_filename_length: None,
+            quarantine: None,
+            max_files_per_run: None,
+            max_bytes_per_run: None,
+            cleanup_empty_dirs: false,
+            on_conflict: ConflictStrategy::default(),
+            stability_check: None,
+            create_missing_dirs: DirectoryCreation::default(),
+            include_hidden: HiddenFiles::default(),
+            processing_order: None,
+            unmatched: UnmatchedFiles::default(),
+            manifest: None,
+            provenance: None,
+            plugins: Plugins::default(),
+            presets: vec![],
+            copy_strategy: CopyStrategy::default(),
+            copy_buffer_size: None,
+            fsync: false,
+            files: vec![],
+            rule_patterns: None,
+        }
+    }
+
+    pub fn with_sanitize<S: Into<String>>(mut self, mode: SanitizeMode, replacement: S) -> Config {
+        self.sanitize = Some(mode);
+        self.sanitize_replacement = replacement.into();
+        self
+    }
+
+    pub fn with_root<P: Into<PathBuf>>(mut self, path: P) -> Config {
+        self.root.push(None, path.into());
+        self
+    }
+
+    pub fn with_named_root<S: Into<String>, P: Into<PathBuf>>(mut self, name: S, path: P) -> Config {
+        self.root.push(Some(name.into()), path.into());
+        self
+    }
+
+    pub fn with_rule(mut self, mut rule: Rule) -> Result<Config> {
+        if let Some(id) = rule.id.as_ref().filter(|id| self.rules.iter().any(|existing| existing.id.as_ref() == Some(*id))) {
+            return Err(anyhow!("More than one rule has the id '{}'", id));
+        }
+        self.rules.extend(map_patterns_to_rules(&mut rule)?);
+        Ok(self)
+    }
+
+    pub fn with_presets(mut self, presets: Vec<Preset>) -> Result<Config> {
+        for preset in presets {
+            if !self.presets.contains(&preset) {
+                self.presets.push(preset);
+            }
+        }
+        self.add_preset_rules()?;
+        Ok(self)
+    }
+
+    // A rule with the preset's title in the configuration replaces the preset.
+    fn add_preset_rules(&mut self) -> Result<()> {
+        for preset in &self.presets {
+            let mut rule = preset.rule();
+            if !self.rules.iter().any(|existing| existing.title == rule.title) {
+                self.rules.extend(map_patterns_to_rules(&mut rule)?);
+            }
         }
         Ok(())
     }
 
+    pub fn with_max_filename_length(mut self, max_filename_length: usize) -> Config {
+        self.max_filename_length = Some(max_filename_length);
+        self
+    }
+
+    pub fn with_quarantine(mut self, policy: QuarantinePolicy) -> Config {
+        self.quarantine = Some(policy);
+        self
+    }
+
+    pub fn with_run_limits(mut self, max_files: Option<usize>, max_bytes: Option<u64>) -> Config {
+        self.max_files_per_run = max_files;
+        self.max_bytes_per_run = max_bytes;
+        self
+    }
+
+    pub fn with_cleanup_empty_dirs(mut self, cleanup_empty_dirs: bool) -> Config {
+        self.cleanup_empty_dirs = cleanup_empty_dirs;
+        self
+    }
+
+    pub fn with_create_missing_dirs(mut self, creation: DirectoryCreation) -> Config {
+        self.create_missing_dirs = creation;
+        self
+    }
+
+    pub fn with_provenance(mut self, store: ProvenanceStore) -> Config {
+        self.provenance = Some(store);
+        self
+    }
+
+    pub fn with_on_conflict(mut self, strategy: ConflictStrategy) -> Config {
+        self.on_conflict = strategy;
+        self
+    }
+
+    pub fn with_stability_check(mut self, check: StabilityCheck) -> Config {
+        self.stability_check = Some(check);
+        self
+    }
+
+    pub fn with_include_hidden(mut self, include_hidden: HiddenFiles) -> Config {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    pub fn with_processing_order(mut self, order: ProcessingOrder) -> Config {
+        self.processing_order = Some(order);
+        self
+    }
+
+    pub fn with_unmatched(mut self, unmatched: UnmatchedFiles) -> Config {
+        self.unmatched = unmatched;
+        self
+    }
+
+    pub fn with_manifest(mut self, manifest: ManifestPolicy) -> Config {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    pub fn with_copy_options(mut self, strategy: CopyStrategy, buffer_size: Option<usize>, fsync: bool) -> Config {
+        self.copy_strategy = strategy;
+        self.copy_buffer_size = buffer_size;
+        self.fsync = fsync;
+        self
+    }
+
+    pub fn with_plugin<S: Into<String>, P: Plugin + 'static>(mut self, name: S, plugin: P) -> Config {
+        self.plugins.insert(name, Arc::new(plugin));
+        self
+    }
+
+    /// Collects the files in the download folder, returning the ones that couldn't be read
+    /// instead of failing on them.
+    pub fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        let mut unreadable = vec![];
+        let ignored = ignore_patterns(&self.download)?;
+        for file_path in glob(self.download.join(WILDCARD).to_str().unwrap())? {
+            match file_path {
+                Ok(file_path) if is_hidden_file(&file_path, self.include_hidden) => continue,
+                Ok(file_path) if file_path.ends_with(IGNORE_FILE) => continue,
+                Ok(file_path) if ignored.matched(&file_path, file_path.is_dir()).is_ignore() => continue,
+                Ok(file_path) => self.files.insert(0, file_path),
+                Err(err) if err.error().kind() == ErrorKind::PermissionDenied => unreadable.push(err.path().to_path_buf()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(unreadable)
+    }
+
     pub fn load(file: PathBuf) -> Result<Config> {
         let file_content = fs::read(file)?;
         let content_str = String::from_utf8(file_content)?;
-        let config: Config = from_str(&content_str)?;
+        let mut config: Config = from_str(&content_str)?;
+        config.add_preset_rules()?;
         Ok(config)
     }
 
-    pub fn process(&self, file: &Path, run_execution: bool) -> Result<()> {
-        let mut file_processor = Processor::new(file);
-        for rule in &self.rules {
-            if let Ok(applied_rule) = self.apply_rule(rule, &mut file_processor) {
-                println!(
+    pub fn process(
+        &self,
+        file: &Path,
+        run_execution: bool,
+        observer: &mut dyn WorkflowObserver,
+        budget: &mut RunBudget,
+        plan: &mut ExecutionPlan,
+        timings: &mut Timings,
+    ) -> Result<Vec<FileOperation>> {
+        let mut operations = vec![];
+        let mut file_processor = Processor::new(file)
+            .with_sanitizer(self.sanitizer())
+            .with_max_filename_length(self.max_filename_length)
+            .with_copy_options(self.copy_options());
+        let candidates = self.candidate_rules(file, timings);
+        for (index, rule) in self.rules.iter().enumerate() {
+            if file.is_dir() != rule.match_directories {
+                let detail = if rule.match_directories { "the rule sorts folders" } else { "the rule sorts files" };
+                observer.on_rule_checked(file, rule, &MatchCheck::new("type", false, detail));
+                continue;
+            }
+            if candidates.as_ref().is_some_and(|candidates| !candidates.matched(index)) {
+                let detail = format!("`{}` against `{}`", rule.old_pattern, file_processor.source_filename()?);
+                observer.on_rule_checked(file, rule, &MatchCheck::new("pattern", false, detail));
+                continue;
+            }
+            let mut applied_rule = match self.apply_rule(rule, &mut file_processor, observer, timings) {
+                Ok(Some(applied_rule)) => applied_rule,
+                Ok(None) => continue,
+                Err(err) => {
+                    observer.on_error(file, &err);
+                    return Err(err.context(FailedRule(rule.title.clone())));
+                }
+            };
+            let file_size = match rule.match_directories {
+                true => directory_size(file)?,
+                false => file.metadata().map(|metadata| metadata.len()).unwrap_or_default(),
+            };
+            if !budget.allows(rule, file_size) {
+                observer.on_rule_checked(file, rule, &MatchCheck::new("run limits", false, "a file or size limit is reached, deferred"));
+                budget.defer(file);
+                continue;
+            }
+            if !budget.record_match(rule, file) {
+                let detail = format!("already matched {} files", rule.max_matches_per_run.unwrap_or_default());
+                observer.on_rule_checked(file, rule, &MatchCheck::new("max_matches_per_run", false, detail));
+                if rule.log_level > LogLevel::Quiet {
+                    info!(
+                        "{} left in place, {} already matched {} files in this run.",
+                        applied_rule.source_filename()?.bold(),
+                        rule.title.bold().blue(),
+                        rule.max_matches_per_run.unwrap_or_default(),
+                    );
+                    info!();
+                }
+                continue;
+            }
+            observer.on_rule_matched(file, rule);
+            let is_logged = rule.log_level > LogLevel::Quiet;
+            let dry_run = run_execution || rule.dry_run;
+            let started = Instant::now();
+            if let Some(max_files) = rule.max_files_per_directory {
+                if let Err(err) = shard(&mut applied_rule, max_files, plan) {
+                    observer.on_error(file, &err);
+                    return Err(err.context(FailedRule(rule.title.clone())));
+                }
+            }
+            if is_logged {
+                info!(
                     "{file} found! Applying setup for {title}.",
                     file = applied_rule.source_filename()?.bold(),
                     title = rule.title.bold().blue(),
                 );
-                if applied_rule.is_changed()? {
-                    println!(
-                        "New filename: {}",
-                        applied_rule.target_filename()?.bold().red()
-                    )
+            }
+            if is_logged && applied_rule.is_changed()? {
+                info!(
+                    "New filename: {}",
+                    applied_rule.target_filename()?.bold().red()
+                )
+            }
+            if is_logged && rule.archive.is_some() {
+                let archive = applied_rule.target.parent().unwrap_or(&applied_rule.target);
+                info!("Archiving into {}", archive.display().to_string().bold().red());
+            }
+            if let Some(chained) = rule.then.as_deref().filter(|_| is_logged) {
+                info!("Then {} sorts it further once it's in place.", chained.title.bold().blue());
+            }
+            if applied_rule.source != applied_rule.target && same_file(&applied_rule.source, &applied_rule.target) {
+                let err = anyhow!("The destination {} is {} itself", applied_rule.target.display(), file.display());
+                observer.on_error(file, &err);
+                return Err(err.context(FailedRule(rule.title.clone())));
+            }
+            let is_sorted = files_identical(&applied_rule.source, &applied_rule.target)?;
+            let is_taken = !is_sorted && match rule.match_directories {
+                true => plan.is_planned(&applied_rule.target),
+                false => plan.is_taken(&applied_rule.target),
+            };
+            let is_moved = match is_taken {
+                false => true,
+                true => match self.resolve_conflict(&mut applied_rule, plan.targets()) {
+                    Ok(is_moved) => is_moved,
+                    Err(err) => {
+                        observer.on_error(file, &err);
+                        return Err(err.context(FailedRule(rule.title.clone())));
+                    }
+                },
+            };
+            let kind = if rule.archive.is_some() { OperationType::Archive } else { OperationType::Move };
+            let directory = target_directory(&applied_rule.target, kind);
+            let is_missing = self.create_missing_dirs == DirectoryCreation::Never
+                && directory.is_some_and(|directory| !directory.is_dir());
+            timings.record(Phase::PathGeneration, started);
+            let operation_type = if is_sorted {
+                if is_logged {
+                    info!("{}", "Already sorted, skipping.".yellow());
+                }
+                OperationType::Skipped
+            } else if !is_moved {
+                if is_logged {
+                    info!("{}", "Destination is taken, skipping.".yellow());
+                }
+                OperationType::Skipped
+            } else if is_missing {
+                if is_logged {
+                    let directory = directory.unwrap_or(&applied_rule.target).display().to_string();
+                    info!("{}", format!("{} doesn't exist and create_missing_dirs is never, skipping.", directory).yellow());
+                }
+                OperationType::Skipped
+            } else if rule.archive.is_some() {
+                OperationType::Archive
+            } else if rule.copy {
+                OperationType::Copy
+            } else {
+                OperationType::Move
+            };
+            if rule.log_level == LogLevel::Verbose {
+                info!("{:?} to {}", operation_type, applied_rule.target.display());
+            }
+            if is_logged && rule.dry_run && !run_execution && operation_type != OperationType::Skipped {
+                info!("{}", "Dry run for this rule, nothing was changed.".yellow());
+            }
+            if is_logged {
+                info!();
+            }
+            let operation = FileOperation {
+                source: applied_rule.source.clone(),
+                target: applied_rule.target.clone(),
+                rule: rule.title.clone(),
+                rule_id: rule.id.clone(),
+                operation: operation_type,
+            };
+            observer.on_operation_planned(&operation);
+            if operation_type != OperationType::Skipped {
+                budget.consume(rule, file_size);
+                plan.reserve(&applied_rule.target);
+                if rule.then.is_some() {
+                    plan.chain(index, file, &applied_rule.target);
                 }
-                println!();
-                if !run_execution {
-                    applied_rule.perform_file_action(rule.copy)?;
+            }
+            if !dry_run && operation_type != OperationType::Skipped {
+                plan.push(PlannedAction {
+                    file: file.to_path_buf(),
+                    operation: operation.clone(),
+                    kind: ActionKind::Rule(applied_rule.clone(), index),
+                });
+            }
+            operations.push(operation);
+            if operation_type != OperationType::Skipped && !rule.match_directories {
+                let companion_targets = rule.companion_targets(file, &applied_rule.target);
+                for (companion, companion_target) in companion_targets.iter().cloned() {
+                    let operation = FileOperation {
+                        source: companion,
+                        target: companion_target,
+                        rule: rule.title.clone(),
+                        rule_id: rule.id.clone(),
+                        operation: operation_type,
+                    };
+                    if is_logged {
+                        info!("Taking along {}", operation.source.display());
+                    }
+                    observer.on_operation_planned(&operation);
+                    if !dry_run {
+                        plan.push(PlannedAction {
+                            file: file.to_path_buf(),
+                            operation: operation.clone(),
+                            kind: ActionKind::Companion,
+                        });
+                    }
+                    plan.reserve(&operation.target);
+                    operations.push(operation);
+                }
+                if is_logged && !companion_targets.is_empty() {
+                    info!();
                 }
             }
         }
 
-        Ok(())
+        Ok(operations)
     }
 
-    fn apply_rule(&self, rule: &Rule, processor: &mut Processor) -> Result<Processor> {
-        let root_path = &self.root[rule.root];
-        let pattern = Regex::new(rule.old_pattern.as_str())?;
-        if pattern.is_match(processor.source_filename()?) {
-            let directory = match &rule.directory {
-                None => PathBuf::from(&rule.title),
-                Some(dir) => dir.to_owned(),
-            };
-            processor.create_and_set_target_directory(root_path, &directory)?;
-            processor.target = generate_target(processor, rule, &processor.target)?;
-            Ok(processor.to_owned())
-        } else {
-            Err(anyhow!("Pattern doesn't match."))
+    // The set is built by `prepare_rules`, rules added or removed after that make it stale.
+    fn candidate_rules(&self, file: &Path, timings: &mut Timings) -> Option<SetMatches> {
+        let started = Instant::now();
+        let set = self.rule_patterns.as_ref().filter(|set| set.len() == self.rules.len())?;
+        let candidates = set.matches(file.file_name()?.to_str()?);
+        timings.record(Phase::Match, started);
+        Some(candidates)
+    }
+
+    /// Files in the download folder that will be moved along with another file that
+    /// a rule with `companions` or a `sidecar` matches, so they aren't sorted on their own.
+    pub fn companion_files(&self) -> Result<HashSet<PathBuf>> {
+        let mut companions = HashSet::new();
+        for rule in self.rules.iter().filter(|rule| !rule.companions.is_empty() || rule.sidecar.is_some()) {
+            let pattern = cached_regex(&rule.old_pattern)?;
+            for file in &self.files {
+                if file.file_name().and_then(|name| name.to_str()).is_some_and(|name| pattern.is_match(name)) {
+                    companions.extend(rule.companions_of(file));
+                }
+            }
         }
+        Ok(companions)
     }
-}
 
+    /// The configuration a rule chained with `then` runs in, with that rule alone.
+    pub(crate) fn chained_stage(&self, rule: &Rule) -> Config {
+        Config {
+            rules: vec![rule.clone()],
+            files: vec![],
+            rule_patterns: None,
+            ..self.clone()
+        }
+    }
 
-pub fn perform_processing_based_on_configuration(argument_matches: ArgMatches) -> Result<()> {
-    let configuration_file_path = PathBuf::from(argument_matches.get_one::<String>("config").unwrap());
-    let configuration_file = read_or_create(configuration_file_path)?;
+    /// Applies the conflict strategy to a processor whose target is taken, returning whether
+    /// the file should still be moved.
+    fn resolve_conflict(&self, processor: &mut Processor, planned_targets: &HashSet<PathBuf>) -> Result<bool> {
+        let is_planned = planned_targets.contains(&processor.target);
+        match self.on_conflict {
+            ConflictStrategy::Overwrite if !is_planned => Ok(true),
+            ConflictStrategy::Skip => Ok(false),
+            ConflictStrategy::Rename => {
+                processor.target = next_free_name(&processor.target, planned_targets, self.max_filename_length)?;
+                info!("Destination is taken, using {}", processor.target_filename()?.bold().red());
+                Ok(true)
+            }
+            ConflictStrategy::Overwrite | ConflictStrategy::Error => Err(anyhow!(
+                "Conflict: {} {}",
+                processor.target.display(),
+                if is_planned { "is the destination of another file in this run" } else { "already exists" }
+            )),
+        }
+    }
 
-    let mut configuration = Config::load(configuration_file)?;
-    prepare_configuration(&mut configuration)?;
+    pub(crate) fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            strategy: self.copy_strategy,
+            buffer_size: self.copy_buffer_size.unwrap_or(DEFAULT_COPY_BUFFER_SIZE),
+            fsync: self.fsync,
+        }
+    }
 
-    execute_based_on_configuration(&configuration, argument_matches.get_flag("dry"))?;
+    fn sanitizer(&self) -> Option<Sanitizer> {
+        self.sanitize.map(|mode| Sanitizer {
+            mode,
+            replacement: self.sanitize_replacement.clone(),
+        })
+    }
 
-    if !argument_matches.get_flag("key") {
-        check_for_stdout_stream();
+    /// Shows where `filename` would end up under the rule, without touching the file system.
+    ///
+    /// Transformative functions aren't resolved, as they need to look at existing folders.
+    pub fn explain(&self, rule: &Rule, filename: &str) -> Result<Option<PathBuf>> {
+        let mut processor = Processor::new(Path::new(filename))
+            .with_sanitizer(self.sanitizer())
+            .with_max_filename_length(self.max_filename_length);
+        if !self.set_target_directory(rule, &mut processor, &mut SilentObserver)? {
+            return Ok(None);
+        }
+        let target = processor.make_destination(&rule.new_pattern, None, rule)?;
+        Ok(Some(self.transform_target(rule, &processor.source, target)?))
     }
 
-    Ok(())
-}
+    fn apply_rule(
+        &self,
+        rule: &Rule,
+        processor: &mut Processor,
+        observer: &mut dyn WorkflowObserver,
+        timings: &mut Timings,
+    ) -> Result<Option<Processor>> {
+        let started = Instant::now();
+        let is_match = self.set_target_directory(rule, processor, observer)?;
+        timings.record_match(&rule.title, started);
+        if !is_match {
+            return Ok(None);
+        }
+        let started = Instant::now();
+        let target = generate_target(processor, rule, &processor.target, self.create_missing_dirs)?;
+        processor.target = self.transform_target(rule, &processor.source, target)?;
+        if let Some(archive) = &rule.archive {
+            processor.target = archive.archive_target(&processor.source, &processor.target)?;
+        }
+        validate_path_components(&processor.target)?;
+        timings.record(Phase::PathGeneration, started);
+        Ok(Some(processor.to_owned()))
+    }
 
-fn prepare_configuration(configuration: &mut Config) -> Result<()> {
-    configuration.get_files().expect("Couldn't read the download folder");
+    fn transform_target(&self, rule: &Rule, source: &Path, mut target: PathBuf) -> Result<PathBuf> {
+        for plugin in self.plugins.for_rule(rule) {
+            target = plugin.transform(source, rule, target)?;
+        }
+        Ok(target)
+    }
 
-    for mapping in &mut configuration.rules {
-        mapping.make_patterns()?;
+    fn set_target_directory(&self, rule: &Rule, processor: &mut Processor, observer: &mut dyn WorkflowObserver) -> Result<bool> {
+        let source = processor.source.clone();
+        let mut check = |condition, passed, detail: String| {
+            observer.on_rule_checked(&source, rule, &MatchCheck::new(condition, passed, detail));
+            passed
+        };
+        let root_path = self.root.resolve(&rule.root_reference())?;
+        let pattern = cached_regex(&rule.old_pattern)?;
+        let filename = processor.source_filename()?;
+        if !check("pattern", pattern.is_match(filename), format!("`{}` against `{}`", rule.old_pattern, filename)) {
+            return Ok(false);
+        }
+        for (name, plugin) in rule.plugins.iter().zip(self.plugins.for_rule(rule)) {
+            if !check("plugin", plugin.matches(&source, rule)?, format!("{} decides", name)) {
+                return Ok(false);
+            }
+        }
+        if let Some(archive) = &rule.archive {
+            let (is_old_enough, age) = archive.is_old_enough(&source)?;
+            let detail = format!("modified {} days ago, archived after {}", age, archive.older_than_days);
+            if !check("archive age", is_old_enough, detail) {
+                return Ok(false);
+            }
+        }
+        processor.metadata = match &rule.sidecar {
+            None => BTreeMap::new(),
+            Some(sidecar) => {
+                let values = sidecar.read(&source)?;
+                let mut is_match = true;
+                for (passed, detail) in sidecar.matches(&values)? {
+                    is_match &= check("sidecar", passed, detail);
+                }
+                if !is_match {
+                    return Ok(false);
+                }
+                values
+            }
+        };
+        let directory = match &rule.directory {
+            None => PathBuf::from(&rule.title),
+            Some(dir) => dir.to_owned(),
+        };
+        let mut directory_case = None;
+        for config_processor in rule.processors.iter().flatten() {
+            if config_processor.case_directories && config_processor.applies_to(processor.source_filename()?)? {
+                directory_case = config_processor.case.or(directory_case);
+            }
+        }
+        processor.set_target_directory(root_path, &directory, directory_case)?;
+        Ok(true)
     }
+}
 
+
+// Moves the target into a hash-named subfolder once its folder has reached `max_files`.
+fn shard(processor: &mut Processor, max_files: usize, plan: &mut ExecutionPlan) -> Result<()> {
+    processor.target = shard_target(&processor.target, max_files, |directory| plan.file_count(directory))?;
     Ok(())
 }
 
-fn execute_based_on_configuration(configuration: &Config, is_dry_run: bool) -> Result<()> {
-    for file in &configuration.files {
-        configuration.process(file, is_dry_run)?;
+pub fn perform_processing_based_on_configuration(argument_matches: ArgMatches) -> Result<u8> {
+    let options = ProcessingOptions::default()
+        .dry_run(argument_matches.get_flag("dry"))
+        .use_lock(!argument_matches.get_flag("no-lock"))
+        .timing(argument_matches.get_flag("timing"))
+        .explain_matches(argument_matches.get_flag("explain-matches"))
+        .rule_filter(RuleFilter {
+            only_tags: get_values(&argument_matches, "only-tags"),
+            skip_tags: get_values(&argument_matches, "skip-tags"),
+            only_rules: get_values(&argument_matches, "only-rule"),
+        });
+
+    let exit_code = run_processing(&argument_matches, options)?;
+
+    if argument_matches.get_flag("pause") {
+        check_for_stdout_stream();
     }
 
-    Ok(())
+    Ok(exit_code)
 }
 
-pub fn read_or_create(config: PathBuf) -> Result<PathBuf> {
-    if !&config.exists() {
-        create_config_if_not_exists(config)
-    } else {
-        Ok(config)
-    }
+/// Runs the rules against a single file or directory, as called from a download manager hook.
+pub fn process_single_file(argument_matches: &ArgMatches) -> Result<u8> {
+    let path = PathBuf::from(argument_matches.get_one::<String>("path").unwrap());
+    let options = ProcessingOptions::default()
+        .dry_run(argument_matches.get_flag("dry"))
+        .use_lock(!argument_matches.get_flag("no-lock"))
+        .timing(argument_matches.get_flag("timing"))
+        .explain_matches(argument_matches.get_flag("explain-matches"))
+        .single_file(path)
+        .rule_filter(RuleFilter {
+            only_tags: get_values(argument_matches, "category"),
+            ..RuleFilter::default()
+        });
+
+    run_processing(argument_matches, options)
 }
 
-fn create_config_if_not_exists(config: PathBuf) -> Result<PathBuf> {
-    let folder = ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION).unwrap();
-    if !folder.config_dir().exists() {
-        create_dir_all(folder.config_dir())?;
+fn run_processing(argument_matches: &ArgMatches, options: ProcessingOptions) -> Result<u8> {
+    let configuration_file = configuration_path(argument_matches)?;
+
+    let presets = get_values(argument_matches, "with-presets")
+        .iter()
+        .map(|name| name.parse())
+        .collect::<Result<Vec<Preset>>>()
+        .context(ConfigurationError)?;
+    let configuration = Config::load(configuration_file)
+        .and_then(|configuration| configuration.with_presets(presets))
+        .context(ConfigurationError)?;
+    let output = argument_matches.get_one::<String>("output").cloned().unwrap_or_default();
+    if output == OUTPUT_TREE || output == OUTPUT_DIFF {
+        set_quiet(true);
+    }
+    let context = process_with_config(configuration, options)?;
+    context.print_error_summary();
+    match output.as_str() {
+        OUTPUT_TREE => print_tree(&context.operations),
+        OUTPUT_DIFF => print_diff(&context.operations),
+        _ => {}
     }
-    Ok(folder.config_dir().join(config))
+    if context.options.timing {
+        context.timings.print();
+    }
+
+    Ok(exit_code_for_run(&context))
 }
 
+pub(crate) fn get_values(argument_matches: &ArgMatches, id: &str) -> Vec<String> {
+    argument_matches.get_many::<String>(id)
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default()
+}
 
+/// The configuration file to read, either `config` itself or the file of that name in the
+/// configuration folder. Fails with [`MissingConfiguration`] when neither exists, so the caller
+/// can offer to create one.
+pub fn read_or_create(config: PathBuf) -> Result<PathBuf> {
+    if config.exists() {
+        return Ok(config);
+    }
+    let path = configuration_folder()?.join(config);
+    match path.exists() {
+        true => Ok(path),
+        false => Err(MissingConfiguration { path }.into()),
+    }
+}
+
+pub fn configuration_folder() -> Result<PathBuf> {
+    ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION)
+        .map(|folder| folder.config_dir().to_path_buf())
+        .ok_or(anyhow!("Couldn't determine the configuration folder"))
+}
@@ -0,0 +1,140 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use reflink_copy::reflink;
+use serde::Deserialize;
+
+use crate::output::{is_quiet, print_progress_line};
+
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+// Smaller files copy too quickly for progress output to be worth the noise.
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyStrategy {
+    /// Clone the file where the file system supports it (Btrfs, XFS, APFS, ReFS), copy the
+    /// data otherwise.
+    #[default]
+    Auto,
+    /// Only clone, copies to file systems that can't clone fail.
+    Reflink,
+    /// Always copy the data.
+    Standard,
+}
+
+/// How files are copied, set with `copy_strategy`, `copy_buffer_size` and `fsync` in the
+/// configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CopyOptions {
+    pub strategy: CopyStrategy,
+    pub buffer_size: usize,
+    /// Flush copies and the folders they land in to disk before they count as done.
+    pub fsync: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            strategy: CopyStrategy::default(),
+            buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            fsync: false,
+        }
+    }
+}
+
+/// Copies `source` to `target` following the strategy in `options`, calling `progress` with
+/// the bytes copied so far and the total while the data is copied.
+pub fn copy_file(source: &Path, target: &Path, options: &CopyOptions, progress: &mut dyn FnMut(u64, u64)) -> io::Result<u64> {
+    let cloned = match options.strategy {
+        CopyStrategy::Standard => false,
+        CopyStrategy::Reflink => reflink(source, target).map(|_| true)?,
+        CopyStrategy::Auto => reflink(source, target).is_ok(),
+    };
+    if !cloned {
+        return stream_copy(source, target, options, progress);
+    }
+    let file = File::open(target)?;
+    if options.fsync {
+        file.sync_all()?;
+    }
+    file.metadata().map(|metadata| metadata.len())
+}
+
+// Copies in chunks of the configured buffer size. Zero-filled chunks of a sparse source are
+// skipped over so they stay holes in the copy. Fails when fewer bytes than the source's size
+// could be copied, instead of leaving a truncated file behind.
+fn stream_copy(source: &Path, target: &Path, options: &CopyOptions, progress: &mut dyn FnMut(u64, u64)) -> io::Result<u64> {
+    let mut reader = File::open(source)?;
+    let metadata = reader.metadata()?;
+    let total = metadata.len();
+    let is_sparse = is_sparse(&metadata);
+    let mut writer = File::create(target)?;
+    let mut buffer = vec![0; options.buffer_size.max(1)];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        match is_sparse && buffer[..read].iter().all(|byte| *byte == 0) {
+            true => writer.seek(SeekFrom::Current(read as i64)).map(|_| ())?,
+            false => writer.write_all(&buffer[..read])?,
+        }
+        copied += read as u64;
+        progress(copied, total);
+    }
+    // A hole at the end is only skipped over, setting the length writes it.
+    writer.set_len(copied)?;
+    if copied != total {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("only {} of {} bytes could be copied", copied, total),
+        ));
+    }
+    fs::set_permissions(target, metadata.permissions())?;
+    if options.fsync {
+        writer.sync_all()?;
+    }
+    Ok(copied)
+}
+
+#[cfg(unix)]
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Flushes the entries of `directory`, so a file renamed into it survives a power loss.
+#[cfg(unix)]
+pub fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+// Folders can't be opened for syncing on other platforms, their entries are flushed with the files.
+#[cfg(not(unix))]
+pub fn sync_directory(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Progress callback showing how much of a large file has been copied.
+pub fn print_progress(name: &str) -> impl FnMut(u64, u64) + '_ {
+    let mut shown = None;
+    move |copied, total| {
+        if total < PROGRESS_THRESHOLD || is_quiet() {
+            return;
+        }
+        let percent = copied * 100 / total;
+        if shown != Some(percent) && print_progress_line(format_args!("  Copying {}: {}%", name, percent), copied == total) {
+            shown = Some(percent);
+        }
+    }
+}
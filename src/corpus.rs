@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use clap::ArgMatches;
+use colored::Colorize;
+use serde::Serialize;
+
+use crate::{configuration_path, prepare_rules, Config, ConfigurationError, EXIT_FAILURE, EXIT_SUCCESS};
+
+// Separates a filename from its expected destination on a corpus line.
+const EXPECTATION_SEPARATOR: &str = " -> ";
+// Expected destination of filenames no rule should pick up.
+const NO_MATCH: &str = "-";
+
+#[derive(Serialize)]
+struct CaseResult {
+    line: usize,
+    filename: String,
+    rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule_id: Option<String>,
+    destination: Option<PathBuf>,
+    expected: Option<String>,
+    passed: bool,
+}
+
+#[derive(Serialize)]
+struct CorpusReport {
+    passed: bool,
+    total: usize,
+    failed: usize,
+    cases: Vec<CaseResult>,
+}
+
+/// Runs every filename of a corpus file through the rules and compares where they'd end up
+/// with the destination written after ` -> `. Relative destinations only have to match the
+/// end of the path, `-` expects no rule to match. Lines without a destination fail only
+/// when no rule matches.
+pub fn test_corpus_command(argument_matches: &ArgMatches) -> Result<u8> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let mut configuration = Config::load(configuration_file).context(ConfigurationError)?;
+    prepare_rules(&mut configuration).context(ConfigurationError)?;
+    let corpus_file = PathBuf::from(argument_matches.get_one::<String>("file").unwrap());
+    let corpus = fs::read_to_string(&corpus_file)
+        .map_err(|err| anyhow!("Couldn't read corpus {}: {}", corpus_file.display(), err))?;
+
+    let mut cases = vec![];
+    for (idx, line) in corpus.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (filename, expected) = match line.split_once(EXPECTATION_SEPARATOR) {
+            Some((filename, expected)) => (filename.trim(), Some(expected.trim())),
+            None => (line, None),
+        };
+        cases.push(run_case(&configuration, idx + 1, filename, expected)?);
+    }
+
+    let failed = cases.iter().filter(|case| !case.passed).count();
+    let report = CorpusReport { passed: failed == 0, total: cases.len(), failed, cases };
+    match argument_matches.get_flag("json") {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => print_report(&report),
+    }
+
+    Ok(if report.passed { EXIT_SUCCESS } else { EXIT_FAILURE })
+}
+
+// The first active rule matching the filename is the one a run would apply.
+fn run_case(configuration: &Config, line: usize, filename: &str, expected: Option<&str>) -> Result<CaseResult> {
+    let today = Local::now().date_naive();
+    let mut matched = None;
+    for rule in configuration.rules.iter().filter(|rule| rule.is_active(today)) {
+        if let Some(destination) = configuration.explain(rule, filename)
+            .map_err(|err| anyhow!("Line {}: rule '{}' failed on {}: {}", line, rule.title, filename, err))? {
+            matched = Some((rule, destination));
+            break;
+        }
+    }
+
+    let passed = match (expected, &matched) {
+        (None, matched) => matched.is_some(),
+        (Some(NO_MATCH), matched) => matched.is_none(),
+        (Some(_), None) => false,
+        (Some(expected), Some((_, destination))) => {
+            let expected = Path::new(expected);
+            match expected.is_absolute() {
+                true => destination == expected,
+                false => destination.ends_with(expected),
+            }
+        }
+    };
+    let (rule, destination) = matched.unzip();
+    Ok(CaseResult {
+        line,
+        filename: filename.to_string(),
+        rule: rule.map(|rule| rule.title.clone()),
+        rule_id: rule.and_then(|rule| rule.id.clone()),
+        destination,
+        expected: expected.map(String::from),
+        passed,
+    })
+}
+
+fn print_report(report: &CorpusReport) {
+    for case in report.cases.iter().filter(|case| !case.passed) {
+        let actual = match (&case.rule, &case.destination) {
+            (Some(rule), Some(destination)) => format!("{} ({})", destination.display(), rule.blue()),
+            _ => String::from("no matching rule"),
+        };
+        println!("{} {}: {}", "FAIL".red().bold(), case.line, case.filename.bold());
+        println!("    expected: {}", case.expected.as_deref().unwrap_or("any rule"));
+        println!("    got:      {}", actual);
+    }
+    println!(
+        "{} of {} filenames sorted as expected, {} failed.",
+        report.total - report.failed, report.total, report.failed.to_string().bold()
+    );
+}
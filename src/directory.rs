@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all, remove_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{files_identical, HiddenFiles};
+use crate::copy::CopyOptions;
+use crate::utils::staged_copy;
+
+/// Total size in bytes of every file below `directory`.
+pub(crate) fn directory_size(directory: &Path) -> Result<u64> {
+    let mut size = 0;
+    for file in directory_files(directory)? {
+        size += directory.join(file).metadata()?.len();
+    }
+    Ok(size)
+}
+
+/// Name of the file listing, in gitignore syntax, what a run leaves alone in the folder it's in.
+pub(crate) const IGNORE_FILE: &str = ".fsortignore";
+
+/// Patterns of the `.fsortignore` file in `directory`, matching nothing when there's none.
+pub(crate) fn ignore_patterns(directory: &Path) -> Result<Gitignore> {
+    let file = directory.join(IGNORE_FILE);
+    let mut builder = GitignoreBuilder::new(directory);
+    if file.is_file() {
+        if let Some(err) = builder.add(&file) {
+            return Err(anyhow!("Couldn't read {}: {}", file.display(), err));
+        }
+    }
+    builder.build().map_err(|err| anyhow!("Couldn't read {}: {}", file.display(), err))
+}
+
+/// Whether `path` should be left out of a run under the given hidden-file policy.
+pub(crate) fn is_hidden_file(path: &Path, policy: HiddenFiles) -> bool {
+    let is_dotfile = || path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    match policy {
+        HiddenFiles::Include => false,
+        HiddenFiles::Skip => is_dotfile() || has_hidden_attribute(path),
+        HiddenFiles::WindowsAttributeOnly => has_hidden_attribute(path),
+    }
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    path.metadata().is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(target_os = "macos")]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::macos::fs::MetadataExt;
+    const UF_HIDDEN: u32 = 0x8000;
+    path.metadata().is_ok_and(|metadata| metadata.st_flags() & UF_HIDDEN != 0)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn has_hidden_attribute(_path: &Path) -> bool {
+    false
+}
+
+/// Moves or copies a whole directory tree to `target`.
+///
+/// When `target` already exists the trees are merged. Files that are already present with
+/// the same content are left alone, and any file that exists with different content aborts
+/// the operation before anything is touched.
+pub(crate) fn transfer_directory(source: &Path, target: &Path, is_copy_operation: bool, copy_options: &CopyOptions) -> Result<()> {
+    if !is_copy_operation && !target.exists() {
+        return rename(source, target)
+            .map_err(|err| anyhow!("Couldn't move {} to {}: {}", source.display(), target.display(), err));
+    }
+
+    let files = directory_files(source)?;
+    let conflicts = files.iter()
+        .filter(|file| target.join(file).exists())
+        .filter(|file| !files_identical(&source.join(file), &target.join(file)).unwrap_or(false))
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "{} already contains different versions of: {}",
+            target.display(), conflicts.join(", ")
+        ));
+    }
+
+    create_dir_all(target)?;
+    for (idx, file) in files.iter().enumerate() {
+        let (source_file, target_file) = (source.join(file), target.join(file));
+        info!("  [{}/{}] {}", idx + 1, files.len(), file.display());
+        if target_file.exists() {
+            if !is_copy_operation {
+                remove_file(&source_file)?;
+            }
+            continue;
+        }
+        if let Some(parent) = target_file.parent() {
+            create_dir_all(parent)?;
+        }
+        match is_copy_operation {
+            true => staged_copy(&source_file, &target_file, copy_options),
+            false => rename(&source_file, &target_file),
+        }
+        .map_err(|err| anyhow!("Couldn't transfer {} to {}: {}", source_file.display(), target_file.display(), err))?;
+    }
+
+    if !is_copy_operation {
+        remove_dir_all(source)
+            .map_err(|err| anyhow!("Couldn't remove {}: {}", source.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Directories below `root`, deepest first, that hold nothing but other such directories
+/// and files listed in `moved`. `root` itself is never included, and neither are directories
+/// `.fsortignore` or the hidden-file policy leave alone, nor the ones containing them.
+pub(crate) fn empty_directories(root: &Path, moved: &HashSet<PathBuf>, ignored: &Gitignore, hidden: HiddenFiles) -> Result<Vec<PathBuf>> {
+    let kept = KeptDirectories { ignored, hidden };
+    let mut empty = vec![];
+    for entry in fs::read_dir(root).map_err(|err| anyhow!("Couldn't read {}: {}", root.display(), err))? {
+        let path = entry?.path();
+        if path.is_dir() && !kept.contains(&path) {
+            collect_empty_directories(&path, moved, &kept, &mut empty)?;
+        }
+    }
+    Ok(empty)
+}
+
+struct KeptDirectories<'a> {
+    ignored: &'a Gitignore,
+    hidden: HiddenFiles,
+}
+
+impl KeptDirectories<'_> {
+    // Empty dot-folders such as Syncthing's `.stfolder` mark the folder for other programs, so
+    // they're kept even when the policy has hidden files sorted.
+    fn contains(&self, directory: &Path) -> bool {
+        let is_hidden = match self.hidden {
+            HiddenFiles::Include => is_hidden_file(directory, HiddenFiles::Skip),
+            policy => is_hidden_file(directory, policy),
+        };
+        is_hidden || self.ignored.matched_path_or_any_parents(directory, true).is_ignore()
+    }
+}
+
+fn collect_empty_directories(directory: &Path, moved: &HashSet<PathBuf>, kept: &KeptDirectories, empty: &mut Vec<PathBuf>) -> Result<bool> {
+    let mut is_empty = true;
+    for entry in fs::read_dir(directory).map_err(|err| anyhow!("Couldn't read {}: {}", directory.display(), err))? {
+        let path = entry?.path();
+        let entry_is_empty = match path.is_dir() && !moved.contains(&path) {
+            true if kept.contains(&path) => false,
+            true => collect_empty_directories(&path, moved, kept, empty)?,
+            false => moved.contains(&path),
+        };
+        is_empty &= entry_is_empty;
+    }
+    if is_empty {
+        empty.push(directory.to_path_buf());
+    }
+    Ok(is_empty)
+}
+
+/// Paths of all files below `directory`, relative to it.
+pub(crate) fn directory_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current)
+            .map_err(|err| anyhow!("Couldn't read {}: {}", current.display(), err))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path.strip_prefix(directory)?.to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
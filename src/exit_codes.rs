@@ -0,0 +1,84 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::WorkflowContext;
+
+pub const EXIT_SUCCESS: u8 = 0;
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_CONFIG_ERROR: u8 = 2;
+pub const EXIT_PARTIAL_FAILURE: u8 = 3;
+pub const EXIT_LOCKED: u8 = 4;
+pub const EXIT_NOTHING_TO_DO: u8 = 5;
+
+/// Marks an error as caused by the configuration rather than by a file being processed.
+#[derive(Debug)]
+pub struct ConfigurationError;
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration")
+    }
+}
+
+/// Another instance holds the run lock.
+#[derive(Debug)]
+pub struct LockContention {
+    /// None when the other instance's PID couldn't be read.
+    pub pid: Option<String>,
+}
+
+impl fmt::Display for LockContention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pid {
+            Some(pid) => write!(f, "Another instance of fsort is already running (PID {}). Use --no-lock to run anyway.", pid),
+            None => write!(f, "Another instance of fsort is already running. Use --no-lock to run anyway."),
+        }
+    }
+}
+
+impl std::error::Error for LockContention {}
+
+/// Neither the given configuration file nor one of that name in the configuration folder
+/// exists, `path` being where fsort expects it.
+#[derive(Debug)]
+pub struct MissingConfiguration {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for MissingConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No configuration found at {}", self.path.display())
+    }
+}
+
+impl std::error::Error for MissingConfiguration {}
+
+/// Names the rule that was being applied when processing a file failed.
+#[derive(Debug)]
+pub struct FailedRule(pub String);
+
+impl fmt::Display for FailedRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rule '{}' failed", self.0)
+    }
+}
+
+pub fn exit_code_for_error(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<LockContention>().is_some() {
+        EXIT_LOCKED
+    } else if error.downcast_ref::<ConfigurationError>().is_some() || error.downcast_ref::<MissingConfiguration>().is_some() {
+        EXIT_CONFIG_ERROR
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+pub fn exit_code_for_run(context: &WorkflowContext) -> u8 {
+    if !context.failures.is_empty() || !context.permission_denied.is_empty() {
+        EXIT_PARTIAL_FAILURE
+    } else if !context.has_work() {
+        EXIT_NOTHING_TO_DO
+    } else {
+        EXIT_SUCCESS
+    }
+}
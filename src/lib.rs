@@ -1,25 +1,106 @@
-use std::fs::{copy, create_dir_all, rename};
+use std::fs::{remove_file, rename};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
 use glob::glob;
-use once_cell::sync::Lazy;
-use regex::{Match, Regex};
-use serde::Deserialize;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 
+pub use archive::{ArchiveFormat, ArchivePolicy};
+pub use attributes::*;
+pub use checksum::*;
 pub use cli::*;
 pub use configuration::*;
+pub use corpus::*;
+pub use copy::CopyStrategy;
+pub use exit_codes::*;
+pub use lock::*;
+pub use manifest::*;
+pub use migration::*;
+pub use one_shot::*;
+pub use onboarding::*;
+pub use output::{is_quiet, set_color, set_quiet, LogLevel};
+pub use permissions::*;
+pub use plugin::*;
+pub use presets::*;
+pub use profile::*;
+pub use provenance::*;
+pub use quarantine::*;
+pub use reorganize::*;
+pub use rule_commands::*;
+pub use rule_packs::*;
+pub use service::*;
+pub use shell::*;
+pub use sidecar::*;
+pub use stability::*;
+pub use state::*;
+pub use system::*;
+pub use verify::*;
+pub use workflow::*;
+use copy::*;
+use directory::*;
+use template::*;
 use parser::*;
 use utils::*;
 
+#[macro_use]
+mod output;
 mod parser;
+#[cfg(unix)]
+mod accounts;
+mod archive;
+mod attributes;
+mod checksum;
 mod cli;
 mod configuration;
+mod corpus;
+mod copy;
+mod directory;
+mod exit_codes;
+mod lock;
+mod manifest;
+mod migration;
+mod onboarding;
+mod one_shot;
+mod permissions;
+mod plugin;
+mod presets;
+mod profile;
+mod provenance;
+mod quarantine;
+mod reorganize;
+mod report;
+mod rule_commands;
+mod rule_packs;
+mod service;
+mod shell;
+mod sidecar;
+mod stability;
+mod state;
+mod system;
+mod template;
 mod utils;
+mod verify;
+mod workflow;
 
 pub mod prelude {
     pub use crate::get_configuration_file_option;
-    pub use crate::perform_processing_based_on_configuration;
+    pub use crate::{exit_code_for_error, MissingConfiguration, EXIT_SUCCESS};
+    pub use crate::onboarding;
+    pub use crate::migrate_configuration;
+    pub use crate::quarantine_command;
+    pub use crate::profile_command;
+    pub use crate::{service_command, system_command};
+    pub use crate::{register_shell_command, unregister_shell_command};
+    pub use crate::{reorganize_command, test_corpus_command, verify_command, whence_command};
+    pub use crate::{add_rule_command, explain_command, list_rules_command};
+    pub use crate::{export_rules_command, import_rules_command};
+    pub use crate::{perform_processing_based_on_configuration, process_single_file, run_command};
+    pub use crate::{process_with_config, process_with_observer, WorkflowObserver};
+    pub use crate::{Config, ProcessingOptions, Rule, WorkflowContext};
 }
 
 pub type RulesList = Vec<Rule>;
@@ -30,33 +111,192 @@ const QUALIFIER: &str = "com";
 const ORGANIZATION: &str = "Ondřej Vágner";
 const APPLICATION: &str = "comic_sort";
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "name")]
 pub enum TransformativeFunction {
-    Last { args: Option<ArgumentList> },
-    First { args: Option<ArgumentList> },
+    Last {
+        args: Option<ArgumentList>,
+        #[serde(default)]
+        create_if_missing: bool,
+    },
+    First {
+        args: Option<ArgumentList>,
+        #[serde(default)]
+        create_if_missing: bool,
+    },
+    Newest {
+        args: Option<ArgumentList>,
+        #[serde(default)]
+        create_if_missing: bool,
+    },
+    Matching {
+        args: Option<ArgumentList>,
+        pattern: String,
+        #[serde(default)]
+        create_if_missing: bool,
+    },
+    Dated {
+        #[serde(default = "default_dated_format")]
+        format: String,
+    },
+    /// A folder per week, month or quarter of the file's date, such as `2024-W09`, `2024-03`
+    /// or `2024-Q1`.
+    #[serde(rename = "date_bucket")]
+    DateBucket {
+        granularity: DateGranularity,
+        #[serde(default)]
+        source: DateSource,
+    },
+    /// A folder picked by the number a capture group of the pattern matched, from ranges
+    /// such as `1-50: Vol1`, `51+: Vol2` and `default: Misc`.
+    #[serde(rename = "numeric_range")]
+    NumericRange {
+        #[serde(default)]
+        group: CaptureGroup,
+        ranges: BTreeMap<String, String>,
+    },
+    /// A folder named after the uppercased first character of a capture group of the pattern,
+    /// or of the filename without one, such as `A`, with one folder for all digits and one for
+    /// everything else.
+    #[serde(rename = "initial_letter")]
+    InitialLetter {
+        group: Option<CaptureGroup>,
+        #[serde(default = "default_digits_bucket")]
+        digits: String,
+        #[serde(default = "default_other_bucket")]
+        other: String,
+    },
+}
+
+/// A capture group of a rule's pattern, by number or by name.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+impl Default for CaptureGroup {
+    fn default() -> Self {
+        CaptureGroup::Index(1)
+    }
+}
+
+impl fmt::Display for CaptureGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureGroup::Index(index) => write!(f, "{}", index),
+            CaptureGroup::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DateGranularity {
+    Week,
+    Month,
+    Quarter,
+}
+
+/// Where the date of a file comes from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DateSource {
+    #[default]
+    Mtime,
+    /// A date such as `2024-03-05` or `20240305` in the filename, the modification time
+    /// for files without one.
+    NameDate,
 }
 
 impl TransformativeFunction {
-    pub fn get_dir(&self, root: &Path) -> Result<PathBuf> {
+    /// The folder the function picks. Folders it names that don't exist yet are created by
+    /// the run before the first file is moved into them, unless `creation` forbids it.
+    pub fn get_dir(&self, root: &Path, source: &Path, captures: Option<&Captures>, creation: DirectoryCreation) -> Result<PathBuf> {
+        match self {
+            TransformativeFunction::Dated { format } => return Ok(dated_dir(root, format)),
+            TransformativeFunction::DateBucket { granularity, source: date_source } => {
+                return date_bucket_dir(root, source, *granularity, *date_source);
+            }
+            TransformativeFunction::NumericRange { group, ranges } => {
+                return numeric_range_dir(root, captures, group, ranges);
+            }
+            TransformativeFunction::InitialLetter { group, digits, other } => {
+                return initial_letter_dir(root, source, captures, group.as_ref(), digits, other);
+            }
+            _ => {}
+        }
+
         let path = self.construct_path(root);
         let path_str = path.to_str().unwrap();
         let results: Vec<PathBuf> = glob(path_str)?.map(|x| x.unwrap()).collect();
         if results.is_empty() {
-            panic!("Couldn't find any folders fitting the pattern {}", path_str);
+            // When folders aren't created, the file is skipped once the folder turns out missing.
+            let creates = match creation {
+                DirectoryCreation::Always => true,
+                DirectoryCreation::Never | DirectoryCreation::RuleDefined => self.create_if_missing(),
+            };
+            if creates {
+                return self.missing_dir(root);
+            }
+            return Err(anyhow!("Couldn't find any folders fitting the pattern {}", path_str));
+        }
+
+        self.get_result_based_on_transformation(results, captures)
+    }
+
+    /// Checks the settings that can't be checked while the configuration is read.
+    pub fn validate(&self) -> Result<()> {
+        if let TransformativeFunction::NumericRange { ranges, .. } = self {
+            NumericRanges::parse(ranges)?;
+        }
+        Ok(())
+    }
+
+    fn args(&self) -> Option<&ArgumentList> {
+        match self {
+            TransformativeFunction::Last { args, .. } => args.as_ref(),
+            TransformativeFunction::First { args, .. } => args.as_ref(),
+            TransformativeFunction::Newest { args, .. } => args.as_ref(),
+            TransformativeFunction::Matching { args, .. } => args.as_ref(),
+            TransformativeFunction::Dated { .. }
+            | TransformativeFunction::DateBucket { .. }
+            | TransformativeFunction::NumericRange { .. }
+            | TransformativeFunction::InitialLetter { .. } => None,
+        }
+    }
+
+    fn create_if_missing(&self) -> bool {
+        match self {
+            TransformativeFunction::Last { create_if_missing, .. } => *create_if_missing,
+            TransformativeFunction::First { create_if_missing, .. } => *create_if_missing,
+            TransformativeFunction::Newest { create_if_missing, .. } => *create_if_missing,
+            TransformativeFunction::Matching { create_if_missing, .. } => *create_if_missing,
+            TransformativeFunction::Dated { .. }
+            | TransformativeFunction::DateBucket { .. }
+            | TransformativeFunction::NumericRange { .. }
+            | TransformativeFunction::InitialLetter { .. } => true,
         }
+    }
 
-        self.get_result_based_on_transformation(results)
+    // Builds the folder path from the arguments, leaving out any component containing a wildcard.
+    fn missing_dir(&self, root: &Path) -> Result<PathBuf> {
+        let literal_args: Vec<&String> = self.args().into_iter().flatten()
+            .filter(|arg| !arg.contains(['*', '?', '[']))
+            .collect();
+        if literal_args.is_empty() {
+            return Err(anyhow!("Can't create a folder in {} without literal arguments", root.display()));
+        }
+        let mut path: PathBuf = root.into();
+        path.extend(literal_args);
+        Ok(path)
     }
 
     fn construct_path(&self, root: &Path) -> PathBuf {
         let mut path: PathBuf = root.into();
-        let args = match self {
-            TransformativeFunction::Last { args } => args,
-            TransformativeFunction::First { args } => args,
-        };
-        match args {
+        match self.args() {
             Some(arg) => {
                 for x in arg {
                     path.push(x)
@@ -68,10 +308,18 @@ impl TransformativeFunction {
         path
     }
 
-    fn get_result_based_on_transformation(&self, results: Vec<PathBuf>) -> Result<PathBuf> {
+    fn get_result_based_on_transformation(&self, results: Vec<PathBuf>, captures: Option<&Captures>) -> Result<PathBuf> {
         match self {
             TransformativeFunction::Last { .. } => Ok(results[results.len() - 1].clone()),
             TransformativeFunction::First { .. } => Ok(results[0].clone()),
+            TransformativeFunction::Newest { .. } => newest_by_modification_time(results),
+            TransformativeFunction::Matching { pattern, .. } => first_matching(results, pattern, captures),
+            TransformativeFunction::Dated { .. }
+            | TransformativeFunction::DateBucket { .. }
+            | TransformativeFunction::NumericRange { .. }
+            | TransformativeFunction::InitialLetter { .. } => {
+                unreachable!("dated, range and letter folders don't use glob results")
+            }
         }
     }
 }
@@ -83,27 +331,456 @@ pub enum Rules {
     RootRules(Vec<RulesList>),
 }
 
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RootReference {
+    Index(usize),
+    Name(String),
+}
+
+impl Default for RootReference {
+    fn default() -> Self {
+        RootReference::Index(0)
+    }
+}
+
+/// An entry of a rule's `patterns`, either the pattern alone or a mapping with the pattern
+/// and settings that replace the rule's for the files it matches.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum PatternEntry {
+    Pattern(String),
+    Override(Box<PatternOverride>),
+}
+
+impl From<String> for PatternEntry {
+    fn from(pattern: String) -> Self {
+        PatternEntry::Pattern(pattern)
+    }
+}
+
+impl From<&str> for PatternEntry {
+    fn from(pattern: &str) -> Self {
+        PatternEntry::Pattern(pattern.to_string())
+    }
+}
+
+impl From<PatternOverride> for PatternEntry {
+    fn from(overrides: PatternOverride) -> Self {
+        PatternEntry::Override(Box::new(overrides))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PatternOverride {
+    pub pattern: String,
+    pub title: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_from_array_to_optional_pathbuf")]
+    pub directory: Option<PathBuf>,
+    pub function: Option<TransformativeFunction>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_processors")]
+    pub processors: Option<Vec<ConfigProcessor>>,
+    pub root: Option<RootReference>,
+    pub copy: Option<bool>,
+    pub checksum: Option<ChecksumAlgorithm>,
+    pub rename_to: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl PatternOverride {
+    pub fn new<S: Into<String>>(pattern: S) -> PatternOverride {
+        PatternOverride {
+            pattern: pattern.into(),
+            title: None,
+            directory: None,
+            function: None,
+            processors: None,
+            root: None,
+            copy: None,
+            checksum: None,
+            rename_to: None,
+            tags: None,
+        }
+    }
+
+    /// Replaces the settings of `rule` given in the override.
+    pub fn apply(&self, rule: &mut Rule) {
+        rule.pattern = Some(self.pattern.clone());
+        if let Some(title) = &self.title {
+            rule.title = title.clone();
+        }
+        if let Some(directory) = &self.directory {
+            rule.directory = Some(directory.clone());
+        }
+        if let Some(function) = &self.function {
+            rule.function = Some(function.clone());
+        }
+        if let Some(processors) = &self.processors {
+            rule.processors = Some(processors.clone());
+        }
+        if let Some(root) = &self.root {
+            rule.root = Some(root.clone());
+        }
+        if let Some(copy) = self.copy {
+            rule.copy = copy;
+        }
+        if let Some(checksum) = self.checksum {
+            rule.checksum = Some(checksum);
+        }
+        if let Some(rename_to) = &self.rename_to {
+            rule.rename_to = Some(rename_to.clone());
+        }
+        if let Some(tags) = &self.tags {
+            rule.tags = tags.clone();
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Rule {
     pub title: String,
+    /// Identifies the rule in manifests and reports, which then still refer to it after its
+    /// title changed.
+    pub id: Option<String>,
     pub pattern: Option<String>,
-    pub patterns: Option<Vec<String>>,
+    pub patterns: Option<Vec<PatternEntry>>,
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_from_array_to_optional_pathbuf")]
     pub directory: Option<PathBuf>,
     pub function: Option<TransformativeFunction>,
-    pub processors: Option<ConfigProcessor>,
     #[serde(default)]
-    pub root: usize,
+    #[serde(deserialize_with = "deserialize_processors")]
+    pub processors: Option<Vec<ConfigProcessor>>,
+    /// The root the rule sorts into, None for the first one or, for a rule under `then`, its
+    /// parent's.
+    pub root: Option<RootReference>,
     #[serde(default)]
     pub copy: bool,
+    pub checksum: Option<ChecksumAlgorithm>,
+    pub rename_to: Option<String>,
+    /// Pack matched files into dated archives in the destination instead of moving them.
+    pub archive: Option<ArchivePolicy>,
+    pub max_files_per_run: Option<usize>,
+    pub max_bytes_per_run: Option<u64>,
+    /// Files the rule picks up in one run, further matches stay in the download folder
+    /// without being deferred, so `processing_order` decides which ones are taken.
+    pub max_matches_per_run: Option<usize>,
+    /// Once the destination holds this many entries, new files go into subfolders named
+    /// after the first two hex digits of their name's hash, such as `3f/`.
+    pub max_files_per_directory: Option<usize>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub active_from: Option<NaiveDate>,
+    pub active_until: Option<NaiveDate>,
+    /// Match folders in the download directory by name and move them as a whole,
+    /// instead of matching files.
+    #[serde(default)]
+    pub match_directories: bool,
+    /// Octal mode such as `"0644"` set on files once they are in place, Unix only.
+    pub chmod: Option<String>,
+    /// `user:group`, `user` or `:group` to own files once they are in place, Unix only.
+    pub chown: Option<String>,
+    /// Carry extended attributes over to copies, which don't keep them on every platform.
+    #[serde(default)]
+    pub preserve_xattrs: bool,
+    /// Extended attributes to set on files once they are in place.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, String>,
+    /// Finder tags to label files with, the color names get their color.
+    #[serde(default)]
+    pub finder_tags: Vec<String>,
+    /// Names of the plugins, defined under `plugins:` in the configuration, the rule runs.
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    /// Suffixes such as `.srt` or `-poster.jpg` of files that share the matched file's stem
+    /// and move along with it, taking on its new name.
+    #[serde(default)]
+    pub companions: Vec<String>,
+    /// Fields of a JSON sidecar such as `photo.jpg.json`, which then moves along with the file.
+    pub sidecar: Option<MetadataSidecar>,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    /// Only report what the rule would do, even when the rest of the run executes.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// A rule sorting the file further once this one has put it in place, which matches every
+    /// file unless it has a pattern of its own.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_chained_rule")]
+    pub then: Option<Box<Rule>>,
     #[serde(skip_deserializing)]
     pub old_pattern: String,
     #[serde(skip_deserializing)]
     pub new_pattern: String,
+    /// `chmod` and `chown` with the owner's names looked up, once the rules are prepared.
+    #[serde(skip_deserializing)]
+    pub ownership: Ownership,
 }
 
 impl Rule {
+    pub fn new<S: Into<String>>(title: S) -> Rule {
+        Rule {
+            title: title.into(),
+            id: None,
+            pattern: None,
+            patterns: None,
+            directory: None,
+            function: None,
+            processors: None,
+            root: None,
+            copy: false,
+            checksum: None,
+            rename_to: None,
+            archive: None,
+            max_files_per_run: None,
+            max_bytes_per_run: None,
+            max_matches_per_run: None,
+            max_files_per_directory: None,
+            tags: vec![],
+            enabled: true,
+            active_from: None,
+            active_until: None,
+            match_directories: false,
+            chmod: None,
+            chown: None,
+            preserve_xattrs: false,
+            xattrs: BTreeMap::new(),
+            finder_tags: vec![],
+            plugins: vec![],
+            companions: vec![],
+            sidecar: None,
+            log_level: LogLevel::default(),
+            dry_run: false,
+            then: None,
+            old_pattern: String::new(),
+            new_pattern: String::new(),
+            ownership: Ownership::default(),
+        }
+    }
+
+    /// The root the rule sorts into.
+    pub fn root_reference(&self) -> RootReference {
+        self.root.clone().unwrap_or_default()
+    }
+
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> Rule {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_pattern<S: Into<String>>(mut self, pattern: S) -> Rule {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    pub fn with_patterns<P: Into<PatternEntry>>(mut self, patterns: Vec<P>) -> Rule {
+        self.patterns = Some(patterns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn with_directory<P: Into<PathBuf>>(mut self, directory: P) -> Rule {
+        self.directory = Some(directory.into());
+        self
+    }
+
+    pub fn with_function(mut self, function: TransformativeFunction) -> Rule {
+        self.function = Some(function);
+        self
+    }
+
+    pub fn with_processors(mut self, processors: Vec<ConfigProcessor>) -> Rule {
+        self.processors = Some(processors);
+        self
+    }
+
+    pub fn with_processor(mut self, processor: ConfigProcessor) -> Rule {
+        self.processors.get_or_insert_with(Vec::new).push(processor);
+        self
+    }
+
+    pub fn with_root(mut self, root: RootReference) -> Rule {
+        self.root = Some(root);
+        self
+    }
+
+    pub fn with_copy(mut self, copy: bool) -> Rule {
+        self.copy = copy;
+        self
+    }
+
+    pub fn with_rename_to<S: Into<String>>(mut self, template: S) -> Rule {
+        self.rename_to = Some(template.into());
+        self
+    }
+
+    pub fn with_archive(mut self, archive: ArchivePolicy) -> Rule {
+        self.archive = Some(archive);
+        self
+    }
+
+    pub fn with_run_limits(mut self, max_files: Option<usize>, max_bytes: Option<u64>) -> Rule {
+        self.max_files_per_run = max_files;
+        self.max_bytes_per_run = max_bytes;
+        self
+    }
+
+    pub fn with_max_matches_per_run(mut self, max_matches: usize) -> Rule {
+        self.max_matches_per_run = Some(max_matches);
+        self
+    }
+
+    pub fn with_max_files_per_directory(mut self, max_files: usize) -> Rule {
+        self.max_files_per_directory = Some(max_files);
+        self
+    }
+
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Rule {
+        self.checksum = Some(algorithm);
+        self
+    }
+
+    pub fn with_tag<S: Into<String>>(mut self, tag: S) -> Rule {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Rule {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_active_period(mut self, from: Option<NaiveDate>, until: Option<NaiveDate>) -> Rule {
+        self.active_from = from;
+        self.active_until = until;
+        self
+    }
+
+    pub fn with_match_directories(mut self, match_directories: bool) -> Rule {
+        self.match_directories = match_directories;
+        self
+    }
+
+    pub fn with_chmod<S: Into<String>>(mut self, mode: S) -> Rule {
+        self.chmod = Some(mode.into());
+        self
+    }
+
+    pub fn with_chown<S: Into<String>>(mut self, owner: S) -> Rule {
+        self.chown = Some(owner.into());
+        self
+    }
+
+    pub fn with_preserve_xattrs(mut self, preserve_xattrs: bool) -> Rule {
+        self.preserve_xattrs = preserve_xattrs;
+        self
+    }
+
+    pub fn with_xattr<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Rule {
+        self.xattrs.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_finder_tag<S: Into<String>>(mut self, tag: S) -> Rule {
+        self.finder_tags.push(tag.into());
+        self
+    }
+
+    pub fn with_plugin<S: Into<String>>(mut self, name: S) -> Rule {
+        self.plugins.push(name.into());
+        self
+    }
+
+    pub fn with_companion<S: Into<String>>(mut self, suffix: S) -> Rule {
+        self.companions.push(suffix.into());
+        self
+    }
+
+    pub fn with_sidecar(mut self, sidecar: MetadataSidecar) -> Rule {
+        self.sidecar = Some(sidecar);
+        self
+    }
+
+    /// Companion files of `file` that exist next to it, including its metadata sidecar.
+    pub fn companions_of(&self, file: &Path) -> Vec<PathBuf> {
+        let Some(stem) = file.file_stem().and_then(|stem| stem.to_str()) else {
+            return vec![];
+        };
+        let mut companions = self.companions.iter()
+            .map(|suffix| file.with_file_name(format!("{}{}", stem, suffix)))
+            .filter(|companion| companion != file && companion.is_file())
+            .collect::<Vec<_>>();
+        let sidecar = self.sidecar.as_ref().and_then(|_| MetadataSidecar::path_for(file));
+        if let Some(sidecar) = sidecar.filter(|sidecar| !companions.contains(sidecar)) {
+            companions.push(sidecar);
+        }
+        companions
+    }
+
+    /// Companion files of `file` paired with the path each gets next to `target`.
+    pub fn companion_targets(&self, file: &Path, target: &Path) -> Vec<(PathBuf, PathBuf)> {
+        let extension = file.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| format!(".{}", extension))
+            .unwrap_or_default();
+        let target_name = target.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let target_stem = target_name.strip_suffix(&extension)
+            .filter(|_| !extension.is_empty())
+            .unwrap_or(target_name);
+        let stem_length = file.file_stem().map(|stem| stem.len()).unwrap_or_default();
+        self.companions_of(file).into_iter()
+            .map(|companion| {
+                let suffix = &companion.file_name().unwrap().to_string_lossy()[stem_length..];
+                let companion_target = target.with_file_name(format!("{}{}", target_stem, suffix));
+                (companion, companion_target)
+            })
+            .collect()
+    }
+
+    pub fn with_log_level(mut self, log_level: LogLevel) -> Rule {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Rule {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_then(mut self, rule: Rule) -> Rule {
+        self.then = Some(Box::new(rule));
+        self
+    }
+
+    /// The rule followed by the rules chained to it with `then`, in the order they run.
+    pub fn stages(&self) -> impl Iterator<Item = &Rule> {
+        std::iter::successors(Some(self), |rule| rule.then.as_deref())
+    }
+
+    /// Whether the rule should fire on `today`; both ends of the active period are inclusive.
+    pub fn is_active(&self, today: NaiveDate) -> bool {
+        self.enabled
+            && self.active_from.is_none_or(|from| from <= today)
+            && self.active_until.is_none_or(|until| today <= until)
+    }
+
+    /// Matches the filenames the rule gives to files it sorts, or `None` when processors
+    /// change the name in ways that can't be traced back.
+    pub fn destination_pattern(&self) -> Result<Option<Regex>> {
+        if self.processors.as_ref().is_some_and(|processors| !processors.is_empty()) {
+            return Ok(None);
+        }
+        let pattern = match &self.rename_to {
+            Some(template) => template_pattern(template)?,
+            None => format!("^(?:{})$", self.new_pattern),
+        };
+        Ok(Some(Regex::new(&pattern)?))
+    }
+
     pub fn make_patterns(&mut self) -> Result<()> {
         if let Some(pattern) = &self.pattern {
             self.old_pattern = clean_pattern(pattern.as_str())?;
@@ -120,13 +797,131 @@ pub struct ConfigProcessor {
     pub merger: Option<String>,
     pub pattern: Option<String>,
     pub date_format: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
     pub replacement: Option<String>,
+    pub case: Option<Case>,
+    #[serde(default)]
+    pub case_directories: bool,
+    pub when: Option<String>,
+    /// Capture group whose roman numerals and spelled-out numbers become digits.
+    pub numerals: Option<CaptureGroup>,
+    pub pad_numbers: Option<usize>,
+    /// Replaces non-ASCII characters with their closest ASCII spelling, `Café` becomes `Cafe`.
+    #[serde(default)]
+    pub transliterate: bool,
+    pub normalize: Option<UnicodeForm>,
+}
+
+impl ConfigProcessor {
+    /// Whether the processor should run for the given value, based on its optional `when` pattern.
+    pub fn applies_to(&self, value: &str) -> Result<bool> {
+        match &self.when {
+            None => Ok(true),
+            Some(condition) => Ok(cached_regex(condition)?.is_match(value)),
+        }
+    }
+}
+
+/// What to do when a file's destination is already taken, either by a file that was there
+/// before the run or by another file sorted earlier in the same run.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Replace files that were there before the run. Two files of the same run never
+    /// replace each other, the second one is reported as a conflict instead.
+    #[default]
+    Overwrite,
+    Skip,
+    /// Add a counter such as ` (2)` to the new file's name.
+    Rename,
+    Error,
+}
+
+/// Which destination folders a run may create.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectoryCreation {
+    /// Every missing folder, including those of functions without `create_if_missing`.
+    Always,
+    /// None, files whose destination folder is missing are skipped.
+    Never,
+    /// Rule destinations, and the folders of functions with `create_if_missing`.
+    #[default]
+    RuleDefined,
+}
+
+/// Which files in the download folder count as hidden and are left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HiddenFiles {
+    /// Process every file, hidden or not.
+    #[default]
+    Include,
+    /// Skip dotfiles and files the platform marks as hidden.
+    Skip,
+    /// Skip only files the platform marks as hidden (the hidden attribute on Windows, the
+    /// hidden flag on macOS), so dotfiles are processed like any other file.
+    WindowsAttributeOnly,
+}
+
+/// What happens to files in the download folder that no rule matched.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum UnmatchedFiles {
+    /// Leave them where they are.
+    #[default]
+    Ignore,
+    /// List them in the summary at the end of the run.
+    Report,
+    /// Move them into a holding folder.
+    MoveTo(PathBuf),
+}
+
+/// Order in which the files of a run are processed, ties are broken by name.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessingOrder {
+    Name,
+    MtimeAsc,
+    MtimeDesc,
+    SizeDesc,
+}
+
+/// Unicode normalization form, so names written on different systems compare equal.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeForm {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Case {
+    Lower,
+    Upper,
+    Title,
+    Slug,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SanitizeMode {
+    Replace,
+    Strip,
+    Error,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Processor {
     source: PathBuf,
     target: PathBuf,
+    sanitizer: Option<Sanitizer>,
+    max_filename_length: Option<usize>,
+    copy_options: CopyOptions,
+    /// Fields of the rule's metadata sidecar, for `rename_to` placeholders.
+    metadata: BTreeMap<String, String>,
 }
 
 impl Processor {
@@ -134,6 +929,32 @@ impl Processor {
         Processor {
             source: file.to_path_buf(),
             target: PathBuf::new(),
+            sanitizer: None,
+            max_filename_length: None,
+            copy_options: CopyOptions::default(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    fn with_sanitizer(mut self, sanitizer: Option<Sanitizer>) -> Processor {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    fn with_max_filename_length(mut self, max_filename_length: Option<usize>) -> Processor {
+        self.max_filename_length = max_filename_length;
+        self
+    }
+
+    fn with_copy_options(mut self, copy_options: CopyOptions) -> Processor {
+        self.copy_options = copy_options;
+        self
+    }
+
+    fn sanitize(&self, component: String) -> Result<String> {
+        match &self.sanitizer {
+            None => Ok(component),
+            Some(sanitizer) => sanitizer.sanitize(&component),
         }
     }
 
@@ -157,93 +978,188 @@ impl Processor {
                 .ok_or(anyhow!("Filename not valid unicode")))
     }
 
-    fn perform_file_action(&self, is_copy_operation: bool) -> Result<()> {
-        let is_rename_operation = !is_copy_operation;
-        self.perform_file_operation(is_copy_operation, is_rename_operation)
+    // Whether one of the rule's plugins took care of the file itself.
+    fn run_plugins(&self, rule: &Rule, plugins: &Plugins) -> Result<bool> {
+        for plugin in plugins.for_rule(rule) {
+            if plugin.perform(&self.source, &self.target, rule)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Archived files are added by the plan, all entries of an archive at once.
+    fn perform_file_action(&self, rule: &Rule, plugins: &Plugins) -> Result<()> {
+        if self.run_plugins(rule, plugins)? {
+            return Ok(());
+        }
+        let source = to_long_path(&self.source);
+        let target = to_long_path(&self.target);
+        // Renames keep the attributes on their own, only copies need them read beforehand.
+        let attributes = match rule.preserve_xattrs && (rule.copy || rule.checksum.is_some()) && source.is_file() {
+            true => Some(ExtendedAttributes::read(&source)?),
+            false => None,
+        };
+        self.transfer(rule)?;
+        // The file is in place by now, not being able to label it doesn't undo the move.
+        let labelled = attributes.map_or(Ok(()), |attributes| attributes.write(&target))
+            .and_then(|_| set_rule_attributes(rule, &target))
+            .and_then(|_| rule.ownership.apply(&target));
+        if let Err(err) = labelled {
+            warning!("Couldn't set the attributes of {}: {:#}", self.target.display(), err);
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, rule: &Rule) -> Result<()> {
+        if self.source.is_dir() {
+            return transfer_directory(&to_long_path(&self.source), &to_long_path(&self.target), rule.copy, &self.copy_options);
+        }
+        if let Some(algorithm) = rule.checksum {
+            return self.perform_verified_file_operation(rule.copy, algorithm);
+        }
+        let is_rename_operation = !rule.copy;
+        self.perform_file_operation(rule.copy, is_rename_operation)
+    }
+
+    // Copies the file under its staging name and compares hashes before it's renamed into place
+    // and the original is removed, then writes a sidecar.
+    fn perform_verified_file_operation(&self, is_copy_operation: bool, algorithm: ChecksumAlgorithm) -> Result<()> {
+        let source = to_long_path(&self.source);
+        let target = to_long_path(&self.target);
+        let staging = staging_path(&target);
+        let source_hash = algorithm.hash_file(&source)?;
+        let name = self.target_filename()?;
+        copy_file(&source, &staging, &self.copy_options, &mut print_progress(name))
+            .with_context(|| format!("Couldn't copy {} to {}", self.source.display(), self.target.display()))?;
+        let target_hash = algorithm.hash_file(&staging)?;
+        if source_hash != target_hash {
+            remove_file(&staging)?;
+            return Err(anyhow!(
+                "Checksum mismatch after copying {} to {}, the original was kept",
+                self.source.display(), self.target.display()
+            ));
+        }
+        rename(&staging, &target)
+            .and_then(|_| sync_parent(&target, &self.copy_options))
+            .with_context(|| format!("Couldn't move {} into place", staging.display()))?;
+        if !is_copy_operation {
+            remove_file(to_long_path(&self.source))?;
+        }
+        algorithm.write_sidecar(&to_long_path(&self.target), &target_hash)?;
+        Ok(())
     }
 
     fn perform_file_operation(&self, is_copy_operation: bool, is_rename_operation: bool) -> Result<()> {
+        let source = to_long_path(&self.source);
+        let target = to_long_path(&self.target);
         if is_copy_operation {
-            copy(&self.source, &self.target)?;
+            staged_copy(&source, &target, &self.copy_options)
+                .with_context(|| format!("Couldn't copy {} to {}", self.source.display(), self.target.display()))?;
         }
         if is_rename_operation {
-            rename(&self.source, &self.target)?;
+            rename(&source, &target)
+                .and_then(|_| sync_parent(&target, &self.copy_options))
+                .with_context(|| format!("Couldn't move {} to {}", self.source.display(), self.target.display()))?;
         }
         Ok(())
     }
 
-    fn resolve_group_substring(&self, range: Vec<usize>) -> Result<String> {
-        let range_start = range[0];
-        let range_end = range[0] + range[1];
-        Ok(self.source_filename()?[range_start..range_end].to_string())
+    fn resolve_group_substring(&self, range: SubstringRange) -> Result<String> {
+        range.apply(self.source_filename()?)
     }
 
-    fn parse_dir(&self, directory: &Path) -> Result<PathBuf> {
-        static GROUP_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r".*<(.*)>.*").unwrap());
+    fn parse_dir(&self, directory: &Path, case: Option<Case>) -> Result<PathBuf> {
         let directory_string = directory.to_str()
             .expect("Failed to convert directory to string");
-        if !GROUP_PATTERN.is_match(directory_string) {
+        let Some(range) = directory_range(directory_string) else {
             return Ok(directory.to_path_buf());
+        };
+        let mut replace_part = self.resolve_group_substring(range.parse()?)?;
+        if let Some(case) = case {
+            replace_part = convert_case(&replace_part, case);
         }
-        let group_match = GROUP_PATTERN.find(directory_string)
-            .expect("Failed to find a match").as_str();
-        let found_group = GROUP_PATTERN
-            .captures(group_match).unwrap().get(1);
-        let group_values = self.extract_group_values(found_group);
-        let replace_part = self.resolve_group_substring(group_values)?;
-        let new_pattern = Regex::new(&format!("<{}>", found_group.unwrap().as_str()))?;
-        let dir = new_pattern.replace(directory_string, &replace_part).to_string();
+        let replace_part = self.sanitize(replace_part)?;
+        let dir = directory_string.replacen(&format!("<{}>", range), &replace_part, 1);
         Ok(PathBuf::from(dir))
     }
 
-    fn extract_group_values(&self, found_group: Option<Match>) -> Vec<usize> {
-        let group_values = found_group
-            .unwrap()
-            .as_str()
-            .split(':')
-            .map(|res| res.parse().unwrap())
-            .collect::<Vec<usize>>();
-        group_values
-    }
-
     fn parse_file(&self, pattern: &str) -> Result<String> {
         let source_filename = self.source_filename()?.to_string();
-        let r = Regex::new(pattern)?;
+        let r = cached_regex(pattern)?;
         let group = r.captures(&source_filename)
             .expect("No match found").get(0);
         Ok(if let Some(g) = group { g.as_str().to_string() } else { source_filename })
     }
 
-    fn create_and_set_target_directory(&mut self, root: &Path, folder: &Path) -> Result<()> {
+    fn set_target_directory(&mut self, root: &Path, folder: &Path, case: Option<Case>) -> Result<()> {
         let folder_full_path = full_path(root, folder);
-        self.target = self.parse_dir(&folder_full_path).unwrap();
-
-        Ok(create_dir_all(&self.target)?)
+        self.target = self.parse_dir(&folder_full_path, case)?;
+        validate_path_components(&self.target)
     }
 
     fn make_destination(&self, new_name: &str, root: Option<&Path>, rule: &Rule) -> Result<PathBuf> {
-        let mut processed_value: String = self.parse_file(new_name)?;
+        let mut processed_value: String = match &rule.rename_to {
+            None => self.parse_file(new_name)?,
+            Some(template) => {
+                let pattern = cached_regex(&rule.old_pattern)?;
+                let captures = pattern.captures(self.source_filename()?);
+                render_template(template, &self.source, captures.as_ref(), &self.metadata)?
+            }
+        };
         let root = match root {
             None => &self.target,
             Some(r) => r,
         };
 
-        if let Some(config_processor) = &rule.processors {
-            if config_processor.date_format.is_some() && config_processor.splitter.is_some() {
+        for config_processor in rule.processors.iter().flatten() {
+            if !config_processor.applies_to(&processed_value)? {
+                continue;
+            }
+
+            if let Some(form) = config_processor.normalize {
+                process_unicode_form(&mut processed_value, form);
+            }
+
+            if let (Some(date_format), Some(splitter)) = (&config_processor.date_format, &config_processor.splitter) {
                 process_date(
                     &mut processed_value,
-                    config_processor.date_format.as_ref().unwrap(),
-                    config_processor.splitter.as_ref().unwrap(),
+                    date_format,
+                    splitter,
                     &config_processor.merger,
+                    config_processor.locale.as_deref(),
+                    config_processor.timezone.as_deref(),
                 )?;
             }
 
             if let Some(pattern) = &config_processor.pattern {
                 process_pattern(&mut processed_value, pattern, &config_processor.replacement)?;
             }
+
+            if let Some(group) = &config_processor.numerals {
+                let captures = cached_regex(&rule.old_pattern)?.captures(self.source_filename()?);
+                process_numerals(&mut processed_value, captures.as_ref(), group)?;
+            }
+
+            if let Some(width) = config_processor.pad_numbers {
+                process_pad_numbers(&mut processed_value, width);
+            }
+
+            if config_processor.transliterate {
+                process_transliteration(&mut processed_value);
+            }
+
+            if let Some(case) = config_processor.case {
+                process_case(&mut processed_value, case);
+            }
+        }
+
+        let mut filename = self.sanitize(processed_value)?;
+        if let Some(max_length) = self.max_filename_length {
+            filename = truncate_filename(&filename, max_length)?;
         }
 
-        Ok(root.join(PathBuf::from(processed_value)))
+        Ok(root.join(PathBuf::from(filename)))
     }
 }
 
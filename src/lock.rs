@@ -0,0 +1,73 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+
+use crate::{LockContention, APPLICATION, ORGANIZATION, QUALIFIER};
+
+const LOCK_FILE: &str = "fsort.lock";
+// The PID is kept apart from the lock file, which can't be read while it's locked on Windows.
+const PID_EXTENSION: &str = "pid";
+
+/// Advisory lock held for the duration of a run so that two instances don't move the same files.
+///
+/// The lock is released when the value is dropped or the process exits.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+    pid_file: PathBuf,
+}
+
+impl RunLock {
+    pub fn default_path() -> Result<PathBuf> {
+        let folder = ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION)
+            .ok_or(anyhow!("Couldn't determine the configuration folder"))?;
+        Ok(folder.config_dir().join(LOCK_FILE))
+    }
+
+    pub fn acquire() -> Result<RunLock> {
+        RunLock::acquire_at(RunLock::default_path()?)
+    }
+
+    pub fn acquire_at(path: PathBuf) -> Result<RunLock> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| anyhow!("Couldn't open the lock file {}: {}", path.display(), err))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let pid = fs::read_to_string(pid_path(&path)).ok()
+                    .map(|pid| pid.trim().to_string())
+                    .filter(|pid| !pid.is_empty());
+                return Err(LockContention { pid }.into());
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(anyhow!("Couldn't lock {}: {}", path.display(), err));
+            }
+        }
+
+        let pid_file = pid_path(&path);
+        fs::write(&pid_file, std::process::id().to_string())
+            .map_err(|err| anyhow!("Couldn't write {}: {}", pid_file.display(), err))?;
+        Ok(RunLock { _file: file, pid_file })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.pid_file);
+    }
+}
+
+fn pid_path(lock: &Path) -> PathBuf {
+    lock.with_extension(PID_EXTENSION)
+}
@@ -1,9 +1,48 @@
-use anyhow::Result;
+use std::process::ExitCode;
+
 use human_panic::setup_panic;
 
 use file_sort::prelude::*;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     setup_panic!();
-    perform_processing_based_on_configuration(get_configuration_file_option()?)
-}
\ No newline at end of file
+    match run() {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(exit_code_for_error(&err))
+        }
+    }
+}
+
+fn run() -> anyhow::Result<u8> {
+    let argument_matches = get_configuration_file_option()?;
+    let result = match argument_matches.subcommand() {
+        Some(("migrate-config", sub_matches)) => migrate_configuration(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("quarantine", sub_matches)) => quarantine_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("add-rule", sub_matches)) => add_rule_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("export-rules", sub_matches)) => export_rules_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("import-rules", sub_matches)) => import_rules_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("list-rules", sub_matches)) => list_rules_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("explain", sub_matches)) => explain_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("profile", sub_matches)) => profile_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("service", sub_matches)) => service_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("register-shell", sub_matches)) => register_shell_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("unregister-shell", sub_matches)) => unregister_shell_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("whence", sub_matches)) => whence_command(sub_matches).map(|_| EXIT_SUCCESS),
+        Some(("verify", sub_matches)) => verify_command(sub_matches),
+        Some(("test-corpus", sub_matches)) => test_corpus_command(sub_matches),
+        Some(("reorganize", sub_matches)) => reorganize_command(sub_matches),
+        Some(("single", sub_matches)) => process_single_file(sub_matches),
+        Some(("run", sub_matches)) => run_command(sub_matches),
+        None if argument_matches.contains_id("system") => system_command(&argument_matches),
+        _ => perform_processing_based_on_configuration(argument_matches),
+    };
+    match result {
+        Err(err) => match err.downcast_ref::<MissingConfiguration>() {
+            Some(missing) => onboarding(&missing.path),
+            None => Err(err),
+        },
+        result => result,
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::directory::directory_size;
+use crate::parser::*;
+use crate::{ChecksumAlgorithm, FileOperation, OperationType, WorkflowContext};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Writes a file per run listing every file that was moved, copied or archived, so backup
+/// tools and audits can follow what happened.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestPolicy {
+    #[serde(deserialize_with = "deserialize_from_array_to_pathbuf")]
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub format: ManifestFormat,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    rule: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule_id: Option<&'a str>,
+    operation: &'static str,
+    source: &'a Path,
+    destination: &'a Path,
+    size: Option<u64>,
+    sha256: Option<String>,
+    /// Why the size and hash are missing, when the destination couldn't be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    started: String,
+    finished: String,
+    files: Vec<ManifestEntry<'a>>,
+}
+
+impl ManifestPolicy {
+    pub fn new<P: Into<PathBuf>>(directory: P, format: ManifestFormat) -> ManifestPolicy {
+        ManifestPolicy {
+            directory: directory.into(),
+            format,
+        }
+    }
+
+    /// Writes the manifest of a run that started at `started`, named after that time, and
+    /// returns its path. Files added to an archive have no size or hash of their own.
+    pub fn write(&self, context: &WorkflowContext, started: DateTime<Local>) -> Result<PathBuf> {
+        let is_dry_run = |title: &str| context.config.rules.iter().any(|rule| rule.title == title && rule.dry_run);
+        let files = context.operations.iter()
+            .filter(|operation| operation.operation != OperationType::Skipped && !is_dry_run(&operation.rule))
+            .map(entry)
+            .collect::<Vec<_>>();
+        let manifest = Manifest {
+            started: started.to_rfc3339(),
+            finished: Local::now().to_rfc3339(),
+            files,
+        };
+        let (extension, content) = match self.format {
+            ManifestFormat::Json => ("json", serde_json::to_string_pretty(&manifest)?),
+            ManifestFormat::Csv => ("csv", to_csv(&manifest)),
+        };
+
+        create_dir_all(&self.directory)
+            .map_err(|err| anyhow!("Couldn't create {}: {}", self.directory.display(), err))?;
+        // Runs starting within the same second get a counter instead of replacing each other.
+        let name = format!("fsort-{}", started.format("%Y-%m-%dT%H-%M-%S"));
+        for counter in 1.. {
+            let path = match counter {
+                1 => self.directory.join(format!("{}.{}", name, extension)),
+                _ => self.directory.join(format!("{}-{}.{}", name, counter, extension)),
+            };
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(anyhow!("Couldn't write manifest {}: {}", path.display(), err)),
+            };
+            file.write_all(content.as_bytes())
+                .map_err(|err| anyhow!("Couldn't write manifest {}: {}", path.display(), err))?;
+            return Ok(path);
+        }
+        unreachable!()
+    }
+}
+
+// A destination that can't be read, such as a file a plugin moved on, is listed without its
+// size and hash rather than leaving the other files out of the manifest.
+fn entry(operation: &FileOperation) -> ManifestEntry<'_> {
+    let target = &operation.target;
+    let read = || -> Result<(Option<u64>, Option<String>)> {
+        Ok(match operation.operation {
+            OperationType::Archive => (None, None),
+            _ if target.is_dir() => (Some(directory_size(target)?), None),
+            _ => (Some(target.metadata()?.len()), Some(ChecksumAlgorithm::Sha256.hash_file(target)?)),
+        })
+    };
+    let ((size, sha256), error) = match read() {
+        Ok(read) => (read, None),
+        Err(err) => ((None, None), Some(format!("Couldn't read {}: {}", target.display(), err))),
+    };
+    ManifestEntry {
+        rule: &operation.rule,
+        rule_id: operation.rule_id.as_deref(),
+        operation: match operation.operation {
+            OperationType::Move => "move",
+            OperationType::Copy => "copy",
+            OperationType::Archive => "archive",
+            OperationType::Skipped => "skip",
+        },
+        source: &operation.source,
+        destination: target,
+        size,
+        sha256,
+        error,
+    }
+}
+
+fn to_csv(manifest: &Manifest) -> String {
+    let mut csv = String::from("time,rule,rule_id,operation,source,destination,size,sha256,error\n");
+    for file in &manifest.files {
+        let row = [
+            manifest.finished.clone(),
+            file.rule.to_string(),
+            file.rule_id.unwrap_or_default().to_string(),
+            file.operation.to_string(),
+            file.source.display().to_string(),
+            file.destination.display().to_string(),
+            file.size.map(|size| size.to_string()).unwrap_or_default(),
+            file.sha256.clone().unwrap_or_default(),
+            file.error.clone().unwrap_or_default(),
+        ];
+        csv.push_str(&row.map(|field| csv_field(&field)).join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+// Quotes fields with separators, quotes or line breaks, doubling the quotes inside.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
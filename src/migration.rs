@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use colored::Colorize;
+use serde_yaml::{Mapping, Value};
+
+const CONFIG_KEYS: [&str; 24] = [
+    "root", "roots", "download", "rules", "sanitize", "sanitize_replacement", "max_filename_length", "quarantine",
+    "max_files_per_run", "max_bytes_per_run", "cleanup_empty_dirs", "plugins", "on_conflict", "include_hidden",
+    "processing_order", "presets", "copy_strategy", "copy_buffer_size", "fsync",
+    "unmatched", "manifest", "create_missing_dirs", "provenance", "stability_check",
+];
+const RULE_KEYS: [&str; 32] = [
+    "title", "id", "pattern", "patterns", "directory", "function", "processors", "root", "copy", "checksum", "rename_to",
+    "max_files_per_run", "max_bytes_per_run", "tags", "enabled", "active_from", "active_until", "match_directories",
+    "chmod", "chown", "preserve_xattrs", "xattrs", "finder_tags", "plugins", "companions", "log_level",
+    "dry_run", "max_files_per_directory",
+    "max_matches_per_run", "sidecar", "archive", "then",
+];
+const RENAMED_CONFIG_KEYS: [(&str, &str); 1] = [("mappings", "rules")];
+const RENAMED_RULE_KEYS: [(&str, &str); 1] = [("transformative_function", "function")];
+
+/// Result of converting a legacy `comic_sort` configuration.
+#[derive(Debug, Default)]
+pub struct Migration {
+    pub config: Value,
+    pub renamed: Vec<String>,
+    pub unconvertible: Vec<String>,
+}
+
+pub fn migrate_configuration(argument_matches: &ArgMatches) -> Result<()> {
+    let input = PathBuf::from(argument_matches.get_one::<String>("input").unwrap());
+    let output = match argument_matches.get_one::<String>("output") {
+        Some(path) => PathBuf::from(path),
+        None => default_output_path(&input),
+    };
+
+    let content = fs::read_to_string(&input)?;
+    let migration = migrate(&content)?;
+
+    for renamed in &migration.renamed {
+        info!("Renamed {}", renamed);
+    }
+    for unconvertible in &migration.unconvertible {
+        println!("{} {}", "Couldn't convert".bold().yellow(), unconvertible);
+    }
+
+    fs::write(&output, serde_yaml::to_string(&migration.config)?)?;
+    info!("Migrated configuration written to {}", output.display().to_string().bold());
+
+    Ok(())
+}
+
+pub fn migrate(content: &str) -> Result<Migration> {
+    let legacy: Value = serde_yaml::from_str(content)?;
+    let mapping = legacy.as_mapping()
+        .ok_or(anyhow!("The configuration must be a mapping at the top level"))?;
+
+    let mut migration = Migration::default();
+    let mut config = rename_keys(mapping, &RENAMED_CONFIG_KEYS, "", &mut migration);
+    report_unknown_keys(&config, &CONFIG_KEYS, "", &mut migration);
+
+    if let Some(rules) = config.get_mut("rules") {
+        migrate_rules(rules, &mut migration);
+    }
+
+    migration.config = Value::Mapping(config);
+    Ok(migration)
+}
+
+fn default_output_path(input: &Path) -> PathBuf {
+    let stem = input.file_stem().and_then(|stem| stem.to_str()).unwrap_or("config");
+    input.with_file_name(format!("{}.migrated.yaml", stem))
+}
+
+fn migrate_rules(rules: &mut Value, migration: &mut Migration) {
+    let Some(rules) = rules.as_sequence_mut() else {
+        migration.unconvertible.push(String::from("'rules' is not a list"));
+        return;
+    };
+    for rule in rules {
+        match rule {
+            Value::Sequence(root_rules) => {
+                for root_rule in root_rules {
+                    migrate_rule(root_rule, migration);
+                }
+            }
+            _ => migrate_rule(rule, migration),
+        }
+    }
+}
+
+fn migrate_rule(rule: &mut Value, migration: &mut Migration) {
+    let Some(mapping) = rule.as_mapping() else {
+        migration.unconvertible.push(format!("rule {:?} is not a mapping", rule));
+        return;
+    };
+    let title = mapping.get("title")
+        .and_then(Value::as_str)
+        .unwrap_or("<untitled>")
+        .to_string();
+    let context = format!("rule '{}': ", title);
+    let migrated = rename_keys(mapping, &RENAMED_RULE_KEYS, &context, migration);
+    report_unknown_keys(&migrated, &RULE_KEYS, &context, migration);
+    *rule = Value::Mapping(migrated);
+}
+
+fn rename_keys(mapping: &Mapping, renames: &[(&str, &str)], context: &str, migration: &mut Migration) -> Mapping {
+    let mut result = Mapping::new();
+    for (key, value) in mapping {
+        let new_key = key.as_str()
+            .and_then(|k| renames.iter().find(|(old, _)| *old == k))
+            .map(|(old, new)| {
+                migration.renamed.push(format!("{}'{}' to '{}'", context, old, new));
+                Value::from(*new)
+            })
+            .unwrap_or_else(|| key.clone());
+        result.insert(new_key, value.clone());
+    }
+    result
+}
+
+fn report_unknown_keys(mapping: &Mapping, known: &[&str], context: &str, migration: &mut Migration) {
+    for key in mapping.keys() {
+        match key.as_str() {
+            Some(k) if known.contains(&k) => {}
+            Some(k) => migration.unconvertible.push(format!("{}unknown key '{}'", context, k)),
+            None => migration.unconvertible.push(format!("{}non-string key {:?}", context, key)),
+        }
+    }
+}
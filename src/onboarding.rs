@@ -0,0 +1,140 @@
+use std::fs::{self, create_dir_all};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use atty::Stream;
+use colored::Colorize;
+use directories::UserDirs;
+
+use crate::{profiles_dir, Preset, EXIT_CONFIG_ERROR, EXIT_SUCCESS};
+
+const FALLBACK_DOWNLOADS: &str = "~/Downloads";
+const FALLBACK_DOCUMENTS: &str = "~/Documents";
+
+/// The folders a new configuration starts from, the platform's Downloads and Documents
+/// folders when they could be found.
+struct StarterConfig {
+    download: PathBuf,
+    root: PathBuf,
+    presets: Vec<Preset>,
+}
+
+impl StarterConfig {
+    fn detected() -> StarterConfig {
+        let folders = UserDirs::new();
+        let folder = |found: Option<&Path>, fallback: &str| found.map(Path::to_path_buf).unwrap_or(PathBuf::from(fallback));
+        StarterConfig {
+            download: folder(folders.as_ref().and_then(UserDirs::download_dir), FALLBACK_DOWNLOADS),
+            root: folder(folders.as_ref().and_then(UserDirs::document_dir), FALLBACK_DOCUMENTS),
+            presets: Preset::ALL.to_vec(),
+        }
+    }
+
+    // Presets that weren't picked stay in the file commented out, so they're easy to add later.
+    fn render(&self) -> String {
+        let presets = Preset::ALL.iter()
+            .map(|preset| match self.presets.contains(preset) {
+                true => format!("- {}\n", preset.name()),
+                false => format!("# - {}\n", preset.name()),
+            })
+            .collect::<String>();
+        format!(
+            "# fsort configuration, see `fsort --help` for the commands using it.\n\
+             # Paths are lists of components, `~` and environment variables are expanded.\n\
+             \n\
+             # The folder fsort sorts.\n\
+             download: [{}]\n\
+             \n\
+             # Folders the rules sort into, rules without a `root` use the first one.\n\
+             root:\n\
+             - [{}]\n\
+             \n\
+             # Ready-made rules moving common file types into a folder named after them, such as\n\
+             # Documents or Images.\n\
+             presets:\n\
+             {}\
+             \n\
+             # Your own rules, for example:\n\
+             #\n\
+             # - title: Invoices\n\
+             #   pattern: '^invoice.*\\.pdf$'\n\
+             #   directory: [Invoices]\n\
+             rules: []\n",
+            quoted(&self.download), quoted(&self.root), presets,
+        )
+    }
+}
+
+/// Runs when fsort finds no configuration: explains where it's expected and offers to write
+/// a starter configuration or to build one with a few questions.
+pub fn onboarding(path: &Path) -> Result<u8> {
+    let detected = StarterConfig::detected();
+    println!("{}", "No configuration found.".bold());
+    println!("fsort reads its configuration from {}", path.display());
+    println!("Use --config to read another file, or --profile to read one from {}", profiles_dir()?.display());
+    println!();
+    println!("Downloads folder: {}", detected.download.display());
+    println!("Documents folder: {}", detected.root.display());
+    println!();
+
+    if !atty::is(Stream::Stdin) {
+        println!("Run fsort in a terminal to create the configuration.");
+        return Ok(EXIT_CONFIG_ERROR);
+    }
+    let starter = match ask("Write a [s]tarter configuration, set it up with the [w]izard, or [q]uit? [S/w/q]")?
+        .to_lowercase()
+        .as_str()
+    {
+        "" | "s" | "starter" => detected,
+        "w" | "wizard" => wizard(detected)?,
+        _ => return Ok(EXIT_CONFIG_ERROR),
+    };
+
+    if let Some(folder) = path.parent().filter(|folder| !folder.as_os_str().is_empty()) {
+        create_dir_all(folder).map_err(|err| anyhow!("Couldn't create {}: {}", folder.display(), err))?;
+    }
+    fs::write(path, starter.render())
+        .map_err(|err| anyhow!("Couldn't write {}: {}", path.display(), err))?;
+    info!("Wrote {}", path.display().to_string().bold());
+    info!("Check what it would do with `fsort --dry` before the first run.");
+    Ok(EXIT_SUCCESS)
+}
+
+fn wizard(detected: StarterConfig) -> Result<StarterConfig> {
+    let download = ask_path("Folder to sort", detected.download)?;
+    let root = ask_path("Folder to sort into", detected.root)?;
+    let mut presets = vec![];
+    for preset in Preset::ALL {
+        let answer = ask(&format!("Sort {} files? [Y/n]", preset.name().bold()))?.to_lowercase();
+        if matches!(answer.as_str(), "" | "y" | "yes") {
+            presets.push(preset);
+        }
+    }
+    Ok(StarterConfig { download, root, presets })
+}
+
+fn ask_path(question: &str, default: PathBuf) -> Result<PathBuf> {
+    let answer = ask(&format!("{} [{}]", question, default.display()))?;
+    let path = match answer.is_empty() {
+        true => default,
+        false => PathBuf::from(answer),
+    };
+    if !path.is_dir() && !path.starts_with("~") {
+        println!("{} {} doesn't exist yet", "Warning:".yellow(), path.display());
+    }
+    Ok(path)
+}
+
+fn ask(question: &str) -> Result<String> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+// A double-quoted JSON string is valid YAML, and escapes backslashes in Windows paths.
+fn quoted(path: &Path) -> String {
+    serde_json::to_string(&path.to_string_lossy()).unwrap_or_default()
+}
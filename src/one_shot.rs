@@ -0,0 +1,48 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use directories::UserDirs;
+use shellexpand::tilde;
+
+use crate::{exit_code_for_run, process_with_config, Config, ConfigurationError, ProcessingOptions, Rule};
+
+/// Sorts the download folder with a single rule built from the command line, for quick moves
+/// that aren't worth adding to the configuration.
+pub fn run_command(argument_matches: &ArgMatches) -> Result<u8> {
+    let pattern = argument_matches.get_one::<String>("pattern").unwrap();
+    let destination = expand_path(argument_matches.get_one::<String>("dest").unwrap());
+    let (Some(root), Some(directory)) = (destination.parent(), destination.file_name()) else {
+        return Err(anyhow!("{} can't be used as the destination", destination.display()));
+    };
+    let download = match argument_matches.get_one::<String>("from") {
+        Some(folder) => expand_path(folder),
+        None => default_download_folder()?,
+    };
+
+    let rule = Rule::new(directory.to_string_lossy())
+        .with_pattern(pattern)
+        .with_directory(directory)
+        .with_copy(argument_matches.get_flag("copy"));
+    let configuration = Config::new(download)
+        .with_root(root)
+        .with_rule(rule)
+        .context(ConfigurationError)?;
+    let options = ProcessingOptions::default()
+        .dry_run(argument_matches.get_flag("dry"))
+        .use_lock(!argument_matches.get_flag("no-lock"));
+    let context = process_with_config(configuration, options)?;
+    context.print_error_summary();
+
+    Ok(exit_code_for_run(&context))
+}
+
+fn expand_path(path: &str) -> PathBuf {
+    PathBuf::from(tilde(path).as_ref())
+}
+
+fn default_download_folder() -> Result<PathBuf> {
+    UserDirs::new()
+        .and_then(|folders| folders.download_dir().map(Path::to_path_buf))
+        .ok_or(anyhow!("Couldn't find the downloads folder, pass it with --from"))
+}
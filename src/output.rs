@@ -0,0 +1,97 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread::{self, ThreadId};
+
+use serde::Deserialize;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+// The thread whose copy shows its progress on the current line. The groups of a plan run
+// concurrently, so output of the others has to start on a line of its own.
+static PROGRESS: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+/// Suppresses informational output; errors and summaries are still printed.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints a line of informational output, below the progress line of a copy that's still running.
+pub fn print_line(line: fmt::Arguments) {
+    if is_quiet() {
+        return;
+    }
+    let mut progress = PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    if progress.take().is_some() {
+        println!();
+    }
+    println!("{}", line);
+}
+
+/// Prints a warning unless `--quiet` was given, below the progress line of a copy that's still
+/// running.
+pub fn print_warning(line: fmt::Arguments) {
+    if is_quiet() {
+        return;
+    }
+    let mut progress = PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    if progress.take().is_some() {
+        println!();
+    }
+    eprintln!("{}", line);
+}
+
+/// Shows the progress of a copy in place on the current line, ending the line once `done`.
+/// Returns false without printing while another thread's copy has the line.
+pub fn print_progress_line(line: fmt::Arguments, done: bool) -> bool {
+    let current = thread::current().id();
+    let mut progress = PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    if progress.is_some_and(|owner| owner != current) {
+        return false;
+    }
+    print!("\r{}", line);
+    match done {
+        true => {
+            println!();
+            *progress = None;
+        }
+        false => *progress = Some(current),
+    }
+    let _ = io::stdout().flush();
+    true
+}
+
+/// How much a single rule reports about the files it handles.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+pub fn set_color(enabled: bool) {
+    colored::control::set_override(enabled);
+}
+
+/// Prints informational output unless `--quiet` was given.
+macro_rules! info {
+    () => {
+        $crate::output::print_line(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print_line(format_args!($($arg)*))
+    };
+}
+
+/// Prints a warning unless `--quiet` was given.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::output::print_warning(format_args!($($arg)*))
+    };
+}
@@ -1,13 +1,22 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use serde::de::Error;
 use serde::{Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value};
 
 use utils::*;
 
-use crate::{Rules, RulesList};
+pub(crate) use utils::map_patterns_to_rules;
+
+use crate::{ConfigProcessor, HiddenFiles, Plugins, Roots, Rule, Rules, RulesList, SubprocessPlugin, UnmatchedFiles};
 
 mod utils;
 
+const CHAINED_RULE_PATTERN: &str = ".*";
+
 pub fn deserialize_from_array_to_pathbuf<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
     where
         D: Deserializer<'de>,
@@ -28,20 +37,187 @@ pub fn deserialize_from_array_to_optional_pathbuf<'de, D>(deserializer: D) -> Re
     }
 }
 
-pub fn deserialize_from_arrays_to_pathbuf_vec<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+pub fn deserialize_plugins<'de, D>(deserializer: D) -> Result<Plugins, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definitions: BTreeMap<String, SubprocessPlugin> = Deserialize::deserialize(deserializer)?;
+    let mut plugins = Plugins::default();
+    for (name, plugin) in definitions {
+        plugins.insert(name, Arc::new(plugin));
+    }
+    Ok(plugins)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RootsDefinition {
+    Indexed(Vec<Vec<String>>),
+    // A mapping keeps the roots in the order they're written, the first one is the default.
+    Named(Mapping),
+}
+
+pub fn deserialize_roots<'de, D>(deserializer: D) -> Result<Roots, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definition: RootsDefinition = Deserialize::deserialize(deserializer)?;
+    let mut roots = Roots::default();
+    match definition {
+        RootsDefinition::Indexed(paths) => {
+            for path in paths {
+                roots.push(None, process_strings_to_paths(path));
+            }
+        }
+        RootsDefinition::Named(named_paths) => {
+            for (name, path) in named_paths {
+                // Rules refer to roots by number or by name, a name made of digits would read as an index.
+                let name = match name {
+                    Value::Number(number) => number.to_string(),
+                    name => serde_yaml::from_value(name).map_err(D::Error::custom)?,
+                };
+                if name.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(D::Error::custom(format!(
+                        "root '{}': names can't be made of digits only, those refer to a root by its position", name
+                    )));
+                }
+                let path: Vec<String> = serde_yaml::from_value(path)
+                    .map_err(|err| D::Error::custom(format!("root '{}': {}", name, err)))?;
+                roots.push(Some(name), process_strings_to_paths(path));
+            }
+        }
+    }
+    Ok(roots)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HiddenFilesDefinition {
+    Flag(bool),
+    Policy(String),
+}
+
+pub fn deserialize_hidden_files<'de, D>(deserializer: D) -> Result<HiddenFiles, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definition: HiddenFilesDefinition = Deserialize::deserialize(deserializer)?;
+    match definition {
+        HiddenFilesDefinition::Flag(true) => Ok(HiddenFiles::Include),
+        HiddenFilesDefinition::Flag(false) => Ok(HiddenFiles::Skip),
+        HiddenFilesDefinition::Policy(policy) if policy == "windows_attribute_only" => Ok(HiddenFiles::WindowsAttributeOnly),
+        HiddenFilesDefinition::Policy(policy) => Err(D::Error::custom(format!(
+            "include_hidden must be true, false or windows_attribute_only, not '{}'", policy
+        ))),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnmatchedDefinition {
+    Policy(String),
+    MoveTo { move_to: Vec<String> },
+}
+
+pub fn deserialize_unmatched<'de, D>(deserializer: D) -> Result<UnmatchedFiles, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definition: UnmatchedDefinition = Deserialize::deserialize(deserializer)?;
+    match definition {
+        UnmatchedDefinition::Policy(policy) if policy == "ignore" => Ok(UnmatchedFiles::Ignore),
+        UnmatchedDefinition::Policy(policy) if policy == "report" => Ok(UnmatchedFiles::Report),
+        UnmatchedDefinition::Policy(policy) => Err(D::Error::custom(format!(
+            "unmatched must be ignore, report or move_to with a folder, not '{}'", policy
+        ))),
+        UnmatchedDefinition::MoveTo { move_to } => Ok(UnmatchedFiles::MoveTo(move_to.iter().map(process_path).collect())),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProcessorsDefinition {
+    Single(Box<ConfigProcessor>),
+    Chain(Vec<ConfigProcessor>),
+}
+
+pub fn deserialize_processors<'de, D>(deserializer: D) -> Result<Option<Vec<ConfigProcessor>>, D::Error>
     where
         D: Deserializer<'de>,
 {
-    let paths: Vec<Vec<String>> = Deserialize::deserialize(deserializer)?;
-    Ok(paths.into_iter().map(process_strings_to_paths).collect())
+    let definition: Option<ProcessorsDefinition> = Deserialize::deserialize(deserializer)?;
+    Ok(definition.map(|processors| match processors {
+        ProcessorsDefinition::Single(processor) => vec![*processor],
+        ProcessorsDefinition::Chain(processors) => processors,
+    }))
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationDefinition {
+    Seconds(u64),
+    Text(String),
+}
+
+/// A number of seconds, or a number with one of the units `ms`, `s`, `m` or `h` such as `2s`.
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definition: DurationDefinition = Deserialize::deserialize(deserializer)?;
+    let text = match definition {
+        DurationDefinition::Seconds(seconds) => return Ok(Duration::from_secs(seconds)),
+        DurationDefinition::Text(text) => text,
+    };
+    let invalid = || D::Error::custom(format!("'{}' isn't a duration such as 500ms, 2s, 5m or 1h", text));
+    let split = text.trim().find(|character: char| !character.is_ascii_digit()).unwrap_or(text.trim().len());
+    let (amount, unit) = text.trim().split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        "h" => Ok(Duration::from_secs(amount * 60 * 60)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The rule under `then`, whose title defaults to its parent's and which matches every file
+/// when it has no pattern.
+pub fn deserialize_chained_rule<'de, D>(deserializer: D) -> Result<Option<Box<Rule>>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let definition: Option<Mapping> = Deserialize::deserialize(deserializer)?;
+    let Some(mut definition) = definition else {
+        return Ok(None);
+    };
+    if definition.contains_key("patterns") {
+        return Err(D::Error::custom("then takes a single pattern, not patterns"));
+    }
+    if !definition.contains_key("title") {
+        definition.insert(Value::from("title"), Value::from(""));
+    }
+    if !definition.contains_key("pattern") {
+        definition.insert(Value::from("pattern"), Value::from(CHAINED_RULE_PATTERN));
+    }
+    let rule: Rule = serde_yaml::from_value(Value::Mapping(definition)).map_err(D::Error::custom)?;
+    Ok(Some(Box::new(rule)))
+}
 
 pub fn parse_rules<'de, D>(deserializer: D) -> Result<RulesList, D::Error>
     where
         D: Deserializer<'de>,
 {
     let parsed_rules: Rules = Deserialize::deserialize(deserializer)?;
+    // The rules a `patterns` list expands to share its id, so ids are compared before that.
+    let ids = match &parsed_rules {
+        Rules::SingleRule(rules) => rules.iter().filter_map(|rule| rule.id.as_ref()).collect::<Vec<_>>(),
+        Rules::RootRules(roots) => roots.iter().flatten().filter_map(|rule| rule.id.as_ref()).collect(),
+    };
+    if let Some(id) = ids.iter().enumerate().find_map(|(idx, id)| ids[..idx].contains(id).then_some(id)) {
+        return Err(D::Error::custom(format!("More than one rule has the id '{}'", id)));
+    }
     let mut result_rules = vec![];
     match parsed_rules {
         Rules::SingleRule(mut rules) => {
@@ -58,3 +234,35 @@ pub fn parse_rules<'de, D>(deserializer: D) -> Result<RulesList, D::Error>
 pub fn default_merger() -> Option<String> {
     Some(String::from("-"))
 }
+
+pub fn default_sanitize_replacement() -> String {
+    String::from("_")
+}
+
+pub fn default_dated_format() -> String {
+    String::from("%Y-%m")
+}
+
+pub fn default_archive_name() -> String {
+    String::from("%Y-%m")
+}
+
+pub fn default_digits_bucket() -> String {
+    String::from("0-9")
+}
+
+pub fn default_other_bucket() -> String {
+    String::from("#")
+}
+
+pub fn default_enabled() -> bool {
+    true
+}
+
+pub fn default_quarantine_after() -> u32 {
+    3
+}
+
+pub fn default_stability_retries() -> u32 {
+    5
+}
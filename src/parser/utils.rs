@@ -2,31 +2,55 @@ use std::path::PathBuf;
 
 use shellexpand::tilde;
 
-use crate::{Rule, RulesList};
+use crate::{PatternEntry, RootReference, Rule, RulesList};
 
 pub fn expand_path(path: &str) -> String {
     tilde(path).to_string()
 }
 
 pub fn handle_colon_end(mut path: String) -> String {
-    if path.ends_with(':') {
+    if is_drive_letter(&path) {
         path += "\\";
     };
     path
 }
 
+fn is_drive_letter(path: &str) -> bool {
+    let mut characters = path.chars();
+    matches!(
+        (characters.next(), characters.next(), characters.next()),
+        (Some(letter), Some(':'), None) if letter.is_ascii_alphabetic()
+    )
+}
+
+// Network roots written as `//server/share` need backslashes to be recognised as UNC paths.
+pub fn handle_unc_prefix(path: String) -> String {
+    if cfg!(windows) && path.starts_with("//") {
+        path.replace('/', "\\")
+    } else {
+        path
+    }
+}
+
 pub fn process_path<S: AsRef<str>>(path: S) -> String {
     let p = expand_path(path.as_ref());
-    handle_colon_end(p)
+    handle_unc_prefix(handle_colon_end(p))
 }
 
 pub fn process_strings_to_paths(strings: Vec<String>) -> PathBuf {
     strings.into_iter().map(process_path).collect()
 }
 
-pub fn process_patterns(rule: &mut Rule, patterns: &[String]) -> RulesList {
+pub fn process_patterns(rule: &mut Rule, patterns: &[PatternEntry]) -> RulesList {
     patterns.iter()
-        .map(|pattern| extract_rule_with_pattern(rule, pattern))
+        .map(|entry| match entry {
+            PatternEntry::Pattern(pattern) => extract_rule_with_pattern(rule, pattern),
+            PatternEntry::Override(overrides) => {
+                let mut derived_rule = extract_rule_with_pattern(rule, &overrides.pattern);
+                overrides.apply(&mut derived_rule);
+                derived_rule
+            }
+        })
         .collect()
 }
 
@@ -59,8 +83,8 @@ pub fn process_and_append_rules(roots: Vec<RulesList>, new_rules: &mut Vec<Rule>
     let roots_with_indices = roots.into_iter().enumerate();
     for (idx, root) in roots_with_indices {
         for mut map in root {
-            if map.root == 0 {
-                map.root = idx;
+            if map.root.is_none() {
+                map.root = Some(RootReference::Index(idx));
             }
             process_and_append_rule(&mut vec![map], new_rules)
         }
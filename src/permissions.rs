@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+#[cfg(unix)]
+use crate::accounts;
+use crate::Rule;
+
+/// Mode and ownership to set on files once they reach their destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ownership {
+    pub mode: Option<u32>,
+    pub user: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl Ownership {
+    /// Reads the rule's `chmod` and `chown`, looking up named users and groups. Other platforms
+    /// have neither, the rule then leaves its files as they are.
+    pub fn from_rule(rule: &Rule) -> Result<Ownership> {
+        let mode = rule.chmod.as_deref()
+            .map(|mode| u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|mode| *mode <= 0o7777)
+                .ok_or(anyhow!("'{}' isn't a valid octal file mode", mode)))
+            .transpose()?;
+        if cfg!(not(unix)) && (mode.is_some() || rule.chown.is_some()) {
+            warning!("chmod and chown are only supported on Unix, rule '{}' leaves its files as they are", rule.title);
+        }
+        let (user, group) = match rule.chown.as_deref() {
+            None => (None, None),
+            Some(owner) => owner_ids(owner)?,
+        };
+        Ok(Ownership { mode, user, group })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Ownership::default()
+    }
+
+    /// Applies the mode to `target` and every file below it, and the owner to everything.
+    /// Directories keep their mode, so a file mode like `0644` doesn't make them unusable.
+    pub fn apply(&self, target: &Path) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if target.is_dir() {
+            for entry in fs::read_dir(target)? {
+                self.apply(&entry?.path())?;
+            }
+        } else if let Some(mode) = self.mode {
+            set_mode(target, mode)?;
+        }
+        if self.user.is_some() || self.group.is_some() {
+            set_owner(target, self.user, self.group)?;
+        }
+        Ok(())
+    }
+}
+
+// Numeric ids are used as they are, names are looked up through the system's name service.
+#[cfg(unix)]
+fn owner_ids(owner: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+    let user = Some(user).filter(|user| !user.is_empty())
+        .map(|user| match user.parse() {
+            Ok(id) => Ok(id),
+            Err(_) => accounts::user(user)?
+                .map(|account| account.uid)
+                .ok_or(anyhow!("No user named '{}' found", user)),
+        })
+        .transpose()?;
+    let group = Some(group).filter(|group| !group.is_empty())
+        .map(|group| match group.parse() {
+            Ok(id) => Ok(id),
+            Err(_) => accounts::group(group)?.ok_or(anyhow!("No group named '{}' found", group)),
+        })
+        .transpose()?;
+    Ok((user, group))
+}
+
+#[cfg(not(unix))]
+fn owner_ids(_owner: &str) -> Result<(Option<u32>, Option<u32>)> {
+    Ok((None, None))
+}
+
+#[cfg(unix)]
+fn set_mode(target: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(target, fs::Permissions::from_mode(mode))
+        .map_err(|err| anyhow!("Couldn't change the mode of {} to {:o}: {}", target.display(), mode, err))
+}
+
+#[cfg(unix)]
+fn set_owner(target: &Path, user: Option<u32>, group: Option<u32>) -> Result<()> {
+    std::os::unix::fs::chown(target, user, group).map_err(|err| match err.kind() {
+        std::io::ErrorKind::PermissionDenied => anyhow!(
+            "Couldn't change the owner of {}: changing owners usually needs root", target.display()
+        ),
+        _ => anyhow!("Couldn't change the owner of {}: {}", target.display(), err),
+    })
+}
+
+// Rules don't get an ownership to apply on other platforms.
+#[cfg(not(unix))]
+fn set_mode(_target: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_owner(_target: &Path, _user: Option<u32>, _group: Option<u32>) -> Result<()> {
+    Ok(())
+}
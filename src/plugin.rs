@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::Rule;
+
+/// Custom logic that rules can opt into with `plugins: [name]`.
+///
+/// Every hook has a default implementation that leaves the file alone, so a plugin only
+/// needs to implement the ones it cares about.
+pub trait Plugin: Send + Sync {
+    /// Called once the rule's pattern matched, returning `false` makes the rule skip the file.
+    fn matches(&self, _file: &Path, _rule: &Rule) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Gets the destination the rule came up with and returns the one to use instead.
+    fn transform(&self, _file: &Path, _rule: &Rule, target: PathBuf) -> Result<PathBuf> {
+        Ok(target)
+    }
+
+    /// Gets the chance to move the file itself, returning `true` when it did.
+    fn perform(&self, _file: &Path, _target: &Path, _rule: &Rule) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The plugins available to rules, by name.
+#[derive(Clone, Default)]
+pub struct Plugins(pub BTreeMap<String, Arc<dyn Plugin>>);
+
+impl Plugins {
+    pub fn insert<S: Into<String>>(&mut self, name: S, plugin: Arc<dyn Plugin>) {
+        self.0.insert(name.into(), plugin);
+    }
+
+    pub fn for_rule<'a>(&'a self, rule: &'a Rule) -> impl Iterator<Item = &'a Arc<dyn Plugin>> {
+        rule.plugins.iter().filter_map(|name| self.0.get(name))
+    }
+
+    pub fn validate(&self, rule: &Rule) -> Result<()> {
+        match rule.plugins.iter().find(|name| !self.0.contains_key(*name)) {
+            Some(name) => Err(anyhow!("No plugin named '{}' is defined", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginHook {
+    Match,
+    Transform,
+    Action,
+}
+
+/// A plugin run as an external program for each hook it has registered for.
+///
+/// The program gets a JSON request such as `{"hook": "match", "file": "...", "rule": "..."}`
+/// on stdin and answers on stdout with `{"matches": true}`, `{"target": "..."}` or
+/// `{"handled": true}`. Leaving a field out keeps the default behavior.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SubprocessPlugin {
+    pub command: Vec<String>,
+    pub hooks: Vec<PluginHook>,
+}
+
+#[derive(Serialize)]
+struct PluginRequest<'a> {
+    hook: PluginHook,
+    file: &'a Path,
+    rule: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a Path>,
+}
+
+#[derive(Deserialize, Default)]
+struct PluginResponse {
+    matches: Option<bool>,
+    target: Option<PathBuf>,
+    handled: Option<bool>,
+}
+
+impl SubprocessPlugin {
+    fn call(&self, hook: PluginHook, file: &Path, rule: &Rule, target: Option<&Path>) -> Result<PluginResponse> {
+        if !self.hooks.contains(&hook) {
+            return Ok(PluginResponse::default());
+        }
+        let (program, args) = self.command.split_first()
+            .ok_or(anyhow!("Plugin command is empty"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| anyhow!("Couldn't start plugin {}: {}", program, err))?;
+
+        let request = PluginRequest { hook, file, rule: &rule.title, target };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&serde_json::to_vec(&request)?)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Plugin {} failed with {}: {}",
+                program, output.status, String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        serde_json::from_slice(&output.stdout)
+            .map_err(|err| anyhow!("Couldn't read the answer of plugin {}: {}", program, err))
+    }
+}
+
+impl Plugin for SubprocessPlugin {
+    fn matches(&self, file: &Path, rule: &Rule) -> Result<bool> {
+        Ok(self.call(PluginHook::Match, file, rule, None)?.matches.unwrap_or(true))
+    }
+
+    fn transform(&self, file: &Path, rule: &Rule, target: PathBuf) -> Result<PathBuf> {
+        Ok(self.call(PluginHook::Transform, file, rule, Some(&target))?.target.unwrap_or(target))
+    }
+
+    fn perform(&self, file: &Path, target: &Path, rule: &Rule) -> Result<bool> {
+        Ok(self.call(PluginHook::Action, file, rule, Some(target))?.handled.unwrap_or(false))
+    }
+}
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::Rule;
+
+const PRESET_TAG: &str = "preset";
+
+/// Ready-made rules sorting common file types by extension into a folder of the first root.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Documents,
+    Images,
+    Audio,
+    Video,
+    Archives,
+    Installers,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 6] = [Preset::Documents, Preset::Images, Preset::Audio, Preset::Video, Preset::Archives, Preset::Installers];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Documents => "documents",
+            Preset::Images => "images",
+            Preset::Audio => "audio",
+            Preset::Video => "video",
+            Preset::Archives => "archives",
+            Preset::Installers => "installers",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Preset::Documents => "Documents",
+            Preset::Images => "Images",
+            Preset::Audio => "Audio",
+            Preset::Video => "Video",
+            Preset::Archives => "Archives",
+            Preset::Installers => "Installers",
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Documents => &[
+                "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv", "ppt", "pptx", "odp", "epub",
+            ],
+            Preset::Images => &["jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "svg", "cr2", "nef", "dng"],
+            Preset::Audio => &["mp3", "flac", "wav", "ogg", "opus", "m4a", "aac", "wma"],
+            Preset::Video => &["mp4", "mkv", "avi", "mov", "wmv", "webm", "m4v", "flv"],
+            Preset::Archives => &["zip", "rar", "7z", "tar", "gz", "tgz", "bz2", "xz", "zst"],
+            Preset::Installers => &["exe", "msi", "dmg", "pkg", "deb", "rpm", "appimage", "apk"],
+        }
+    }
+
+    /// The preset's rule, tagged `preset` and with the preset's name so runs can filter on them.
+    pub fn rule(&self) -> Rule {
+        Rule::new(self.title())
+            .with_pattern(format!(r"^.*\.(?i:{})$", self.extensions().join("|")))
+            .with_tag(PRESET_TAG)
+            .with_tag(self.name())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Preset::ALL.into_iter()
+            .find(|preset| preset.name() == name)
+            .ok_or(anyhow!(
+                "Unknown preset '{}', expected one of {}",
+                name, Preset::ALL.map(|preset| preset.name()).join(", ")
+            ))
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+
+use crate::{configuration_folder, read_or_create};
+
+const PROFILES_FOLDER: &str = "profiles";
+const PROFILE_EXTENSION: &str = "yaml";
+
+pub fn profiles_dir() -> Result<PathBuf> {
+    Ok(configuration_folder()?.join(PROFILES_FOLDER))
+}
+
+/// The configuration file to read: `profiles/<name>.yaml` when `--profile` is given,
+/// otherwise the `--config` path.
+pub fn configuration_path(argument_matches: &ArgMatches) -> Result<PathBuf> {
+    let Some(profile) = argument_matches.get_one::<String>("profile") else {
+        return read_or_create(PathBuf::from(argument_matches.get_one::<String>("config").unwrap()));
+    };
+    if argument_matches.value_source("config") == Some(ValueSource::CommandLine) {
+        return Err(anyhow!("--profile and --config can't be used together"));
+    }
+    let path = profiles_dir()?.join(profile).with_extension(PROFILE_EXTENSION);
+    if !path.exists() {
+        return Err(anyhow!("No profile named '{}', expected it at {}", profile, path.display()));
+    }
+    Ok(path)
+}
+
+pub fn profile_command(argument_matches: &ArgMatches) -> Result<()> {
+    match argument_matches.subcommand() {
+        Some(("list", _)) | None => list(),
+        Some((name, _)) => Err(anyhow!("Unknown profile command '{}'", name)),
+    }
+}
+
+fn list() -> Result<()> {
+    let folder = profiles_dir()?;
+    let mut profiles = vec![];
+    if folder.is_dir() {
+        for entry in fs::read_dir(&folder)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == PROFILE_EXTENSION) {
+                if let Some(name) = path.file_stem() {
+                    profiles.push(name.to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+    if profiles.is_empty() {
+        println!("No profiles found in {}.", folder.display());
+    }
+    profiles.sort();
+    for profile in profiles {
+        println!("{}", profile);
+    }
+    Ok(())
+}
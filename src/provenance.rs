@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use clap::ArgMatches;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{FileOperation, OperationType};
+
+const SIDECAR_EXTENSION: &str = "fsort.json";
+
+/// Where a run records which file a sorted file was and the rule that moved it, read back
+/// by `fsort whence`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProvenanceStore {
+    /// The `user.fsort.source` extended attribute on Unix, an alternate data stream of the
+    /// same name on Windows.
+    Xattr,
+    /// A `.fsort.json` file next to the sorted file.
+    Sidecar,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub source: PathBuf,
+    pub sorted: String,
+    pub rule: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<String>,
+}
+
+impl Provenance {
+    pub fn of(operation: &FileOperation) -> Provenance {
+        Provenance {
+            source: operation.source.clone(),
+            sorted: Local::now().to_rfc3339(),
+            rule: operation.rule.clone(),
+            rule_id: operation.rule_id.clone(),
+        }
+    }
+
+    /// The record of `file`, from its sidecar or its attribute.
+    pub fn read(file: &Path) -> Result<Option<Provenance>> {
+        let content = match sidecar_path(file) {
+            sidecar if sidecar.is_file() => Some(fs::read(&sidecar)
+                .map_err(|err| anyhow!("Couldn't read {}: {}", sidecar.display(), err))?),
+            _ => sys::read(file)?,
+        };
+        content
+            .map(|content| serde_json::from_slice(&content)
+                .map_err(|err| anyhow!("Couldn't read where {} came from: {}", file.display(), err)))
+            .transpose()
+    }
+
+    pub fn write(&self, file: &Path, store: ProvenanceStore) -> Result<()> {
+        let content = serde_json::to_vec(self)?;
+        match store {
+            ProvenanceStore::Xattr => sys::write(file, &content),
+            ProvenanceStore::Sidecar => {
+                let sidecar = sidecar_path(file);
+                fs::write(&sidecar, content).map_err(|err| anyhow!("Couldn't write {}: {}", sidecar.display(), err))
+            }
+        }
+    }
+}
+
+/// Records where the file of `operation` came from once it's in place. Files that were sorted
+/// before, like the ones `reorganize` moves, keep the source of their first record.
+pub(crate) fn record_provenance(operation: &FileOperation, store: ProvenanceStore, earlier: Option<Provenance>) -> Result<()> {
+    let mut provenance = Provenance::of(operation);
+    if let Some(earlier) = earlier {
+        provenance.source = earlier.source;
+    }
+    provenance.write(&operation.target, store)?;
+    let old_sidecar = sidecar_path(&operation.source);
+    if operation.operation == OperationType::Move && old_sidecar.is_file() {
+        fs::remove_file(&old_sidecar).map_err(|err| anyhow!("Couldn't remove {}: {}", old_sidecar.display(), err))?;
+    }
+    Ok(())
+}
+
+pub fn sidecar_path(file: &Path) -> PathBuf {
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(SIDECAR_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+pub fn is_provenance_sidecar(filename: &str) -> bool {
+    filename.ends_with(&format!(".{}", SIDECAR_EXTENSION))
+}
+
+/// Shows where a sorted file came from, when and by which rule, as recorded by `provenance`.
+pub fn whence_command(argument_matches: &ArgMatches) -> Result<()> {
+    let file = PathBuf::from(argument_matches.get_one::<String>("file").unwrap());
+    if !file.exists() {
+        return Err(anyhow!("{} doesn't exist", file.display()));
+    }
+    let provenance = Provenance::read(&file)?
+        .ok_or(anyhow!("No record of where {} came from, was it sorted with `provenance` set?", file.display()))?;
+    println!("{}", file.display().to_string().bold());
+    println!("    source:     {}", provenance.source.display());
+    println!("    sorted:     {}", provenance.sorted);
+    match &provenance.rule_id {
+        Some(id) => println!("    rule:       {} ({})", provenance.rule.blue(), id),
+        None => println!("    rule:       {}", provenance.rule.blue()),
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    const ATTRIBUTE: &str = "user.fsort.source";
+
+    pub fn read(file: &Path) -> Result<Option<Vec<u8>>> {
+        xattr::get(file, ATTRIBUTE)
+            .map_err(|err| anyhow!("Couldn't read attribute {} of {}: {}", ATTRIBUTE, file.display(), err))
+    }
+
+    pub fn write(file: &Path, content: &[u8]) -> Result<()> {
+        xattr::set(file, ATTRIBUTE, content)
+            .map_err(|err| anyhow!("Couldn't set attribute {} on {}: {}", ATTRIBUTE, file.display(), err))
+    }
+}
+
+// NTFS keeps named streams such as `file.pdf:fsort.source` alongside the file's content,
+// they move and copy with it on NTFS volumes.
+#[cfg(windows)]
+mod sys {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use anyhow::{anyhow, Result};
+
+    const STREAM: &str = "fsort.source";
+
+    fn stream(file: &Path) -> PathBuf {
+        let mut stream = file.as_os_str().to_owned();
+        stream.push(":");
+        stream.push(STREAM);
+        PathBuf::from(stream)
+    }
+
+    pub fn read(file: &Path) -> Result<Option<Vec<u8>>> {
+        match fs::read(stream(file)) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(anyhow!("Couldn't read stream {} of {}: {}", STREAM, file.display(), err)),
+        }
+    }
+
+    pub fn write(file: &Path, content: &[u8]) -> Result<()> {
+        fs::write(stream(file), content)
+            .map_err(|err| anyhow!("Couldn't write stream {} of {}: {}", STREAM, file.display(), err))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod sys {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    pub fn read(_file: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    pub fn write(file: &Path, _content: &[u8]) -> Result<()> {
+        Err(anyhow!("Extended attributes aren't supported here, use a sidecar to record where {} came from", file.display()))
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::{create_dir_all, rename};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use colored::Colorize;
+use serde::Deserialize;
+
+use crate::parser::*;
+use crate::state::{QuarantineEntry, StateStore};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct QuarantinePolicy {
+    #[serde(default = "default_quarantine_after")]
+    pub after: u32,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_from_array_to_optional_pathbuf")]
+    pub directory: Option<PathBuf>,
+}
+
+impl QuarantinePolicy {
+    /// Records a failed attempt and quarantines the file once it has failed often enough.
+    pub fn handle_failure(&self, state: &mut StateStore, file: &Path, error: &anyhow::Error) -> Result<()> {
+        // The operation can fail after the file was already moved, there's nothing to hold back then.
+        if !file.exists() {
+            state.clear_failures(file);
+            return Ok(());
+        }
+        let failures = state.record_failure(file, error);
+        if failures < self.after {
+            return Ok(());
+        }
+
+        let location = match &self.directory {
+            None => None,
+            Some(directory) => {
+                create_dir_all(directory)?;
+                let filename = file.file_name().ok_or(anyhow!("No filename found"))?;
+                let location = directory.join(filename);
+                rename(file, &location)
+                    .map_err(|err| anyhow!("Couldn't quarantine {}: {}", file.display(), err))?;
+                Some(location)
+            }
+        };
+        info!(
+            "{} {} after {} failures.",
+            "Quarantined".bold().yellow(),
+            file.display(),
+            failures
+        );
+        state.clear_failures(file);
+        state.quarantined.insert(file.to_path_buf(), QuarantineEntry {
+            error: error.to_string(),
+            location,
+        });
+        Ok(())
+    }
+}
+
+pub fn quarantine_command(argument_matches: &ArgMatches) -> Result<()> {
+    let mut state = StateStore::load()?;
+    match argument_matches.subcommand() {
+        Some(("release", release_matches)) => {
+            let file = PathBuf::from(release_matches.get_one::<String>("file").unwrap());
+            release(&mut state, &file)?;
+            state.save()
+        }
+        _ => {
+            list(&state);
+            Ok(())
+        }
+    }
+}
+
+fn list(state: &StateStore) {
+    if state.quarantined.is_empty() {
+        println!("No files are quarantined.");
+    }
+    for (original, entry) in &state.quarantined {
+        match &entry.location {
+            Some(location) => println!("{} (moved to {})", original.display().to_string().bold(), location.display()),
+            None => println!("{} (skipped)", original.display().to_string().bold()),
+        }
+        println!("    {}", entry.error);
+    }
+}
+
+fn release(state: &mut StateStore, file: &Path) -> Result<()> {
+    let original = state.quarantined.iter()
+        .find(|(original, entry)| original.as_path() == file || entry.location.as_deref() == Some(file))
+        .map(|(original, _)| original.clone())
+        .ok_or(anyhow!("{} is not quarantined", file.display()))?;
+    let entry = state.quarantined.remove(&original).unwrap();
+    if let Some(location) = entry.location {
+        rename(&location, &original)
+            .map_err(|err| anyhow!("Couldn't move {} back to {}: {}", location.display(), original.display(), err))?;
+    }
+    info!("Released {}", original.display().to_string().bold());
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use colored::Colorize;
+
+use crate::directory::directory_files;
+use crate::provenance::is_provenance_sidecar;
+use crate::utils::STAGING_EXTENSION;
+use crate::{
+    configuration_path, exit_code_for_run, parse_root_reference, process_with_config, ChecksumAlgorithm, Config,
+    ConfigurationError, OperationType, ProcessingOptions, UnmatchedFiles,
+};
+
+/// Runs the current rules against the files already sorted into the roots, so a library
+/// follows changes to its destination templates. Only plans the moves unless `--apply` is given.
+pub fn reorganize_command(argument_matches: &ArgMatches) -> Result<u8> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let mut configuration = Config::load(configuration_file).context(ConfigurationError)?;
+    // Sorted files no rule matches anymore stay where they are.
+    configuration.unmatched = UnmatchedFiles::Ignore;
+
+    let roots = match argument_matches.get_one::<String>("root") {
+        Some(root) => vec![configuration.root.resolve(&parse_root_reference(root))?.clone()],
+        None => configuration.root.0.iter().map(|root| root.path.clone()).collect(),
+    };
+    let mut files = vec![];
+    for root in roots.iter().filter(|root| root.is_dir()) {
+        files.extend(sorted_files(root)?);
+    }
+
+    let apply = argument_matches.get_flag("apply");
+    let options = ProcessingOptions::default()
+        .dry_run(!apply)
+        .use_lock(!argument_matches.get_flag("no-lock"))
+        .files(files);
+    let context = process_with_config(configuration, options)?;
+    context.print_error_summary();
+
+    let planned = context.operations.iter()
+        .filter(|operation| operation.operation != OperationType::Skipped)
+        .count();
+    match apply {
+        true => println!("{} files reorganized.", planned.to_string().bold()),
+        false if planned > 0 => println!("{} files would be reorganized, run with --apply to move them.", planned.to_string().bold()),
+        false => println!("Everything is where the rules would put it."),
+    }
+
+    Ok(exit_code_for_run(&context))
+}
+
+// Leaves out checksum and provenance sidecars and unfinished copies, they only make sense next
+// to their file.
+fn sorted_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let sidecar_extension = format!(".{}", ChecksumAlgorithm::Sha256.extension());
+    let staging_extension = format!(".{}", STAGING_EXTENSION);
+    Ok(directory_files(root)?
+        .into_iter()
+        .map(|file| root.join(file))
+        .filter(|file| {
+            let name = file.to_string_lossy();
+            !name.ends_with(&sidecar_extension) && !name.ends_with(&staging_extension) && !is_provenance_sidecar(&name)
+        })
+        .collect())
+}
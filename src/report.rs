@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::directory::directory_size;
+use crate::workflow::format_size;
+use crate::{FileOperation, OperationType};
+
+#[derive(Default)]
+struct Directory {
+    children: BTreeMap<OsString, Directory>,
+    files: Vec<(String, Option<String>)>,
+    count: usize,
+    bytes: u64,
+}
+
+impl Directory {
+    fn insert(&mut self, components: &[OsString], file: (String, Option<String>), bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+        match components.split_first() {
+            Some((first, rest)) => self.children.entry(first.clone()).or_default().insert(rest, file, bytes),
+            None => self.files.push(file),
+        }
+    }
+
+    fn summary(&self) -> String {
+        let files = if self.count == 1 { "file" } else { "files" };
+        format!("({} {}, {})", self.count, files, format_size(self.bytes))
+    }
+
+    fn print(&mut self, prefix: &str) {
+        self.files.sort();
+        let entries = self.children.len() + self.files.len();
+        for (idx, (name, child)) in self.children.iter_mut().enumerate() {
+            let is_last = idx + 1 == entries;
+            println!("{}{}{} {}", prefix, branch(is_last), name.to_string_lossy().bold().blue(), child.summary().dimmed());
+            child.print(&format!("{}{}", prefix, if is_last { "    " } else { "│   " }));
+        }
+        for (idx, (name, source)) in self.files.iter().enumerate() {
+            let is_last = self.children.len() + idx + 1 == entries;
+            match source {
+                Some(source) => println!("{}{}{} {}", prefix, branch(is_last), name, format!("<- {}", source).dimmed()),
+                None => println!("{}{}{}", prefix, branch(is_last), name),
+            }
+        }
+    }
+}
+
+fn branch(is_last: bool) -> &'static str {
+    if is_last { "└── " } else { "├── " }
+}
+
+/// Prints the planned moves and copies grouped by destination folder, with the number of
+/// files and their size for every folder. Renamed files show their original name.
+pub fn print_tree(operations: &[FileOperation]) {
+    let planned = operations.iter()
+        .filter(|operation| operation.operation != OperationType::Skipped)
+        .collect::<Vec<_>>();
+    if planned.is_empty() {
+        println!("Nothing to move.");
+        return;
+    }
+
+    let base = common_ancestor(planned.iter().filter_map(|operation| operation.target.parent()));
+    let mut tree = Directory::default();
+    for operation in planned {
+        let directory = operation.target.parent().unwrap_or(Path::new(""));
+        let components = directory.strip_prefix(&base).unwrap_or(directory)
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let name = file_name(&operation.target);
+        let source = Some(file_name(&operation.source)).filter(|source| *source != name);
+        tree.insert(&components, (name, source), size(operation));
+    }
+
+    println!("{} {}", base.display().to_string().bold(), tree.summary().dimmed());
+    tree.print("");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Kept,
+    Removed,
+    Added,
+}
+
+/// Prints every planned rename as its old and new filename, with the removed characters
+/// highlighted in the old name and the added ones in the new name.
+pub fn print_diff(operations: &[FileOperation]) {
+    let mut unchanged = 0;
+    for operation in operations.iter().filter(|operation| operation.operation != OperationType::Skipped) {
+        let (old, new) = (file_name(&operation.source), file_name(&operation.target));
+        if old == new {
+            unchanged += 1;
+            continue;
+        }
+        let changes = diff(&old, &new);
+        let render = |shown: Change| changes
+            .chunk_by(|(first, _), (second, _)| first == second)
+            .filter(|run| run[0].0 == Change::Kept || run[0].0 == shown)
+            .map(|run| {
+                let text = run.iter().map(|(_, character)| character).collect::<String>();
+                match run[0].0 {
+                    Change::Kept => text.normal(),
+                    Change::Removed => text.red().bold().underline(),
+                    Change::Added => text.green().bold().underline(),
+                }.to_string()
+            })
+            .collect::<String>();
+        println!("{}", operation.rule.blue());
+        println!("  {} {}", "-".red(), render(Change::Removed));
+        println!("  {} {}", "+".green(), render(Change::Added));
+    }
+    if unchanged > 0 {
+        println!("{} files keep their names.", unchanged);
+    }
+}
+
+// Character diff from the longest common subsequence, filenames are short enough for the
+// quadratic table.
+fn diff(old: &str, new: &str) -> Vec<(Change, char)> {
+    let (old, new) = (old.chars().collect::<Vec<_>>(), new.chars().collect::<Vec<_>>());
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut changes = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            changes.push((Change::Kept, old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            changes.push((Change::Added, new[j]));
+            j += 1;
+        } else {
+            changes.push((Change::Removed, old[i]));
+            i += 1;
+        }
+    }
+    changes
+}
+
+fn common_ancestor<'a>(mut directories: impl Iterator<Item = &'a Path>) -> PathBuf {
+    let Some(first) = directories.next() else {
+        return PathBuf::new();
+    };
+    let mut base = first.to_path_buf();
+    for directory in directories {
+        while !directory.starts_with(&base) {
+            if !base.pop() {
+                break;
+            }
+        }
+    }
+    base
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Measured on whichever end exists, the source on a dry run and the target otherwise.
+fn size(operation: &FileOperation) -> u64 {
+    [&operation.source, &operation.target].into_iter()
+        .find(|path| path.exists())
+        .map(|path| match path.is_dir() {
+            true => directory_size(path).unwrap_or_default(),
+            false => path.metadata().map(|metadata| metadata.len()).unwrap_or_default(),
+        })
+        .unwrap_or_default()
+}
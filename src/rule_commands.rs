@@ -0,0 +1,335 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveDate};
+use clap::ArgMatches;
+use colored::Colorize;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use uuid::Uuid;
+
+use crate::utils::clean_pattern;
+use crate::{configuration_path, prepare_rules, Config, ConfigProcessor, ConfigurationError, RootReference, Rule, TransformativeFunction};
+
+pub fn add_rule_command(argument_matches: &ArgMatches) -> Result<()> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let title = argument_matches.get_one::<String>("title").unwrap();
+    let pattern = argument_matches.get_one::<String>("pattern").unwrap();
+    let root = argument_matches.get_one::<String>("root").map(|root| parse_root_reference(root));
+
+    let configuration = Config::load(configuration_file.clone()).context(ConfigurationError)?;
+    if configuration.rules.iter().any(|rule| &rule.title == title) {
+        return Err(anyhow!("A rule titled '{}' already exists", title));
+    }
+    Regex::new(&clean_pattern(pattern)?)
+        .map_err(|err| anyhow!("Invalid pattern '{}': {}", pattern, err))?;
+    if let Some(root) = &root {
+        configuration.root.resolve(root)?;
+    }
+
+    let mut rule = Mapping::new();
+    rule.insert("title".into(), title.as_str().into());
+    rule.insert("id".into(), Uuid::new_v4().to_string().into());
+    rule.insert("pattern".into(), pattern.as_str().into());
+    if let Some(directory) = argument_matches.get_one::<String>("dir") {
+        rule.insert("directory".into(), path_components(directory));
+    }
+    if argument_matches.get_flag("copy") {
+        rule.insert("copy".into(), true.into());
+    }
+    if let Some(ranges) = argument_matches.get_many::<String>("range") {
+        let group = argument_matches.get_one::<String>("range-group");
+        rule.insert("function".into(), numeric_range_function(ranges, group)?);
+    }
+
+    let content = fs::read_to_string(&configuration_file)?;
+    let mut document: Value = serde_yaml::from_str(&content)?;
+    let rule = append_rule(&mut document, rule, root)?;
+
+    serde_yaml::from_value::<Config>(document.clone())
+        .map_err(|err| anyhow!("The rule would make the configuration invalid: {}", err))?;
+    write_rules(&configuration_file, &content, &document, &[rule])?;
+
+    info!("Added rule {} to {}", title.bold().blue(), configuration_file.display());
+    Ok(())
+}
+
+pub fn list_rules_command(argument_matches: &ArgMatches) -> Result<()> {
+    let configuration = load_prepared_configuration(argument_matches)?;
+    let today = Local::now().date_naive();
+    for (idx, rule) in configuration.rules.iter().enumerate() {
+        match rule.is_active(today) {
+            true => println!("{}. {}", idx + 1, rule.title.bold().blue()),
+            false => println!("{}. {} {}", idx + 1, rule.title.bold().dimmed(), "(inactive)".yellow()),
+        }
+        print_rule_details(&configuration, rule)?;
+        println!();
+    }
+    Ok(())
+}
+
+pub fn explain_command(argument_matches: &ArgMatches) -> Result<()> {
+    let configuration = load_prepared_configuration(argument_matches)?;
+    let title = argument_matches.get_one::<String>("rule").unwrap();
+    let filename = argument_matches.get_one::<String>("filename").unwrap();
+
+    let rules: Vec<&Rule> = configuration.rules.iter().filter(|rule| &rule.title == title).collect();
+    if rules.is_empty() {
+        return Err(anyhow!("No rule titled '{}'", title));
+    }
+    for rule in rules {
+        println!("{} {}", "Pattern".bold(), rule.old_pattern);
+        match configuration.explain(rule, filename)? {
+            None => println!("    doesn't match {}", filename),
+            Some(destination) => {
+                println!("    {} -> {}", filename, destination.display().to_string().bold().red());
+                if let Some(function) = &rule.function {
+                    println!("    the folder is then picked by {:?} at run time", function);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_prepared_configuration(argument_matches: &ArgMatches) -> Result<Config> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let mut configuration = Config::load(configuration_file).context(ConfigurationError)?;
+    prepare_rules(&mut configuration).context(ConfigurationError)?;
+    Ok(configuration)
+}
+
+fn print_rule_details(configuration: &Config, rule: &Rule) -> Result<()> {
+    let root = configuration.root.resolve(&rule.root_reference())?;
+    let directory = rule.directory.clone().unwrap_or_else(|| PathBuf::from(&rule.title));
+    if let Some(id) = &rule.id {
+        println!("    id:         {}", id);
+    }
+    println!("    pattern:    {}", rule.old_pattern);
+    println!("    filename:   {}", rule.rename_to.as_ref().unwrap_or(&rule.new_pattern));
+    println!("    target:     {}", root.join(directory).display());
+    if let Some(max_matches) = rule.max_matches_per_run {
+        println!("    limit:      {} matches per run", max_matches);
+    }
+    if let Some(max_files) = rule.max_files_per_directory {
+        println!("    sharded:    after {} files", max_files);
+    }
+    if let Some(function) = &rule.function {
+        println!("    function:   {:?}", function);
+    }
+    for processor in rule.processors.iter().flatten() {
+        println!("    processor:  {}", describe_processor(processor));
+    }
+    if !rule.enabled {
+        println!("    enabled:    no");
+    }
+    if rule.active_from.is_some() || rule.active_until.is_some() {
+        let format_date = |date: Option<NaiveDate>| date.map(|date| date.to_string()).unwrap_or_else(|| String::from("..."));
+        println!("    active:     {} to {}", format_date(rule.active_from), format_date(rule.active_until));
+    }
+    if let Some(mode) = &rule.chmod {
+        println!("    chmod:      {}", mode);
+    }
+    if let Some(owner) = &rule.chown {
+        println!("    chown:      {}", owner);
+    }
+    for (name, value) in &rule.xattrs {
+        println!("    xattr:      {}={}", name, value);
+    }
+    if !rule.finder_tags.is_empty() {
+        println!("    finder:     {}", rule.finder_tags.join(", "));
+    }
+    if !rule.companions.is_empty() {
+        println!("    companions: {}", rule.companions.join(", "));
+    }
+    if let Some(sidecar) = &rule.sidecar {
+        println!("    sidecar:    {}", sidecar.fields.keys().cloned().collect::<Vec<_>>().join(", "));
+    }
+    if !rule.plugins.is_empty() {
+        println!("    plugins:    {}", rule.plugins.join(", "));
+    }
+    if !rule.tags.is_empty() {
+        println!("    tags:       {}", rule.tags.join(", "));
+    }
+    match &rule.archive {
+        Some(archive) => println!(
+            "    action:     archive into {}.{} after {} days",
+            archive.name, archive.format.extension(), archive.older_than_days
+        ),
+        None => println!("    action:     {}{}", if rule.copy { "copy" } else { "move" }, if rule.match_directories { " directories" } else { "" }),
+    }
+    if let Some(checksum) = &rule.checksum {
+        println!("    checksum:   {}", checksum.extension());
+    }
+    if let Some(chained) = &rule.then {
+        println!("    then:       {}", chained.title.blue());
+        print_rule_details(configuration, chained)?;
+    }
+    Ok(())
+}
+
+fn describe_processor(processor: &ConfigProcessor) -> String {
+    let mut parts = vec![];
+    if let Some(when) = &processor.when {
+        parts.push(format!("when {}", when));
+    }
+    if let (Some(splitter), Some(date_format)) = (&processor.splitter, &processor.date_format) {
+        parts.push(format!("date split on '{}' as '{}'", splitter, date_format));
+    }
+    if let Some(pattern) = &processor.pattern {
+        parts.push(format!("replace '{}' with '{}'", pattern, processor.replacement.as_deref().unwrap_or_default()));
+    }
+    if let Some(form) = &processor.normalize {
+        parts.push(format!("{} normalized", format!("{:?}", form).to_uppercase()));
+    }
+    if let Some(group) = &processor.numerals {
+        parts.push(format!("numerals in group {}", group));
+    }
+    if let Some(width) = processor.pad_numbers {
+        parts.push(format!("pad numbers to {}", width));
+    }
+    if processor.transliterate {
+        parts.push(String::from("transliterate"));
+    }
+    if let Some(case) = &processor.case {
+        parts.push(format!("{:?} case", case).to_lowercase());
+    }
+    parts.join(", ")
+}
+
+pub fn parse_root_reference(root: &str) -> RootReference {
+    match root.parse::<usize>() {
+        Ok(index) => RootReference::Index(index),
+        Err(_) => RootReference::Name(root.to_string()),
+    }
+}
+
+// `--range 1-50:Vol1 --range default:Misc` as a `numeric_range` function.
+fn numeric_range_function<'a>(ranges: impl Iterator<Item = &'a String>, group: Option<&String>) -> Result<Value> {
+    let mut folders = Mapping::new();
+    for range in ranges {
+        let (range, folder) = range.split_once(':')
+            .ok_or(anyhow!("'{}' should be a range and a folder, such as 1-50:Vol1", range))?;
+        folders.insert(range.trim().into(), folder.trim().into());
+    }
+    let mut function = Mapping::new();
+    function.insert("name".into(), "numeric_range".into());
+    if let Some(group) = group {
+        let group = match group.parse::<u64>() {
+            Ok(index) => Value::from(index),
+            Err(_) => Value::from(group.as_str()),
+        };
+        function.insert("group".into(), group);
+    }
+    function.insert("ranges".into(), Value::Mapping(folders));
+    let function = Value::Mapping(function);
+    serde_yaml::from_value::<TransformativeFunction>(function.clone())?.validate()?;
+    Ok(function)
+}
+
+// Directories are written as component lists, like the rest of the configuration's paths.
+fn path_components(directory: &str) -> Value {
+    Value::Sequence(directory
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .map(Value::from)
+        .collect())
+}
+
+/// Writes the configuration once rules were added to `document`. Where the new rules only
+/// need appending to the list of rules, they're added to the text and the rest of the file,
+/// comments and all, stays as it was. Otherwise the whole document is written anew, which
+/// loses the comments, so the old file is kept next to it as a backup.
+pub(crate) fn write_rules(configuration_file: &Path, content: &str, document: &Value, added: &[Mapping]) -> Result<()> {
+    if let Some(appended) = appended_rules(content, added) {
+        if serde_yaml::from_str::<Value>(&appended).is_ok_and(|appended| appended == *document) {
+            return fs::write(configuration_file, appended)
+                .map_err(|err| anyhow!("Couldn't write {}: {}", configuration_file.display(), err));
+        }
+    }
+    let mut backup = configuration_file.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    fs::copy(configuration_file, &backup)
+        .map_err(|err| anyhow!("Couldn't back up {}: {}", configuration_file.display(), err))?;
+    eprintln!(
+        "{} was rewritten and lost its comments and formatting, the old one is kept as {}",
+        configuration_file.display(), backup.display()
+    );
+    fs::write(configuration_file, serde_yaml::to_string(document)?)
+        .map_err(|err| anyhow!("Couldn't write {}: {}", configuration_file.display(), err))
+}
+
+// The configuration's text with the rules added after the last entry of the top-level
+// `rules:` list, indented like the entries already there.
+fn appended_rules(content: &str, added: &[Mapping]) -> Option<String> {
+    if added.is_empty() {
+        return Some(content.to_string());
+    }
+    let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+    let start = lines.iter().position(|line| {
+        line.strip_prefix("rules:").is_some_and(|rest| rest.trim().is_empty() || rest.trim_start().starts_with('#'))
+    })?;
+    let block = &lines[start + 1..];
+    let end = block.iter()
+        .position(|line| !line.trim().is_empty() && !line.starts_with([' ', '\t', '-', '#']))
+        .unwrap_or(block.len());
+    // Blank lines and comments after the last entry stay below the new rules.
+    let last = block[..end].iter()
+        .rposition(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map_or(start + 1, |last| start + 2 + last);
+    let indent = block[..end].iter()
+        .find(|line| line.trim_start().starts_with('-'))
+        .map_or("", |line| &line[..line.len() - line.trim_start().len()]);
+
+    let rules = serde_yaml::to_string(&Value::Sequence(added.iter().cloned().map(Value::Mapping).collect())).ok()?;
+    let mut appended = lines[..last].concat();
+    if !appended.ends_with('\n') {
+        appended.push('\n');
+    }
+    for line in rules.lines() {
+        appended.push_str(indent);
+        appended.push_str(line);
+        appended.push('\n');
+    }
+    appended.push_str(&lines[last..].concat());
+    Some(appended)
+}
+
+// Rules grouped per root get appended to their root's group, flat rule lists get a `root` key.
+// Returns the rule as it was appended.
+pub(crate) fn append_rule(document: &mut Value, mut rule: Mapping, root: Option<RootReference>) -> Result<Mapping> {
+    let rules = document.get_mut("rules")
+        .and_then(Value::as_sequence_mut)
+        .ok_or(anyhow!("The configuration has no list of rules"))?;
+    let grouped = rules.first().is_some_and(Value::is_sequence);
+
+    match (grouped, root) {
+        (true, Some(RootReference::Index(index))) => {
+            while rules.len() <= index {
+                rules.push(Value::Sequence(vec![]));
+            }
+            rules[index].as_sequence_mut().unwrap().push(Value::Mapping(rule.clone()));
+        }
+        (true, root) => {
+            if let Some(RootReference::Name(name)) = root {
+                rule.insert("root".into(), name.into());
+            }
+            rules[0].as_sequence_mut().unwrap().push(Value::Mapping(rule.clone()));
+        }
+        (false, root) => {
+            match root {
+                Some(RootReference::Index(index)) if index != 0 => {
+                    rule.insert("root".into(), (index as u64).into());
+                }
+                Some(RootReference::Name(name)) => {
+                    rule.insert("root".into(), name.into());
+                }
+                _ => {}
+            }
+            rules.push(Value::Mapping(rule.clone()));
+        }
+    }
+    Ok(rule)
+}
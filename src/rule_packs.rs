@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use atty::Stream;
+use clap::ArgMatches;
+use colored::Colorize;
+use serde_yaml::{Mapping, Value};
+
+use crate::configuration::get_values;
+use crate::rule_commands::{append_rule, write_rules};
+use crate::{configuration_path, prepare_rules, Config, ConfigurationError};
+
+/// Writes the rules of the configuration, or the ones with the given tags or titles, as a rule
+/// pack that `import-rules` can merge into another configuration.
+pub fn export_rules_command(argument_matches: &ArgMatches) -> Result<()> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let content = fs::read_to_string(&configuration_file)?;
+    let document: Value = serde_yaml::from_str(&content).context(ConfigurationError)?;
+    let tags = get_values(argument_matches, "tags");
+    let titles = get_values(argument_matches, "rule");
+
+    let rules = rule_list(&document)?
+        .into_iter()
+        .filter(|rule| titles.is_empty() || title(rule).is_some_and(|title| titles.contains(&title)))
+        .filter(|rule| tags.is_empty() || rule_tags(rule).iter().any(|tag| tags.contains(tag)))
+        .map(Value::Mapping)
+        .collect::<Vec<_>>();
+    if rules.is_empty() {
+        return Err(anyhow!("No rules to export"));
+    }
+
+    let mut pack = Mapping::new();
+    pack.insert("rules".into(), Value::Sequence(rules));
+    let output = serde_yaml::to_string(&pack)?;
+    match argument_matches.get_one::<String>("output") {
+        Some(path) => fs::write(path, output)?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
+
+/// Merges the rules of a rule pack into the configuration. Rules identical to an existing one
+/// are left out, for other rules with an id or title that's already taken the user decides.
+pub fn import_rules_command(argument_matches: &ArgMatches) -> Result<()> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let pack_file = PathBuf::from(argument_matches.get_one::<String>("file").unwrap());
+    let pack: Value = serde_yaml::from_str(&fs::read_to_string(&pack_file)?)
+        .map_err(|err| anyhow!("Couldn't read rule pack {}: {}", pack_file.display(), err))?;
+    let content = fs::read_to_string(&configuration_file)?;
+    let mut document: Value = serde_yaml::from_str(&content).context(ConfigurationError)?;
+
+    let (mut added, mut replaced, mut skipped) = (vec![], 0, 0);
+    for rule in rule_list(&pack)? {
+        let title = title(&rule).ok_or(anyhow!("Every rule in the pack needs a title"))?;
+        let existing_rules = rule_list(&document)?;
+        let existing = same_rule(existing_rules.iter().map(Some), &rule).map(|idx| &existing_rules[idx]);
+        match existing {
+            None => {
+                added.push(append_rule(&mut document, rule, None)?);
+            }
+            Some(existing) if *existing == rule => skipped += 1,
+            Some(_) if should_replace(argument_matches, &title)? => {
+                replace_rule(&mut document, rule)?;
+                replaced += 1;
+            }
+            Some(_) => {
+                info!("Keeping the existing rule {}", title.bold().blue());
+                skipped += 1;
+            }
+        }
+    }
+
+    let mut configuration = serde_yaml::from_value::<Config>(document.clone())
+        .map_err(|err| anyhow!("The imported rules would make the configuration invalid: {}", err))?;
+    prepare_rules(&mut configuration)
+        .map_err(|err| anyhow!("The imported rules would make the configuration invalid: {}", err))?;
+    write_rules(&configuration_file, &content, &document, &added)?;
+
+    info!(
+        "Imported {} new and {} replaced rules into {}, {} left out.",
+        added.len().to_string().bold(), replaced.to_string().bold(), configuration_file.display(), skipped
+    );
+    Ok(())
+}
+
+fn should_replace(argument_matches: &ArgMatches, title: &str) -> Result<bool> {
+    if argument_matches.get_flag("replace") {
+        return Ok(true);
+    }
+    if argument_matches.get_flag("skip-existing") || !atty::is(Stream::Stdin) {
+        return Ok(false);
+    }
+    print!("A different rule titled {} already exists, replace it? [y/N] ", title.bold().blue());
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Rules grouped per root get a `root` key with their group's index, so they keep their root
+// when they're merged into a flat list.
+fn rule_list(document: &Value) -> Result<Vec<Mapping>> {
+    let rules = match document {
+        Value::Sequence(rules) => rules,
+        _ => document.get("rules")
+            .and_then(Value::as_sequence)
+            .ok_or(anyhow!("No list of rules found"))?,
+    };
+    let mut list = vec![];
+    for (index, entry) in rules.iter().enumerate() {
+        match entry {
+            Value::Sequence(group) => {
+                for rule in group.iter().filter_map(Value::as_mapping) {
+                    let mut rule = rule.clone();
+                    if index != 0 && !rule.contains_key("root") {
+                        rule.insert("root".into(), (index as u64).into());
+                    }
+                    list.push(rule);
+                }
+            }
+            Value::Mapping(rule) => list.push(rule.clone()),
+            _ => {}
+        }
+    }
+    Ok(list)
+}
+
+fn replace_rule(document: &mut Value, rule: Mapping) -> Result<()> {
+    let rules = document.get_mut("rules")
+        .and_then(Value::as_sequence_mut)
+        .ok_or(anyhow!("The configuration has no list of rules"))?;
+    let mut entries = rules.iter_mut()
+        .flat_map(|entry| match entry {
+            Value::Sequence(group) => group.iter_mut().collect::<Vec<_>>(),
+            other => vec![other],
+        })
+        .collect::<Vec<_>>();
+    let idx = same_rule(entries.iter().map(|entry| entry.as_mapping()), &rule)
+        .ok_or(anyhow!("No rule titled '{}' found", title(&rule).unwrap_or_default()))?;
+    *entries[idx] = Value::Mapping(rule);
+    Ok(())
+}
+
+// Position of the rule `rule` would take the place of. Rules with the same id are the same rule
+// even after one of them was renamed, otherwise a rule of the same title is, whatever its id.
+fn same_rule<'a>(mut rules: impl Iterator<Item = Option<&'a Mapping>> + Clone, rule: &Mapping) -> Option<usize> {
+    let id = rule.get("id");
+    let title = title(rule);
+    rules.clone()
+        .position(|existing| id.is_some() && existing.and_then(|existing| existing.get("id")) == id)
+        .or_else(|| rules.position(|existing| title.is_some() && existing.and_then(self::title) == title))
+}
+
+fn title(rule: &Mapping) -> Option<String> {
+    rule.get("title").and_then(Value::as_str).map(String::from)
+}
+
+fn rule_tags(rule: &Mapping) -> Vec<String> {
+    rule.get("tags")
+        .and_then(Value::as_sequence)
+        .map(|tags| tags.iter().filter_map(Value::as_str).map(String::from).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,145 @@
+use std::env;
+use std::fs::{self, create_dir_all};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use colored::Colorize;
+
+use crate::cli::{SERVICE_SYSTEMD, SERVICE_TASK};
+
+const SERVICE_NAME: &str = "fsort";
+const SYSTEMD_UNITS_FOLDER: &str = "/etc/systemd/system";
+
+pub fn service_command(argument_matches: &ArgMatches) -> Result<()> {
+    match argument_matches.subcommand() {
+        Some(("install", sub_matches)) => install(sub_matches),
+        Some((name, _)) => Err(anyhow!("Unknown service command '{}'", name)),
+        None => Err(anyhow!("No service command given")),
+    }
+}
+
+/// Writes the files running `fsort --system` every few minutes, a systemd service with its
+/// timer or a Scheduled Task for `schtasks /Create /XML`.
+fn install(argument_matches: &ArgMatches) -> Result<()> {
+    let format = argument_matches.get_one::<String>("format").unwrap();
+    let interval = *argument_matches.get_one::<u64>("interval").unwrap();
+    let system = argument_matches.get_one::<String>("system").unwrap();
+    let executable = env::current_exe()?;
+
+    let files = match format.as_str() {
+        SERVICE_SYSTEMD => vec![
+            (format!("{}.service", SERVICE_NAME), systemd_service(&executable, system)),
+            (format!("{}.timer", SERVICE_NAME), systemd_timer(interval)),
+        ],
+        SERVICE_TASK => vec![(format!("{}.xml", SERVICE_NAME), scheduled_task(&executable, system, interval))],
+        _ => return Err(anyhow!("Unknown service format '{}'", format)),
+    };
+
+    if argument_matches.get_flag("print") {
+        for (name, content) in files {
+            println!("{}", format!("# {}", name).bold());
+            println!("{}", content);
+        }
+        return Ok(());
+    }
+
+    let folder = match argument_matches.get_one::<String>("output") {
+        Some(output) => PathBuf::from(output),
+        None if format == SERVICE_SYSTEMD => PathBuf::from(SYSTEMD_UNITS_FOLDER),
+        None => env::current_dir()?,
+    };
+    create_dir_all(&folder).map_err(|err| anyhow!("Couldn't create {}: {}", folder.display(), err))?;
+    for (name, content) in &files {
+        let path = folder.join(name);
+        let content = match format.as_str() {
+            SERVICE_TASK => utf16(content),
+            _ => content.clone().into_bytes(),
+        };
+        fs::write(&path, content).map_err(|err| anyhow!("Couldn't write {}: {}", path.display(), err))?;
+        info!("Wrote {}", path.display());
+    }
+    match format.as_str() {
+        SERVICE_SYSTEMD => info!("Start it with `systemctl daemon-reload && systemctl enable --now {}.timer`", SERVICE_NAME),
+        _ => info!("Register it with `schtasks /Create /XML \"{}\" /TN {}`", folder.join(&files[0].0).display(), SERVICE_NAME),
+    }
+    Ok(())
+}
+
+fn systemd_service(executable: &Path, system: &str) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Sort the download folders of the users in {system}\n\
+         After=local-fs.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart=\"{}\" --system \"{system}\"\n",
+        executable.display(),
+    )
+}
+
+fn systemd_timer(interval: u64) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Run {SERVICE_NAME} every {interval} minutes\n\
+         \n\
+         [Timer]\n\
+         OnBootSec=5min\n\
+         OnUnitActiveSec={interval}min\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n"
+    )
+}
+
+fn scheduled_task(executable: &Path, system: &str, interval: u64) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-16\"?>\r\n\
+         <Task version=\"1.2\" xmlns=\"http://schemas.microsoft.com/windows/2004/02/mit/task\">\r\n\
+         \x20 <RegistrationInfo>\r\n\
+         \x20   <Description>Sort the download folders of the users in {}</Description>\r\n\
+         \x20 </RegistrationInfo>\r\n\
+         \x20 <Triggers>\r\n\
+         \x20   <BootTrigger>\r\n\
+         \x20     <Repetition>\r\n\
+         \x20       <Interval>PT{interval}M</Interval>\r\n\
+         \x20     </Repetition>\r\n\
+         \x20     <Enabled>true</Enabled>\r\n\
+         \x20   </BootTrigger>\r\n\
+         \x20 </Triggers>\r\n\
+         \x20 <Principals>\r\n\
+         \x20   <Principal id=\"Author\">\r\n\
+         \x20     <UserId>S-1-5-18</UserId>\r\n\
+         \x20     <RunLevel>HighestAvailable</RunLevel>\r\n\
+         \x20   </Principal>\r\n\
+         \x20 </Principals>\r\n\
+         \x20 <Settings>\r\n\
+         \x20   <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>\r\n\
+         \x20   <StartWhenAvailable>true</StartWhenAvailable>\r\n\
+         \x20   <ExecutionTimeLimit>PT1H</ExecutionTimeLimit>\r\n\
+         \x20 </Settings>\r\n\
+         \x20 <Actions Context=\"Author\">\r\n\
+         \x20   <Exec>\r\n\
+         \x20     <Command>{}</Command>\r\n\
+         \x20     <Arguments>--system \"{}\"</Arguments>\r\n\
+         \x20   </Exec>\r\n\
+         \x20 </Actions>\r\n\
+         </Task>\r\n",
+        xml_escape(system), xml_escape(&executable.display().to_string()), xml_escape(system),
+    )
+}
+
+// Task Scheduler expects the XML in UTF-16, with a byte order mark.
+fn utf16(text: &str) -> Vec<u8> {
+    let mut content = vec![0xFF, 0xFE];
+    content.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    content
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,339 @@
+use std::env;
+use std::fs;
+
+use anyhow::Result;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use colored::Colorize;
+
+use crate::configuration_path;
+
+const MENU_LABEL: &str = "Sort with fsort";
+
+/// Adds an entry running `fsort single` on the clicked file or folder to the file manager's
+/// context menu, reading the configuration or profile given to this command.
+pub fn register_shell_command(argument_matches: &ArgMatches) -> Result<()> {
+    let command = single_command(argument_matches)?;
+    for location in platform::register(&command)? {
+        info!("Wrote {}", location);
+    }
+    info!("Added {} to the context menu", MENU_LABEL.bold());
+    Ok(())
+}
+
+pub fn unregister_shell_command(_argument_matches: &ArgMatches) -> Result<()> {
+    let removed = platform::unregister()?;
+    if removed.is_empty() {
+        info!("{} isn't in the context menu", MENU_LABEL.bold());
+    }
+    for location in removed {
+        info!("Removed {}", location);
+    }
+    Ok(())
+}
+
+// The program and arguments the menu entry runs, without the path it's given.
+fn single_command(argument_matches: &ArgMatches) -> Result<Vec<String>> {
+    let mut command = vec![env::current_exe()?.display().to_string()];
+    if let Some(profile) = argument_matches.get_one::<String>("profile") {
+        command.extend(["--profile".to_string(), profile.clone()]);
+    } else if argument_matches.value_source("config") == Some(ValueSource::CommandLine) {
+        let config = fs::canonicalize(configuration_path(argument_matches)?)?;
+        command.extend(["--config".to_string(), config.display().to_string()]);
+    }
+    command.push("single".to_string());
+    Ok(command)
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::process::Command;
+
+    use anyhow::{anyhow, Result};
+
+    use super::MENU_LABEL;
+
+    // Files and folders, for the current user only.
+    const KEYS: [&str; 2] = [r"HKCU\Software\Classes\*\shell\fsort", r"HKCU\Software\Classes\Directory\shell\fsort"];
+
+    pub fn register(command: &[String]) -> Result<Vec<String>> {
+        let command_line = command.iter()
+            .chain([&"%1".to_string()])
+            .map(|argument| format!("\"{}\"", argument))
+            .collect::<Vec<_>>()
+            .join(" ");
+        for key in KEYS {
+            reg(&["add", key, "/ve", "/d", MENU_LABEL, "/f"])?;
+            let command_key = format!(r"{}\command", key);
+            reg(&["add", command_key.as_str(), "/ve", "/d", command_line.as_str(), "/f"])?;
+        }
+        Ok(KEYS.map(String::from).to_vec())
+    }
+
+    pub fn unregister() -> Result<Vec<String>> {
+        let mut removed = vec![];
+        for key in KEYS {
+            if reg(&["query", key]).is_ok() {
+                reg(&["delete", key, "/f"])?;
+                removed.push(key.to_string());
+            }
+        }
+        Ok(removed)
+    }
+
+    fn reg(arguments: &[&str]) -> Result<()> {
+        let output = Command::new("reg").args(arguments).output()
+            .map_err(|err| anyhow!("Couldn't run reg: {}", err))?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(anyhow!("reg {} failed: {}", arguments[0], String::from_utf8_lossy(&output.stderr).trim())),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::fs::{self, create_dir_all};
+    use std::path::PathBuf;
+
+    use anyhow::{anyhow, Result};
+    use directories::BaseDirs;
+
+    use super::MENU_LABEL;
+
+    fn workflow() -> Result<PathBuf> {
+        let folders = BaseDirs::new().ok_or(anyhow!("Couldn't determine the home folder"))?;
+        Ok(folders.home_dir().join("Library").join("Services").join(format!("{}.workflow", MENU_LABEL)))
+    }
+
+    /// A Quick Action running a shell script on the files selected in Finder.
+    pub fn register(command: &[String]) -> Result<Vec<String>> {
+        let workflow = workflow()?;
+        let contents = workflow.join("Contents");
+        create_dir_all(&contents)?;
+        let script = format!(
+            "for file in \"$@\"; do\n    {} \"$file\"\ndone",
+            command.iter().map(|argument| shell_quote(argument)).collect::<Vec<_>>().join(" "),
+        );
+        fs::write(contents.join("Info.plist"), info_plist())?;
+        fs::write(contents.join("document.wflow"), document(&script))?;
+        Ok(vec![workflow.display().to_string()])
+    }
+
+    pub fn unregister() -> Result<Vec<String>> {
+        let workflow = workflow()?;
+        if !workflow.exists() {
+            return Ok(vec![]);
+        }
+        fs::remove_dir_all(&workflow)?;
+        Ok(vec![workflow.display().to_string()])
+    }
+
+    fn shell_quote(argument: &str) -> String {
+        format!("'{}'", argument.replace('\'', r"'\''"))
+    }
+
+    fn xml_escape(text: &str) -> String {
+        text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    fn info_plist() -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>NSServices</key>
+	<array>
+		<dict>
+			<key>NSMenuItem</key>
+			<dict>
+				<key>default</key>
+				<string>{}</string>
+			</dict>
+			<key>NSMessage</key>
+			<string>runWorkflowAsService</string>
+			<key>NSRequiredContext</key>
+			<dict>
+				<key>NSApplicationIdentifier</key>
+				<string>com.apple.finder</string>
+			</dict>
+			<key>NSSendFileTypes</key>
+			<array>
+				<string>public.item</string>
+			</array>
+		</dict>
+	</array>
+</dict>
+</plist>
+"#,
+            MENU_LABEL,
+        )
+    }
+
+    fn document(script: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>AMApplicationBuild</key>
+	<string>523</string>
+	<key>AMApplicationVersion</key>
+	<string>2.10</string>
+	<key>AMDocumentVersion</key>
+	<string>2</string>
+	<key>actions</key>
+	<array>
+		<dict>
+			<key>action</key>
+			<dict>
+				<key>AMAccepts</key>
+				<dict>
+					<key>Container</key>
+					<string>List</string>
+					<key>Optional</key>
+					<true/>
+					<key>Types</key>
+					<array>
+						<string>com.apple.cocoa.string</string>
+					</array>
+				</dict>
+				<key>AMActionVersion</key>
+				<string>2.0.3</string>
+				<key>AMParameterProperties</key>
+				<dict>
+					<key>COMMAND_STRING</key>
+					<dict/>
+					<key>inputMethod</key>
+					<dict/>
+					<key>shell</key>
+					<dict/>
+				</dict>
+				<key>ActionBundlePath</key>
+				<string>/System/Library/Automator/Run Shell Script.action</string>
+				<key>ActionName</key>
+				<string>Run Shell Script</string>
+				<key>ActionParameters</key>
+				<dict>
+					<key>COMMAND_STRING</key>
+					<string>{}</string>
+					<key>CheckedForUserDefaultShell</key>
+					<true/>
+					<key>inputMethod</key>
+					<integer>1</integer>
+					<key>shell</key>
+					<string>/bin/sh</string>
+					<key>source</key>
+					<string></string>
+				</dict>
+				<key>BundleIdentifier</key>
+				<string>com.apple.RunShellScript</string>
+				<key>CFBundleVersion</key>
+				<string>2.0.3</string>
+				<key>Class Name</key>
+				<string>RunShellScriptAction</string>
+			</dict>
+		</dict>
+	</array>
+	<key>workflowMetaData</key>
+	<dict>
+		<key>serviceInputTypeIdentifier</key>
+		<string>com.apple.Automator.fileSystemObject</string>
+		<key>serviceOutputTypeIdentifier</key>
+		<string>com.apple.Automator.nothing</string>
+		<key>workflowTypeIdentifier</key>
+		<string>com.apple.Automator.servicesMenu</string>
+	</dict>
+</dict>
+</plist>
+"#,
+            xml_escape(script),
+        )
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::fs::{self, create_dir_all};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    use anyhow::{anyhow, Result};
+    use directories::BaseDirs;
+
+    use super::MENU_LABEL;
+
+    // A service menu for Dolphin and other KDE file managers, and a script for Nautilus.
+    fn locations() -> Result<[PathBuf; 2]> {
+        let folders = BaseDirs::new().ok_or(anyhow!("Couldn't determine the home folder"))?;
+        let data = folders.data_dir();
+        Ok([
+            data.join("kio").join("servicemenus").join("fsort.desktop"),
+            data.join("nautilus").join("scripts").join(MENU_LABEL),
+        ])
+    }
+
+    pub fn register(command: &[String]) -> Result<Vec<String>> {
+        let [service_menu, script] = locations()?;
+        let desktop_command = command.iter().map(|argument| desktop_quote(argument)).collect::<Vec<_>>().join(" ");
+        write_executable(&service_menu, &format!(
+            "[Desktop Entry]\n\
+             Type=Service\n\
+             MimeType=all/all;\n\
+             X-KDE-ServiceTypes=KonqPopupMenu/Plugin\n\
+             Actions=sort;\n\
+             \n\
+             [Desktop Action sort]\n\
+             Name={}\n\
+             Icon=folder-move\n\
+             Exec={} %f\n",
+            MENU_LABEL, desktop_command,
+        ))?;
+        let shell_command = command.iter().map(|argument| shell_quote(argument)).collect::<Vec<_>>().join(" ");
+        write_executable(&script, &format!("#!/bin/sh\nfor file in \"$@\"; do\n    {} \"$file\"\ndone\n", shell_command))?;
+        Ok(vec![service_menu.display().to_string(), script.display().to_string()])
+    }
+
+    pub fn unregister() -> Result<Vec<String>> {
+        let mut removed = vec![];
+        for location in locations()? {
+            if location.exists() {
+                fs::remove_file(&location)
+                    .map_err(|err| anyhow!("Couldn't remove {}: {}", location.display(), err))?;
+                removed.push(location.display().to_string());
+            }
+        }
+        Ok(removed)
+    }
+
+    // KDE only reads service menus that are executable.
+    fn write_executable(path: &Path, content: &str) -> Result<()> {
+        if let Some(folder) = path.parent() {
+            create_dir_all(folder)?;
+        }
+        fs::write(path, content).map_err(|err| anyhow!("Couldn't write {}: {}", path.display(), err))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    fn shell_quote(argument: &str) -> String {
+        format!("'{}'", argument.replace('\'', r"'\''"))
+    }
+
+    // Quoting of the desktop entry specification. Its string escapes are undone before the
+    // quoting, so a quoted backslash takes four of them, and `%` starts field codes.
+    fn desktop_quote(argument: &str) -> String {
+        let escaped = argument.chars().fold(String::new(), |mut escaped, character| {
+            match character {
+                '"' | '`' | '$' => escaped.push_str(r"\\"),
+                '\\' => escaped.push_str(r"\\\"),
+                '%' => escaped.push('%'),
+                _ => {}
+            }
+            escaped.push(character);
+            escaped
+        });
+        format!("\"{}\"", escaped)
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::utils::cached_regex;
+
+/// Fields read from the JSON file phones and cameras write next to a photo, such as the
+/// `photo.jpg.json` of Google Takeout or a `photo.json`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MetadataSidecar {
+    /// Names usable as `rename_to` placeholders, mapped to dotted paths into the JSON such as
+    /// `photoTakenTime.timestamp`.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Patterns that named fields have to match for the rule to apply.
+    #[serde(default)]
+    pub when: BTreeMap<String, String>,
+}
+
+impl MetadataSidecar {
+    /// The sidecar next to `file`, the Takeout naming is preferred over the one with the
+    /// extension left out.
+    pub fn path_for(file: &Path) -> Option<PathBuf> {
+        let filename = file.file_name()?.to_str()?;
+        let stem = file.file_stem()?.to_str()?;
+        [format!("{}.json", filename), format!("{}.json", stem)].into_iter()
+            .map(|name| file.with_file_name(name))
+            .find(|sidecar| sidecar != file && sidecar.is_file())
+    }
+
+    /// The configured fields of the file's sidecar. Fields missing from the sidecar, or all of
+    /// them when there's no sidecar, are left out.
+    pub fn read(&self, file: &Path) -> Result<BTreeMap<String, String>> {
+        let Some(sidecar) = MetadataSidecar::path_for(file) else {
+            return Ok(BTreeMap::new());
+        };
+        let document: Value = serde_json::from_slice(&fs::read(&sidecar)?)
+            .map_err(|err| anyhow!("Couldn't read metadata sidecar {}: {}", sidecar.display(), err))?;
+        Ok(self.fields.iter()
+            .filter_map(|(name, path)| field_value(&document, path).map(|value| (name.clone(), value)))
+            .collect())
+    }
+
+    /// Whether each `when` field is in the sidecar and matches its pattern, with what was
+    /// compared. The rule only applies when every one of them does.
+    pub fn matches(&self, values: &BTreeMap<String, String>) -> Result<Vec<(bool, String)>> {
+        self.when.iter()
+            .map(|(name, pattern)| match values.get(name) {
+                Some(value) => Ok((cached_regex(pattern)?.is_match(value), format!("{} `{}` against `{}`", name, value, pattern))),
+                None => Ok((false, format!("{} isn't in the sidecar", name))),
+            })
+            .collect()
+    }
+}
+
+fn field_value(document: &Value, path: &str) -> Option<String> {
+    let pointer = path.split('.').fold(String::new(), |pointer, key| format!("{}/{}", pointer, key));
+    match document.pointer(&pointer)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
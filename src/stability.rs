@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::directory::directory_size;
+use crate::parser::*;
+
+/// Waits for files that are still being written, such as downloads a browser hasn't finished
+/// or copies over the network, before a run sorts them.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StabilityCheck {
+    /// Time between two looks at the files.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    /// Looks after the first one before a file that keeps changing is left for the next run.
+    #[serde(default = "default_stability_retries")]
+    pub retries: u32,
+}
+
+impl StabilityCheck {
+    pub fn new(interval: Duration, retries: u32) -> StabilityCheck {
+        StabilityCheck { interval, retries }
+    }
+
+    /// The files whose size or modification time still changed on the last look. All files
+    /// are checked together, so the run waits at most `interval` times `retries`.
+    pub fn unsettled(&self, files: &[PathBuf]) -> Vec<PathBuf> {
+        let mut pending = files.iter()
+            .map(|file| (file.clone(), snapshot(file)))
+            .collect::<HashMap<_, _>>();
+        for _ in 0..self.retries {
+            if pending.is_empty() {
+                break;
+            }
+            thread::sleep(self.interval);
+            // Files that are gone by now are no longer the run's concern.
+            pending.retain(|file, previous| match snapshot(file) {
+                None => false,
+                current if current == *previous => false,
+                current => {
+                    *previous = current;
+                    true
+                }
+            });
+        }
+        let mut unsettled = pending.into_keys().collect::<Vec<_>>();
+        unsettled.sort();
+        unsettled
+    }
+}
+
+// The size and modification time of a file, or of everything in a folder.
+fn snapshot(file: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = file.metadata().ok()?;
+    let size = match metadata.is_dir() {
+        true => directory_size(file).ok()?,
+        false => metadata.len(),
+    };
+    Some((size, metadata.modified().ok()?))
+}
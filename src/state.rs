@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::{APPLICATION, ORGANIZATION, QUALIFIER};
+
+const STATE_FILE: &str = "state.yaml";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FailureRecord {
+    pub count: u32,
+    pub last_error: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QuarantineEntry {
+    pub error: String,
+    /// Where the file was moved to, or `None` when it was left in place and is only skipped.
+    pub location: Option<PathBuf>,
+}
+
+/// Information kept between runs, stored next to the configuration in the user's data folder.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct StateStore {
+    #[serde(default)]
+    pub failures: BTreeMap<PathBuf, FailureRecord>,
+    #[serde(default)]
+    pub quarantined: BTreeMap<PathBuf, QuarantineEntry>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn default_path() -> Result<PathBuf> {
+        let folder = ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION)
+            .ok_or(anyhow!("Couldn't determine the data folder"))?;
+        Ok(folder.data_dir().join(STATE_FILE))
+    }
+
+    pub fn load() -> Result<StateStore> {
+        StateStore::load_from(StateStore::default_path()?)
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<StateStore> {
+        let mut state: StateStore = match fs::read_to_string(&path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .map_err(|err| anyhow!("Couldn't read state from {}: {}", path.display(), err))?,
+            Err(_) => StateStore::default(),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_yaml::to_string(self)?)
+            .map_err(|err| anyhow!("Couldn't write state to {}: {}", self.path.display(), err))
+    }
+
+    /// Records a failure for the file and returns how many times it has failed so far.
+    pub fn record_failure(&mut self, file: &Path, error: &anyhow::Error) -> u32 {
+        let record = self.failures.entry(file.to_path_buf()).or_insert(FailureRecord {
+            count: 0,
+            last_error: String::new(),
+        });
+        record.count += 1;
+        record.last_error = error.to_string();
+        record.count
+    }
+
+    pub fn clear_failures(&mut self, file: &Path) {
+        self.failures.remove(file);
+    }
+
+    pub fn is_quarantined(&self, file: &Path) -> bool {
+        self.quarantined.contains_key(file)
+    }
+}
@@ -0,0 +1,180 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use serde::Deserialize;
+
+#[cfg(unix)]
+use crate::accounts;
+use crate::parser::*;
+use crate::{ConfigurationError, EXIT_FAILURE, EXIT_NOTHING_TO_DO, EXIT_PARTIAL_FAILURE, EXIT_SUCCESS};
+
+// Priority prefixes journald reads from the start of a line, see sd-daemon(3).
+const JOURNAL_INFO: &str = "<6>";
+const JOURNAL_ERROR: &str = "<3>";
+
+/// Users whose download folders a service sorts, read from the file given to `--system`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SystemConfig {
+    pub users: Vec<SystemUser>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SystemUser {
+    /// Account the user's configuration runs as, the service's own when left out.
+    pub user: Option<String>,
+    /// The user's configuration, by default the one in their configuration folder.
+    #[serde(default, deserialize_with = "deserialize_from_array_to_optional_pathbuf")]
+    pub config: Option<PathBuf>,
+}
+
+impl SystemConfig {
+    pub fn load(file: &Path) -> Result<SystemConfig> {
+        let content = fs::read_to_string(file)
+            .map_err(|err| anyhow!("Couldn't read system configuration {}: {}", file.display(), err))?;
+        let config: SystemConfig = serde_yaml::from_str(&content)?;
+        if config.users.is_empty() {
+            return Err(anyhow!("No users are listed in {}", file.display()));
+        }
+        Ok(config)
+    }
+}
+
+impl SystemUser {
+    fn name(&self) -> String {
+        match (&self.user, &self.config) {
+            (Some(user), _) => user.clone(),
+            (None, Some(config)) => config.display().to_string(),
+            (None, None) => "default".to_string(),
+        }
+    }
+
+    // Each user is sorted by a separate fsort process, which can run with the user's
+    // permissions and finds their configuration, lock and state in their home folder.
+    fn command(&self, dry_run: bool) -> Result<Command> {
+        let mut command = Command::new(env::current_exe()?);
+        command.arg("--no-color")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if dry_run {
+            command.arg("--dry");
+        }
+        if let Some(config) = &self.config {
+            command.arg("--config").arg(config);
+        }
+        if let Some(user) = &self.user {
+            run_as(&mut command, user)?;
+        }
+        Ok(command)
+    }
+
+    fn run(&self, dry_run: bool) -> Result<u8> {
+        let name = self.name();
+        let mut child = self.command(dry_run)?
+            .spawn()
+            .map_err(|err| anyhow!("Couldn't start fsort: {}", err))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("No output to read"))?;
+        let stderr = child.stderr.take().ok_or(anyhow!("No output to read"))?;
+        thread::scope(|scope| {
+            scope.spawn(|| forward(stderr, JOURNAL_ERROR, &name));
+            forward(stdout, JOURNAL_INFO, &name);
+        });
+        let status = child.wait()?;
+        Ok(status.code().map_or(EXIT_FAILURE, |code| code as u8))
+    }
+}
+
+/// Sorts the download folder of every user in the system configuration one after another. A
+/// user whose run fails doesn't stop the others.
+pub fn system_command(argument_matches: &ArgMatches) -> Result<u8> {
+    let path = PathBuf::from(argument_matches.get_one::<String>("system").unwrap());
+    let system = SystemConfig::load(&path).context(ConfigurationError)?;
+    let dry_run = argument_matches.get_flag("dry");
+
+    let mut failed = 0;
+    for user in &system.users {
+        let name = user.name();
+        log(JOURNAL_INFO, &name, "Sorting");
+        match user.run(dry_run) {
+            Ok(EXIT_SUCCESS | EXIT_NOTHING_TO_DO) => {}
+            Ok(code) => {
+                log(JOURNAL_ERROR, &name, &format!("fsort exited with code {}", code));
+                failed += 1;
+            }
+            Err(err) => {
+                log(JOURNAL_ERROR, &name, &format!("{:#}", err));
+                failed += 1;
+            }
+        }
+    }
+    Ok(match failed {
+        0 => EXIT_SUCCESS,
+        _ if failed == system.users.len() => EXIT_FAILURE,
+        _ => EXIT_PARTIAL_FAILURE,
+    })
+}
+
+fn forward<R: Read>(output: R, priority: &str, name: &str) {
+    for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+        let line = String::from_utf8_lossy(&line);
+        if !line.trim().is_empty() {
+            log(priority, name, line.trim_end());
+        }
+    }
+}
+
+// systemd sets JOURNAL_STREAM when the output goes to the journal, the prefix then sets the
+// priority of the entry.
+fn log(priority: &str, name: &str, message: &str) {
+    let prefix = match env::var_os("JOURNAL_STREAM") {
+        Some(_) => priority,
+        None => "",
+    };
+    match priority {
+        JOURNAL_ERROR => eprintln!("{}[{}] {}", prefix, name, message),
+        _ => println!("{}[{}] {}", prefix, name, message),
+    }
+}
+
+// The ids are changed in the child before it runs, together with the groups the user is a
+// member of, so folders shared through a group stay readable.
+#[cfg(unix)]
+fn run_as(command: &mut Command, user: &str) -> Result<()> {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+
+    let account = accounts::user(user)?.ok_or(anyhow!("No user named {} found", user))?;
+    let groups = accounts::groups(user, account.gid)?;
+    let (uid, gid) = (account.uid, account.gid);
+    command.current_dir(&account.home)
+        .env("HOME", &account.home)
+        .env("USER", user)
+        .env("LOGNAME", user)
+        .env_remove("XDG_CONFIG_HOME")
+        .env_remove("XDG_DATA_HOME")
+        .env_remove("XDG_CACHE_HOME");
+    // setgroups, setgid and setuid are safe to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_as(_command: &mut Command, user: &str) -> Result<()> {
+    Err(anyhow!("Running as {} is only supported on Unix, list the configuration of each user instead", user))
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use regex::Captures;
+
+use crate::utils::pad_number;
+
+/// Renders a `rename_to` template such as `{series} #{issue}.{ext}`.
+///
+/// Placeholders refer to capture groups of the rule's pattern, either by number or by name.
+/// `{filename}`, `{stem}` and `{ext}` refer to the source file, names of `variables` come
+/// before capture groups. Literal braces are written as `{{` and `}}`.
+///
+/// Values pass through the filters after the name in turn, such as
+/// `{series|lower|replace:' ':'_'}`: `lower`, `upper`, `trim`, `replace:from:to`, `pad:width`,
+/// `substring:start:end` (counted in characters, the end is optional) and `default:value`,
+/// which fills in for a missing or empty value.
+pub fn render_template(template: &str, source: &Path, captures: Option<&Captures>, variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut characters = template.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '{' if characters.peek() == Some(&'{') => {
+                characters.next();
+                result.push('{');
+            }
+            '}' if characters.peek() == Some(&'}') => {
+                characters.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match characters.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(anyhow!("Unclosed placeholder in template '{}'", template)),
+                    }
+                }
+                result.push_str(&render_placeholder(&placeholder, source, captures, variables)?);
+            }
+            '}' => return Err(anyhow!("Unmatched '}}' in template '{}'", template)),
+            _ => result.push(character),
+        }
+    }
+    Ok(result)
+}
+
+/// Turns a `rename_to` template into a regular expression matching any name it can render.
+pub fn template_pattern(template: &str) -> Result<String> {
+    let mut pattern = String::from("^");
+    let mut literal = String::new();
+    let mut characters = template.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '{' | '}' if characters.peek() == Some(&character) => {
+                characters.next();
+                literal.push(character);
+            }
+            '{' => {
+                if !characters.by_ref().any(|c| c == '}') {
+                    return Err(anyhow!("Unclosed placeholder in template '{}'", template));
+                }
+                pattern.push_str(&regex::escape(&literal));
+                pattern.push_str(".+?");
+                literal.clear();
+            }
+            '}' => return Err(anyhow!("Unmatched '}}' in template '{}'", template)),
+            _ => literal.push(character),
+        }
+    }
+    pattern.push_str(&regex::escape(&literal));
+    pattern.push('$');
+    Ok(pattern)
+}
+
+fn render_placeholder(placeholder: &str, source: &Path, captures: Option<&Captures>, variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut parts = split_unquoted(placeholder, '|').into_iter();
+    let head = parts.next().unwrap_or_default();
+    let (name, format_spec) = match head.split_once(':') {
+        Some((name, format_spec)) => (name.trim(), Some(format_spec.trim())),
+        None => (head.trim(), None),
+    };
+    let filters = parts.map(|filter| parse_filter(&filter)).collect::<Vec<_>>();
+    let default = filters.iter()
+        .find(|(filter, _)| filter == "default")
+        .map(|(_, arguments)| arguments.first().cloned().unwrap_or_default());
+
+    let mut value = match (resolve_value(name, source, captures, variables), default) {
+        (Ok(value), Some(default)) if value.is_empty() => default,
+        (Ok(value), _) => value,
+        (Err(_), Some(default)) => default,
+        (Err(err), None) => return Err(err),
+    };
+    if let Some(format_spec) = format_spec {
+        value = format_value(&value, format_spec)
+            .map_err(|err| anyhow!("Placeholder '{}': {}", name, err))?;
+    }
+    for (filter, arguments) in &filters {
+        value = apply_filter(filter, arguments, value)
+            .map_err(|err| anyhow!("Placeholder '{}': {}", name, err))?;
+    }
+    Ok(value)
+}
+
+// Only zero-padding (`03`) is supported for now.
+fn format_value(value: &str, format_spec: &str) -> Result<String> {
+    let width = format_spec.strip_prefix('0')
+        .and_then(|width| width.parse::<usize>().ok())
+        .ok_or(anyhow!("unknown format '{}'", format_spec))?;
+    zero_pad(value, width)
+}
+
+fn zero_pad(value: &str, width: usize) -> Result<String> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("'{}' is not a number, so it can't be zero-padded", value));
+    }
+    Ok(pad_number(value, width))
+}
+
+fn apply_filter(filter: &str, arguments: &[String], value: String) -> Result<String> {
+    let argument = |index: usize| arguments.get(index)
+        .ok_or(anyhow!("the {} filter needs {} arguments", filter, index + 1));
+    let number = |index: usize| argument(index)?.parse::<usize>()
+        .map_err(|_| anyhow!("the {} filter needs a number, not '{}'", filter, arguments[index]));
+    match filter {
+        "lower" => Ok(value.to_lowercase()),
+        "upper" => Ok(value.to_uppercase()),
+        "trim" => Ok(value.trim().to_string()),
+        "replace" => Ok(value.replace(argument(0)?.as_str(), argument(1)?)),
+        "pad" => zero_pad(&value, number(0)?),
+        "substring" => {
+            let start = number(0)?;
+            let end = match arguments.len() > 1 {
+                true => number(1)?,
+                false => usize::MAX,
+            };
+            Ok(value.chars().skip(start).take(end.saturating_sub(start)).collect())
+        }
+        "default" => Ok(value),
+        _ => Err(anyhow!("unknown filter '{}'", filter)),
+    }
+}
+
+// A filter's name and arguments are separated by colons, arguments in single or double
+// quotes can hold colons, pipes and spaces.
+fn parse_filter(filter: &str) -> (String, Vec<String>) {
+    let mut parts = split_unquoted(filter, ':').into_iter().map(|part| unquote(part.trim()));
+    let name = parts.next().unwrap_or_default();
+    (name, parts.collect())
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quote = None;
+    for character in text.chars() {
+        match (quote, character) {
+            (None, '\'' | '"') => quote = Some(character),
+            (Some(open), _) if open == character => quote = None,
+            (None, _) if character == separator => {
+                parts.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        parts.last_mut().unwrap().push(character);
+    }
+    parts
+}
+
+fn unquote(text: &str) -> String {
+    for quote in ['\'', '"'] {
+        if let Some(inner) = text.strip_prefix(quote).and_then(|text| text.strip_suffix(quote)) {
+            return inner.to_string();
+        }
+    }
+    text.to_string()
+}
+
+fn resolve_value(name: &str, source: &Path, captures: Option<&Captures>, variables: &BTreeMap<String, String>) -> Result<String> {
+    let file_part = |part: Option<&std::ffi::OsStr>| part
+        .and_then(|value| value.to_str())
+        .unwrap_or_default()
+        .to_string();
+    match name {
+        "filename" => return Ok(file_part(source.file_name())),
+        "stem" => return Ok(file_part(source.file_stem())),
+        "ext" => return Ok(file_part(source.extension())),
+        _ => {}
+    }
+    if let Some(value) = variables.get(name) {
+        return Ok(value.clone());
+    }
+
+    let captures = captures.ok_or(anyhow!("The rule's pattern doesn't match, so '{{{}}}' can't be filled in", name))?;
+    let group = match name.parse::<usize>() {
+        Ok(index) => captures.get(index),
+        Err(_) => captures.name(name),
+    };
+    group.map(|value| value.as_str().to_string())
+        .ok_or(anyhow!("The rule's pattern has no capture group '{}'", name))
+}
@@ -1,57 +1,241 @@
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{remove_file, rename};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
 
-use chrono::TimeZone;
-use chrono::Utc;
+use anyhow::anyhow;
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, Locale, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use deunicode::deunicode;
 use once_cell::sync::Lazy;
 use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::copy::{copy_file, print_progress, sync_directory, CopyOptions};
+use crate::{CaptureGroup, Case, DateGranularity, DirectoryCreation, DateSource, Processor, Rule, SanitizeMode, UnicodeForm};
 
-use crate::{Processor, Rule};
+// Named groups like `(?P<name>...)` use the same angle brackets as the filename marker,
+// so they are masked while the marker is handled.
+const NAMED_GROUP_OPEN: char = '\u{1}';
+const NAMED_GROUP_CLOSE: char = '\u{2}';
+
+fn mask_named_groups(pattern: &str) -> String {
+    static NAMED_GROUP_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\(\?P?<(\w+)>").unwrap());
+    NAMED_GROUP_RE
+        .replace_all(pattern, format!("(?P{}${{1}}{}", NAMED_GROUP_OPEN, NAMED_GROUP_CLOSE))
+        .to_string()
+}
+
+fn unmask_named_groups(pattern: &str) -> String {
+    pattern.replace(NAMED_GROUP_OPEN, "<").replace(NAMED_GROUP_CLOSE, ">")
+}
 
 // Helper method to clean pattern
 pub fn clean_pattern(pattern: &str) -> anyhow::Result<String> {
     static CLEAN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[<>]").unwrap());
-    Ok(CLEAN_RE.replace_all(pattern, "").to_string())
+    let masked = mask_named_groups(pattern);
+    Ok(unmask_named_groups(&CLEAN_RE.replace_all(&masked, "")))
 }
 
 // Helper method to extract pattern
 pub fn extract_pattern(pattern: &str) -> anyhow::Result<String> {
     static EXTRACT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r".*<(.*)>.*").unwrap());
-    let captures: Option<Captures> = EXTRACT_RE.captures(pattern);
+    let masked = mask_named_groups(pattern);
+    let captures: Option<Captures> = EXTRACT_RE.captures(&masked);
     match captures {
-        Some(c) => Ok(c.get(1).unwrap().as_str().to_string()),
+        Some(c) => Ok(unmask_named_groups(c.get(1).unwrap().as_str())),
         None => Ok(pattern.to_string()),
     }
 }
 
+/// The `start:length` between the angle brackets of a directory, if it has them.
+pub fn directory_range(directory: &str) -> Option<&str> {
+    static RANGE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<(.*)>").unwrap());
+    RANGE_RE.captures(directory).and_then(|captures| captures.get(1)).map(|range| range.as_str())
+}
+
+/// A `<start:length>` range of the filename's characters in a rule's directory. A negative
+/// start counts from the end of the name, a negative length leaves that many characters off
+/// its end, and without a length the range runs to the end of the name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubstringRange {
+    start: isize,
+    length: Option<isize>,
+}
+
+impl FromStr for SubstringRange {
+    type Err = anyhow::Error;
+
+    fn from_str(range: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("'<{}>' isn't a range such as <0:4>, <-4:4> or <2:>", range);
+        let (start, length) = range.split_once(':').ok_or_else(invalid)?;
+        Ok(SubstringRange {
+            start: start.trim().parse().map_err(|_| invalid())?,
+            length: match length.trim() {
+                "" => None,
+                length => Some(length.parse().map_err(|_| invalid())?),
+            },
+        })
+    }
+}
+
+impl fmt::Display for SubstringRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.length {
+            Some(length) => write!(f, "<{}:{}>", self.start, length),
+            None => write!(f, "<{}:>", self.start),
+        }
+    }
+}
+
+impl SubstringRange {
+    /// The characters of `text` in the range. Names are composed first, so an accented letter
+    /// macOS stores as a letter and a combining mark counts once.
+    pub fn apply(&self, text: &str) -> anyhow::Result<String> {
+        let characters = text.nfc().collect::<Vec<char>>();
+        let count = characters.len() as isize;
+        let start = match self.start < 0 {
+            true => count + self.start,
+            false => self.start,
+        };
+        let end = match self.length {
+            None => count,
+            Some(length) if length < 0 => count + length,
+            Some(length) => start + length,
+        };
+        if start < 0 || start > end || end > count {
+            return Err(anyhow!("The range {} doesn't fit in '{}', which has {} characters", self, text, count));
+        }
+        Ok(characters[start as usize..end as usize].iter().collect())
+    }
+}
+
 pub fn full_path(root: &Path, folder: &Path) -> PathBuf {
     root.join(folder)
 }
 
-pub fn process_date(destination: &mut String, fmt: &str, splitter: &str, merger: &Option<String>) -> anyhow::Result<()> {
+// How far back `process_date` looks for a date matching the splitter before giving up.
+const MAX_DATE_SEARCH_DAYS: u32 = 100 * 366;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateTimeZone {
+    Utc,
+    Local,
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl FromStr for DateTimeZone {
+    type Err = anyhow::Error;
+
+    /// Accepts `utc`, `local`, an IANA name such as `Europe/Prague` or a fixed offset like `+02:00`.
+    fn from_str(zone: &str) -> anyhow::Result<Self> {
+        match zone.to_lowercase().as_str() {
+            "utc" => return Ok(DateTimeZone::Utc),
+            "local" => return Ok(DateTimeZone::Local),
+            _ => {}
+        }
+        if let Ok(offset) = zone.parse::<FixedOffset>() {
+            return Ok(DateTimeZone::Fixed(offset));
+        }
+        zone.parse::<Tz>()
+            .map(DateTimeZone::Named)
+            .map_err(|_| anyhow!("Unknown timezone '{}'", zone))
+    }
+}
+
+impl DateTimeZone {
+    fn today(&self) -> NaiveDate {
+        match self {
+            DateTimeZone::Utc => Utc::now().date_naive(),
+            DateTimeZone::Local => Local::now().date_naive(),
+            DateTimeZone::Named(tz) => Utc::now().with_timezone(tz).date_naive(),
+            DateTimeZone::Fixed(offset) => Utc::now().with_timezone(offset).date_naive(),
+        }
+    }
+
+    fn format_timestamp(&self, timestamp: i64, fmt: &str, locale: Locale) -> anyhow::Result<String> {
+        let utc = Utc.timestamp_opt(timestamp, 0)
+            .single()
+            .ok_or(anyhow!("{} is not a valid timestamp", timestamp))?;
+        Ok(match self {
+            DateTimeZone::Utc => utc.format_localized(fmt, locale).to_string(),
+            DateTimeZone::Local => utc.with_timezone(&Local).format_localized(fmt, locale).to_string(),
+            DateTimeZone::Named(tz) => utc.with_timezone(tz).format_localized(fmt, locale).to_string(),
+            DateTimeZone::Fixed(offset) => utc.with_timezone(offset).format_localized(fmt, locale).to_string(),
+        })
+    }
+}
+
+/// Reformats a leading timestamp, or a date found via a `%`-style splitter, using `fmt`.
+///
+/// Month and day names are read and written in the given locale, e.g. `de_DE`.
+pub fn process_date(
+    destination: &mut String,
+    fmt: &str,
+    splitter: &str,
+    merger: &Option<String>,
+    locale: Option<&str>,
+    timezone: Option<&str>,
+) -> anyhow::Result<()> {
+    let locale = match locale {
+        None => Locale::POSIX,
+        Some(name) => name.parse::<Locale>().map_err(|_| anyhow!("Unknown locale '{}'", name))?,
+    };
+    let timezone = match timezone {
+        None => DateTimeZone::Utc,
+        Some(zone) => zone.parse::<DateTimeZone>()?,
+    };
     let parts: Vec<&str> = if splitter.contains('%') {
-        let mut dt = Utc::now().date_naive();
-        let mut _fmt = dt.format(splitter).to_string();
+        let mut dt = timezone.today();
+        let mut _fmt = dt.format_localized(splitter, locale).to_string();
+        let mut searched_days = 0;
         while !destination.contains(&_fmt) {
+            searched_days += 1;
+            if searched_days > MAX_DATE_SEARCH_DAYS {
+                return Err(anyhow!("No date matching '{}' found in {}", splitter, destination));
+            }
             dt = dt.pred_opt().unwrap();
-            _fmt = dt.format(splitter).to_string();
+            _fmt = dt.format_localized(splitter, locale).to_string();
         }
-        destination.split(&_fmt).collect()
+        let parts: Vec<&str> = destination.splitn(2, &_fmt).collect();
+        if parts[0].parse::<i64>().is_err() {
+            // No timestamp in front of the date, so the found date itself is reformatted.
+            *destination = [parts[0], &dt.format_localized(fmt, locale).to_string(), parts[1]].concat();
+            return Ok(());
+        }
+        parts
     } else {
         destination.split(splitter).collect()
     };
-    let creation_date: String = Utc
-        .timestamp_opt(parts[0].parse()?, 0)
-        .unwrap()
-        .format(fmt)
-        .to_string();
+    let creation_date: String = timezone.format_timestamp(parts[0].parse()?, fmt, locale)?;
     *destination = [creation_date.as_str(), parts[1]]
         .join(merger.as_ref().unwrap().as_str());
 
     Ok(())
 }
 
+/// Compiles each pattern once per process and hands out clones of the compiled regex,
+/// which share the compiled program.
+pub fn cached_regex(pattern: &str) -> anyhow::Result<Regex> {
+    static CACHE: Lazy<Mutex<HashMap<String, Regex>>> = Lazy::new(Default::default);
+    let mut cache = CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(regex) = cache.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = Regex::new(pattern)?;
+    cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
 pub fn process_pattern(destination: &mut String, pattern: &str, replacement: &Option<String>) -> anyhow::Result<()> {
-    let pattern = Regex::new(pattern)?;
+    let pattern = cached_regex(pattern)?;
     *destination = match replacement {
         Some(replacement_value) => pattern.replace(destination.as_str(), replacement_value).to_string(),
         None => destination.to_string(),
@@ -60,15 +244,627 @@ pub fn process_pattern(destination: &mut String, pattern: &str, replacement: &Op
     Ok(())
 }
 
-pub(crate) fn generate_target(processor: &Processor, rule: &Rule, root: &Path) -> anyhow::Result<PathBuf> {
+/// Zero-pads a string of ASCII digits to `width`, dropping any extra leading zeros first.
+pub fn pad_number(digits: &str, width: usize) -> String {
+    let trimmed = digits.trim_start_matches('0');
+    let trimmed = if trimmed.is_empty() { "0" } else { trimmed };
+    format!("{:0>width$}", trimmed, width = width)
+}
+
+pub fn process_pad_numbers(destination: &mut String, width: usize) {
+    static NUMBER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b\d+\b").unwrap());
+    let (stem, extension) = match destination.rfind('.') {
+        Some(idx) if idx > 0 => destination.split_at(idx),
+        _ => (destination.as_str(), ""),
+    };
+    let padded = NUMBER_RE.replace_all(stem, |number: &Captures| pad_number(&number[0], width));
+    *destination = format!("{}{}", padded, extension);
+}
+
+const NUMBER_WORDS: [(&str, u32); 28] = [
+    ("zero", 0), ("one", 1), ("two", 2), ("three", 3), ("four", 4), ("five", 5), ("six", 6),
+    ("seven", 7), ("eight", 8), ("nine", 9), ("ten", 10), ("eleven", 11), ("twelve", 12),
+    ("thirteen", 13), ("fourteen", 14), ("fifteen", 15), ("sixteen", 16), ("seventeen", 17),
+    ("eighteen", 18), ("nineteen", 19), ("twenty", 20), ("thirty", 30), ("forty", 40),
+    ("fifty", 50), ("sixty", 60), ("seventy", 70), ("eighty", 80), ("ninety", 90),
+];
+
+const NUMBERING_WORDS: [&str; 11] = [
+    "part", "pt", "volume", "vol", "book", "chapter", "episode", "act", "season", "tome", "issue",
+];
+
+pub fn process_unicode_form(destination: &mut String, form: UnicodeForm) {
+    *destination = match form {
+        UnicodeForm::Nfc => destination.nfc().collect(),
+        UnicodeForm::Nfd => destination.nfd().collect(),
+        UnicodeForm::Nfkc => destination.nfkc().collect(),
+        UnicodeForm::Nfkd => destination.nfkd().collect(),
+    };
+}
+
+pub fn process_transliteration(destination: &mut String) {
+    *destination = deunicode(destination);
+}
+
+/// Replaces the roman numerals (`IV`) and spelled-out numbers (`twenty one`) in the text the
+/// capture group `group` matched with digits, leaving the rest of the name as it is.
+pub fn process_numerals(destination: &mut String, captures: Option<&Captures>, group: &CaptureGroup) -> anyhow::Result<()> {
+    let text = captured_group(captures, group)?;
+    let converted = numerals_to_digits(text);
+    if converted != text {
+        *destination = destination.replacen(text, &converted, 1);
+    }
+    Ok(())
+}
+
+// A tens word followed by a unit, such as `twenty one` or `twenty-one`, is a single number.
+fn numerals_to_digits(text: &str) -> String {
+    static WORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b[[:alpha:]]+\b").unwrap());
+    let is_whole = WORD_RE.find(text).is_some_and(|word| word.as_str() == text);
+    let mut converted = String::new();
+    let mut previous: Option<&str> = None;
+    // Where a spelled-out tens number starts in `converted`, and its value.
+    let mut tens: Option<(usize, u32)> = None;
+    let mut end = 0;
+    for word in WORD_RE.find_iter(text) {
+        let separator = &text[end..word.start()];
+        let spelled = word_to_number(word.as_str());
+        match (tens, spelled) {
+            (Some((start, value)), Some(unit @ 1..=9)) if matches!(separator, " " | "-") => {
+                converted.truncate(start);
+                converted.push_str(&(value + unit).to_string());
+                tens = None;
+            }
+            _ => {
+                converted.push_str(separator);
+                tens = spelled.filter(|number| *number >= 20 && number % 10 == 0).map(|number| (converted.len(), number));
+                match spelled.or_else(|| roman_to_number(word.as_str(), previous, is_whole)) {
+                    Some(number) => converted.push_str(&number.to_string()),
+                    None => converted.push_str(word.as_str()),
+                }
+            }
+        }
+        previous = Some(word.as_str());
+        end = word.end();
+    }
+    converted.push_str(&text[end..]);
+    converted
+}
+
+fn word_to_number(word: &str) -> Option<u32> {
+    let word = word.to_lowercase();
+    NUMBER_WORDS.iter()
+        .find(|(number_word, _)| *number_word == word)
+        .map(|(_, number)| *number)
+}
+
+// Only upper-case numerals up to XXXIX are converted, and only after a word such as "Part" or
+// "Vol" unless the group matched nothing else, so "MIX", "Malcolm X" or "Who Am I" stay intact.
+fn roman_to_number(word: &str, previous: Option<&str>, is_whole: bool) -> Option<u32> {
+    static ROMAN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^X{0,3}(IX|IV|V?I{0,3})$").unwrap());
+    if word.is_empty() || !ROMAN_RE.is_match(word) {
+        return None;
+    }
+    let is_numbering = previous.is_some_and(|previous| NUMBERING_WORDS.iter().any(|numbering| numbering.eq_ignore_ascii_case(previous)));
+    if !is_whole && !is_numbering {
+        return None;
+    }
+    let value = |c: char| match c {
+        'I' => 1, 'V' => 5, 'X' => 10, _ => 0,
+    };
+    let digits: Vec<u32> = word.chars().map(value).collect();
+    let mut total = 0;
+    for (idx, digit) in digits.iter().enumerate() {
+        match digits.get(idx + 1) {
+            Some(next) if next > digit => total -= *digit as i64,
+            _ => total += *digit as i64,
+        }
+    }
+    u32::try_from(total).ok()
+}
+
+pub fn process_case(destination: &mut String, case: Case) {
+    let (stem, extension) = match destination.rfind('.') {
+        Some(idx) if idx > 0 => destination.split_at(idx),
+        _ => (destination.as_str(), ""),
+    };
+    let extension = match case {
+        Case::Slug => extension.to_lowercase(),
+        _ => extension.to_string(),
+    };
+    *destination = format!("{}{}", convert_case(stem, case), extension);
+}
+
+pub fn convert_case(value: &str, case: Case) -> String {
+    match case {
+        Case::Lower => value.to_lowercase(),
+        Case::Upper => value.to_uppercase(),
+        Case::Title => title_case(value),
+        Case::Slug => slugify(value),
+    }
+}
+
+fn title_case(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut at_word_start = true;
+    for character in value.chars() {
+        if at_word_start {
+            result.extend(character.to_uppercase());
+        } else {
+            result.extend(character.to_lowercase());
+        }
+        at_word_start = !character.is_alphanumeric() && character != '\'';
+    }
+    result
+}
+
+fn slugify(value: &str) -> String {
+    value.to_lowercase()
+        .split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+pub(crate) fn generate_target(processor: &Processor, rule: &Rule, root: &Path, creation: DirectoryCreation) -> anyhow::Result<PathBuf> {
     match &rule.function {
         None => processor.make_destination(&rule.new_pattern, Some(root), rule),
         Some(func) => match func {
             &_ => {
                 let temporary_root = processor.make_destination(&rule.new_pattern, None, rule)?;
-                let directory = func.get_dir(temporary_root.parent().unwrap())?;
+                let pattern = cached_regex(&rule.old_pattern)?;
+                let source_filename = processor.source_filename()?;
+                let captures = pattern.captures(source_filename);
+                let directory = func.get_dir(temporary_root.parent().unwrap(), &processor.source, captures.as_ref(), creation)?;
                 processor.make_destination(&rule.new_pattern, Some(&directory), rule)
             }
         },
     }
 }
+
+pub fn newest_by_modification_time(folders: Vec<PathBuf>) -> anyhow::Result<PathBuf> {
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    for folder in folders {
+        let modified = folder.metadata()?.modified()?;
+        if newest.as_ref().is_none_or(|(newest_modified, _)| modified > *newest_modified) {
+            newest = Some((modified, folder));
+        }
+    }
+    newest.map(|(_, folder)| folder)
+        .ok_or(anyhow!("No folders to choose the newest from"))
+}
+
+/// Picks the first folder whose name matches `pattern` after `$1`-style references have been
+/// filled in from the capture groups of the rule's pattern. The captured text is matched as it
+/// is, so a name like `C++ (2nd)` doesn't turn into a regex of its own.
+pub fn first_matching(folders: Vec<PathBuf>, pattern: &str, captures: Option<&Captures>) -> anyhow::Result<PathBuf> {
+    let expanded = match captures {
+        Some(c) => expand_escaped(c, pattern),
+        None => pattern.to_string(),
+    };
+    let folder_pattern = Regex::new(&expanded)?;
+    folders.into_iter()
+        .find(|folder| folder.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| folder_pattern.is_match(name)))
+        .ok_or(anyhow!("No folder matches the pattern {}", expanded))
+}
+
+// `$1`, `$name` and `${name}` are replaced like `Captures::expand` does, `$$` is a dollar sign.
+fn expand_escaped(captures: &Captures, pattern: &str) -> String {
+    static REFERENCE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$(?:(\$)|\{(\w+)\}|(\w+))").unwrap());
+    REFERENCE_RE.replace_all(pattern, |reference: &Captures| {
+        if reference.get(1).is_some() {
+            return String::from("$");
+        }
+        let name = reference.get(2).or(reference.get(3)).unwrap().as_str();
+        let group = match name.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(name),
+        };
+        group.map(|group| regex::escape(group.as_str())).unwrap_or_default()
+    }).to_string()
+}
+
+/// The folder of the current date in local time.
+pub fn dated_dir(root: &Path, format: &str) -> PathBuf {
+    root.join(Local::now().format(format).to_string())
+}
+
+/// The folder of the week, month or quarter the file's date falls in.
+pub fn date_bucket_dir(root: &Path, source: &Path, granularity: DateGranularity, date_source: DateSource) -> anyhow::Result<PathBuf> {
+    let name_date = match date_source {
+        DateSource::Mtime => None,
+        DateSource::NameDate => source.file_name().and_then(|name| date_in_name(&name.to_string_lossy())),
+    };
+    let date = match name_date {
+        Some(date) => date,
+        None => {
+            let modified = source.metadata()
+                .and_then(|metadata| metadata.modified())
+                .map_err(|err| anyhow!("Couldn't read the modification time of {}: {}", source.display(), err))?;
+            DateTime::<Local>::from(modified).date_naive()
+        }
+    };
+    let bucket = match granularity {
+        DateGranularity::Week => date.format("%G-W%V").to_string(),
+        DateGranularity::Month => date.format("%Y-%m").to_string(),
+        DateGranularity::Quarter => format!("{}-Q{}", date.year(), date.month0() / 3 + 1),
+    };
+    Ok(root.join(bucket))
+}
+
+// The first year, month and day written together, with or without separators.
+fn date_in_name(name: &str) -> Option<NaiveDate> {
+    static DATE_RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"(?:^|\D)((?:19|20)\d{2})[-_.]?(0[1-9]|1[0-2])[-_.]?(0[1-9]|[12]\d|3[01])(?:\D|$)").unwrap()
+    });
+    DATE_RE.captures_iter(name).find_map(|captures| NaiveDate::from_ymd_opt(
+        captures[1].parse().ok()?,
+        captures[2].parse().ok()?,
+        captures[3].parse().ok()?,
+    ))
+}
+
+// Key of the folder for numbers outside every range.
+const DEFAULT_RANGE: &str = "default";
+
+/// Bounds of a range such as `1-50`, `7` or `101+`, the upper bound left out for the last.
+type NumericBounds = (u64, Option<u64>);
+
+/// The ranges of a `numeric_range` function in ascending order, with the default folder.
+pub struct NumericRanges<'a> {
+    ranges: Vec<(NumericBounds, &'a str)>,
+    default: Option<&'a str>,
+}
+
+impl<'a> NumericRanges<'a> {
+    pub fn parse(ranges: &'a BTreeMap<String, String>) -> anyhow::Result<NumericRanges<'a>> {
+        let mut bounds = vec![];
+        for (range, folder) in ranges.iter().filter(|(range, _)| range.as_str() != DEFAULT_RANGE) {
+            bounds.push((parse_numeric_range(range)?, folder.as_str()));
+        }
+        bounds.sort_by_key(|((start, _), _)| *start);
+        for pair in bounds.windows(2) {
+            let (((_, end), first), ((start, _), second)) = (pair[0], pair[1]);
+            if end.is_none_or(|end| end >= start) {
+                return Err(anyhow!("The ranges of {} and {} overlap", first, second));
+            }
+        }
+        Ok(NumericRanges { ranges: bounds, default: ranges.get(DEFAULT_RANGE).map(String::as_str) })
+    }
+
+    pub fn folder(&self, number: u64) -> Option<&'a str> {
+        self.ranges.iter()
+            .find(|((start, end), _)| number >= *start && end.is_none_or(|end| number <= end))
+            .map(|(_, folder)| *folder)
+            .or(self.default)
+    }
+}
+
+fn parse_numeric_range(range: &str) -> anyhow::Result<NumericBounds> {
+    let number = |text: &str| text.trim().parse::<u64>()
+        .map_err(|_| anyhow!("'{}' isn't a range such as 1-50, 7 or 101+", range));
+    let bounds = match (range.strip_suffix('+'), range.split_once('-')) {
+        (Some(start), _) => (number(start)?, None),
+        (None, Some((start, end))) => (number(start)?, Some(number(end)?)),
+        (None, None) => (number(range)?, Some(number(range)?)),
+    };
+    match bounds {
+        (start, Some(end)) if end < start => Err(anyhow!("The range {} ends before it starts", range)),
+        bounds => Ok(bounds),
+    }
+}
+
+fn captured_group<'a>(captures: Option<&Captures<'a>>, group: &CaptureGroup) -> anyhow::Result<&'a str> {
+    captures
+        .and_then(|captures| match group {
+            CaptureGroup::Index(index) => captures.get(*index),
+            CaptureGroup::Name(name) => captures.name(name),
+        })
+        .map(|captured| captured.as_str())
+        .ok_or(anyhow!("Group {} of the pattern matched nothing", group))
+}
+
+/// The folder of the range the number captured by `group` falls in, or the default folder.
+pub fn numeric_range_dir(root: &Path, captures: Option<&Captures>, group: &CaptureGroup, ranges: &BTreeMap<String, String>) -> anyhow::Result<PathBuf> {
+    let text = captured_group(captures, group)?;
+    let number = text.parse::<u64>()
+        .map_err(|_| anyhow!("Group {} of the pattern matched '{}', which isn't a number", group, text))?;
+    let folder = NumericRanges::parse(ranges)?.folder(number)
+        .ok_or(anyhow!("No range includes {} and no default folder is set", number))?;
+    Ok(root.join(folder))
+}
+
+/// The folder of the first character of the text captured by `group`, or of the filename.
+/// Letters are uppercased, which can make them more than one character, like `ß` to `SS`.
+pub fn initial_letter_dir(root: &Path, source: &Path, captures: Option<&Captures>, group: Option<&CaptureGroup>, digits: &str, other: &str) -> anyhow::Result<PathBuf> {
+    let text = match group {
+        None => source.file_name()
+            .ok_or(anyhow!("{} has no filename", source.display()))?
+            .to_string_lossy()
+            .to_string(),
+        Some(group) => captured_group(captures, group)?.to_string(),
+    };
+    let folder = match text.nfc().next() {
+        Some(character) if character.is_numeric() => digits.to_string(),
+        Some(character) if character.is_alphabetic() => character.to_uppercase().collect(),
+        _ => other.to_string(),
+    };
+    Ok(root.join(folder))
+}
+
+const WINDOWS_MAX_PATH: usize = 260;
+const WINDOWS_FORBIDDEN_CHARACTERS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+const VERBATIM_PREFIX: &str = r"\\?\";
+const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+
+/// Checks every generated path component, so a bad character is reported by name instead
+/// of as a raw OS error when the directory is created or the file is moved.
+pub fn validate_path_components(path: &Path) -> anyhow::Result<()> {
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            let part = part.to_str()
+                .ok_or(anyhow!("Path component {:?} of {} is not valid unicode", part, path.display()))?;
+            if cfg!(windows) {
+                validate_windows_component(part, path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_windows_component(part: &str, path: &Path) -> anyhow::Result<()> {
+    if let Some(character) = part.chars().find(|c| WINDOWS_FORBIDDEN_CHARACTERS.contains(c) || c.is_control()) {
+        return Err(anyhow!(
+            "Path component '{}' of {} contains '{}', which is not allowed on Windows",
+            part, path.display(), character.escape_default()
+        ));
+    }
+    if part.ends_with('.') || part.ends_with(' ') {
+        return Err(anyhow!(
+            "Path component '{}' of {} ends with a dot or a space, which is not allowed on Windows",
+            part, path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Prefixes long absolute paths with `\\?\` on Windows so they aren't limited to 260 characters.
+pub fn to_long_path(path: &Path) -> PathBuf {
+    let path_string = path.to_string_lossy();
+    if !cfg!(windows)
+        || !path.is_absolute()
+        || path_string.len() < WINDOWS_MAX_PATH
+        || path_string.starts_with(VERBATIM_PREFIX) {
+        return path.to_path_buf();
+    }
+    let path_string = path_string.replace('/', "\\");
+    match path_string.strip_prefix(r"\\") {
+        Some(unc_path) => PathBuf::from(format!("{}{}", VERBATIM_UNC_PREFIX, unc_path)),
+        None => PathBuf::from(format!("{}{}", VERBATIM_PREFIX, path_string)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Sanitizer {
+    pub mode: SanitizeMode,
+    pub replacement: String,
+}
+
+impl Sanitizer {
+    /// Makes a single generated path component safe for the current OS.
+    pub fn sanitize(&self, component: &str) -> anyhow::Result<String> {
+        let mut sanitized = String::with_capacity(component.len());
+        for character in component.chars() {
+            if !is_invalid_character(character) {
+                sanitized.push(character);
+                continue;
+            }
+            match self.mode {
+                SanitizeMode::Replace => sanitized.push_str(&self.replacement),
+                SanitizeMode::Strip => {}
+                SanitizeMode::Error => return Err(anyhow!(
+                    "Generated path component '{}' contains '{}', which is not allowed",
+                    component, character.escape_default()
+                )),
+            }
+        }
+
+        if cfg!(windows) {
+            let trimmed_length = sanitized.trim_end_matches(['.', ' ']).len();
+            if trimmed_length != sanitized.len() {
+                if self.mode == SanitizeMode::Error {
+                    return Err(anyhow!(
+                        "Generated path component '{}' ends with a dot or a space, which is not allowed on Windows",
+                        component
+                    ));
+                }
+                sanitized.truncate(trimmed_length);
+            }
+        }
+
+        if sanitized.is_empty() {
+            return Err(anyhow!("Generated path component '{}' is empty after sanitization", component));
+        }
+        Ok(sanitized)
+    }
+}
+
+fn is_invalid_character(character: char) -> bool {
+    if cfg!(windows) {
+        WINDOWS_FORBIDDEN_CHARACTERS.contains(&character) || matches!(character, '/' | '\\') || character.is_control()
+    } else {
+        matches!(character, '/' | '\0')
+    }
+}
+
+/// Extension added to files while they are being copied into place.
+pub const STAGING_EXTENSION: &str = "fsort-tmp";
+
+pub fn staging_path(target: &Path) -> PathBuf {
+    let mut staging = target.as_os_str().to_owned();
+    staging.push(".");
+    staging.push(STAGING_EXTENSION);
+    PathBuf::from(staging)
+}
+
+/// Copies `source` next to `target` under a temporary name and renames it once complete,
+/// so programs watching the destination never pick up a half-copied file.
+pub fn staged_copy(source: &Path, target: &Path, options: &CopyOptions) -> io::Result<()> {
+    let staging = staging_path(target);
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let result = copy_file(source, &staging, options, &mut print_progress(&name))
+        .and_then(|_| rename(&staging, target))
+        .and_then(|_| sync_parent(target, options));
+    if result.is_err() {
+        let _ = remove_file(&staging);
+    }
+    result
+}
+
+/// Flushes the folder holding `path` when the options ask for durable writes.
+pub fn sync_parent(path: &Path, options: &CopyOptions) -> io::Result<()> {
+    match (options.fsync, path.parent()) {
+        (true, Some(parent)) => sync_directory(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Whether two different paths lead to the same file, through links or a case-insensitive
+/// file system.
+#[cfg(unix)]
+pub fn same_file(first: &Path, second: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (first.metadata(), second.metadata()) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_file(first: &Path, second: &Path) -> bool {
+    match (first.canonicalize(), second.canonicalize()) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// First variant of `target` with a ` (2)`, ` (3)`, … counter before the extension that
+/// neither exists nor is in `taken`. Names longer than `max_length` are shortened again,
+/// keeping the counter.
+pub fn next_free_name(target: &Path, taken: &HashSet<PathBuf>, max_length: Option<usize>) -> anyhow::Result<PathBuf> {
+    let filename = target.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(anyhow!("No filename found in {}", target.display()))?;
+    let (stem, extension) = match filename.rfind('.') {
+        Some(idx) if idx > 0 => filename.split_at(idx),
+        _ => (filename, ""),
+    };
+    for counter in 2.. {
+        let mut name = format!("{} ({}){}", stem, counter, extension);
+        if let Some(max_length) = max_length {
+            name = truncate_filename(&name, max_length)?;
+        }
+        let candidate = target.with_file_name(name);
+        if !candidate.exists() && !taken.contains(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    Err(anyhow!("No free name found for {}", target.display()))
+}
+
+/// Where `target` goes in a destination limited to `max_files` files: the folder itself while
+/// it has room, otherwise a subfolder picked by the hash of the filename. `file_count` gives
+/// the files in a folder, including the ones the run already plans to put there.
+pub fn shard_target<F>(target: &Path, max_files: usize, file_count: F) -> anyhow::Result<PathBuf>
+where
+    F: FnOnce(&Path) -> anyhow::Result<usize>,
+{
+    let (Some(directory), Some(filename)) = (target.parent(), target.file_name()) else {
+        return Err(anyhow!("No filename found in {}", target.display()));
+    };
+    let hash = format!("{:x}", Sha256::digest(filename.as_encoded_bytes()));
+    let shard = directory.join(&hash[..2]).join(filename);
+    if target.exists() || shard.exists() {
+        return Ok(if target.exists() { target.to_path_buf() } else { shard });
+    }
+
+    Ok(if file_count(directory)? < max_files { target.to_path_buf() } else { shard })
+}
+
+/// Shortens a filename to at most `max_length` bytes, keeping its extension and a trailing
+/// counter such as ` (2)` or `_2` intact so that only the descriptive part is cut.
+pub fn truncate_filename(filename: &str, max_length: usize) -> anyhow::Result<String> {
+    static COUNTER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?: \(\d+\)|_\d+)$").unwrap());
+    if filename.len() <= max_length {
+        return Ok(filename.to_string());
+    }
+
+    let (stem, extension) = match filename.rfind('.') {
+        Some(idx) if idx > 0 => filename.split_at(idx),
+        _ => (filename, ""),
+    };
+    let (stem, counter) = match COUNTER_RE.find(stem) {
+        Some(found) => stem.split_at(found.start()),
+        None => (stem, ""),
+    };
+
+    let kept_length = extension.len() + counter.len();
+    if kept_length >= max_length {
+        return Err(anyhow!(
+            "Filename '{}' can't be shortened to {} bytes without losing its extension",
+            filename, max_length
+        ));
+    }
+
+    let mut cut = max_length - kept_length;
+    while !stem.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    Ok(format!("{}{}{}", stem[..cut].trim_end(), counter, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::{numerals_to_digits, process_numerals};
+    use crate::CaptureGroup;
+
+    #[test]
+    fn converts_numbering_numerals() {
+        assert_eq!(numerals_to_digits("Part II"), "Part 2");
+        assert_eq!(numerals_to_digits("Episode IV"), "Episode 4");
+        assert_eq!(numerals_to_digits("Vol. XII"), "Vol. 12");
+        assert_eq!(numerals_to_digits("Part I"), "Part 1");
+        assert_eq!(numerals_to_digits("Chapter seven"), "Chapter 7");
+        assert_eq!(numerals_to_digits("XIV"), "14");
+    }
+
+    #[test]
+    fn combines_compound_numbers() {
+        assert_eq!(numerals_to_digits("twenty one"), "21");
+        assert_eq!(numerals_to_digits("Book Twenty-Three"), "Book 23");
+        assert_eq!(numerals_to_digits("thirty"), "30");
+        assert_eq!(numerals_to_digits("twenty, one"), "20, 1");
+    }
+
+    #[test]
+    fn keeps_words_that_look_like_numerals() {
+        assert_eq!(numerals_to_digits("MIX DC Comic"), "MIX DC Comic");
+        assert_eq!(numerals_to_digits("Best of CD DIV"), "Best of CD DIV");
+        assert_eq!(numerals_to_digits("Who Am I"), "Who Am I");
+        assert_eq!(numerals_to_digits("Malcolm X"), "Malcolm X");
+        assert_eq!(numerals_to_digits("V for Vendetta"), "V for Vendetta");
+    }
+
+    #[test]
+    fn converts_only_the_captured_group() {
+        let pattern = Regex::new(r"^(?P<title>.+) Book (?P<volume>\w+)\.epub$").unwrap();
+        let captures = pattern.captures("The One Ring Book seven.epub");
+        let mut name = String::from("The One Ring Book seven.epub");
+        process_numerals(&mut name, captures.as_ref(), &CaptureGroup::Name(String::from("volume"))).unwrap();
+        assert_eq!(name, "The One Ring Book 7.epub");
+    }
+}
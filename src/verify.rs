@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use colored::Colorize;
+use regex::Regex;
+
+use crate::directory::directory_files;
+use crate::provenance::is_provenance_sidecar;
+use crate::utils::{cached_regex, STAGING_EXTENSION};
+use crate::{configuration_path, prepare_rules, Config, ConfigurationError, Rule, EXIT_FAILURE, EXIT_SUCCESS};
+
+/// A file in one of the roots whose name no rule would have given it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Misplaced {
+    pub file: PathBuf,
+    pub closest_rule: Option<String>,
+}
+
+struct RuleTarget<'a> {
+    rule: &'a Rule,
+    directory: PathBuf,
+    destination: Option<Regex>,
+}
+
+pub fn verify_command(argument_matches: &ArgMatches) -> Result<u8> {
+    let configuration_file = configuration_path(argument_matches)?;
+    let mut configuration = Config::load(configuration_file).context(ConfigurationError)?;
+    prepare_rules(&mut configuration).context(ConfigurationError)?;
+
+    let (checked, misplaced) = verify(&configuration)?;
+    for entry in &misplaced {
+        match &entry.closest_rule {
+            Some(rule) => println!("{} (closest rule: {})", entry.file.display().to_string().bold(), rule.blue()),
+            None => println!("{} (no similar rule)", entry.file.display().to_string().bold()),
+        }
+    }
+    println!("{} files checked, {} don't match their rules.", checked, misplaced.len().to_string().bold());
+
+    Ok(if misplaced.is_empty() { EXIT_SUCCESS } else { EXIT_FAILURE })
+}
+
+/// Walks every root and reports files that aren't named the way a rule with that destination
+/// would name them. Rules whose processors rewrite the name, and whole-folder rules, accept
+/// anything in their destination.
+pub fn verify(configuration: &Config) -> Result<(usize, Vec<Misplaced>)> {
+    let mut targets = vec![];
+    for rule in &configuration.rules {
+        let directory = rule.directory.clone().unwrap_or_else(|| PathBuf::from(&rule.title));
+        targets.push(RuleTarget {
+            rule,
+            directory: configuration.root.resolve(&rule.root_reference())?.join(directory),
+            destination: match rule.match_directories {
+                true => None,
+                false => rule.destination_pattern()?,
+            },
+        });
+    }
+
+    let mut checked = 0;
+    let mut misplaced = vec![];
+    for root in &configuration.root.0 {
+        if !root.path.is_dir() {
+            continue;
+        }
+        for relative in directory_files(&root.path)? {
+            let file = root.path.join(relative);
+            let Some(filename) = file.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if is_bookkeeping_file(filename, &targets) {
+                continue;
+            }
+            checked += 1;
+            if !conforms(&file, filename, &targets) {
+                misplaced.push(Misplaced {
+                    closest_rule: closest_rule(&root.path, &file, filename, &targets),
+                    file,
+                });
+            }
+        }
+    }
+    Ok((checked, misplaced))
+}
+
+fn conforms(file: &Path, filename: &str, targets: &[RuleTarget]) -> bool {
+    targets.iter()
+        .filter(|target| file.starts_with(&target.directory))
+        .any(|target| target.destination.as_ref().is_none_or(|destination| destination.is_match(filename)))
+}
+
+// Prefers a rule that would produce or pick up this filename, then the rule whose destination
+// shares the longest part of the file's path below the root.
+fn closest_rule(root: &Path, file: &Path, filename: &str, targets: &[RuleTarget]) -> Option<String> {
+    let by_name = targets.iter().find(|target| {
+        target.destination.as_ref().is_some_and(|destination| destination.is_match(filename))
+            || cached_regex(&target.rule.old_pattern).is_ok_and(|pattern| pattern.is_match(filename))
+    });
+    let by_location = || targets.iter()
+        .map(|target| (common_components(file, &target.directory), target))
+        .filter(|(common, _)| *common > root.components().count())
+        .max_by_key(|(common, _)| *common)
+        .map(|(_, target)| target);
+    by_name.or_else(by_location).map(|target| target.rule.title.clone())
+}
+
+fn common_components(first: &Path, second: &Path) -> usize {
+    first.components().zip(second.components()).take_while(|(a, b)| a == b).count()
+}
+
+// Checksum and provenance sidecars and unfinished copies sit next to sorted files without
+// following the rules.
+fn is_bookkeeping_file(filename: &str, targets: &[RuleTarget]) -> bool {
+    filename.ends_with(&format!(".{}", STAGING_EXTENSION))
+        || is_provenance_sidecar(filename)
+        || targets.iter()
+            .filter_map(|target| target.rule.checksum)
+            .any(|algorithm| filename.ends_with(&format!(".{}", algorithm.extension())))
+}
@@ -0,0 +1,9 @@
+pub use batch::*;
+pub use engine::*;
+pub use timing::*;
+pub use trace::*;
+
+mod batch;
+mod engine;
+mod timing;
+mod trace;
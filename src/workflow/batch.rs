@@ -0,0 +1,392 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, remove_file, rename};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+use crate::provenance::record_provenance;
+use crate::utils::{staged_copy, sync_parent, to_long_path};
+use crate::{ArchivePolicy, Config, FailedRule, FileOperation, OperationType, Processor, Provenance, Rule};
+
+// Stands in for a rule title on operations of files no rule matched.
+pub(crate) const UNMATCHED_RULE: &str = "unmatched";
+
+/// How a planned operation is carried out.
+#[derive(Debug, Clone)]
+pub(crate) enum ActionKind {
+    /// The operation of the rule at this index in the configuration.
+    Rule(Processor, usize),
+    /// A companion file taken along with the file its rule matched.
+    Companion,
+    /// A file no rule matched, moved to the holding folder.
+    Unmatched(Processor),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PlannedAction {
+    /// The entry of the download folder the operation belongs to.
+    pub file: PathBuf,
+    pub operation: FileOperation,
+    pub kind: ActionKind,
+}
+
+impl PlannedAction {
+    fn directory(&self) -> Option<&Path> {
+        target_directory(&self.operation.target, self.operation.operation)
+    }
+
+    // Operations sharing any of these paths have to run one after another.
+    fn keys(&self) -> impl Iterator<Item = &Path> {
+        [Some(self.file.as_path()), Some(self.operation.source.as_path()), Some(self.operation.target.as_path()), self.operation.target.parent()]
+            .into_iter()
+            .flatten()
+    }
+
+    // The destination folder is created before the first operation into it. A warning about
+    // the finished operation, such as its provenance not being recorded, comes back with it.
+    fn perform(&self, config: &Config, created: &mut HashSet<PathBuf>) -> Result<Option<String>> {
+        let store = config.provenance.filter(|_| self.records_provenance());
+        // Read before the move, which can leave a sidecar behind.
+        let earlier = store.and_then(|_| Provenance::read(&self.operation.source).ok().flatten());
+        let result = self.create_directory(created).and_then(|_| match &self.kind {
+            ActionKind::Rule(processor, index) => processor.perform_file_action(&config.rules[*index], &config.plugins),
+            ActionKind::Companion => move_companion(&self.operation, config),
+            ActionKind::Unmatched(processor) => processor.transfer(&Rule::new(UNMATCHED_RULE)),
+        });
+        let result = result.map(|_| {
+            let store = store?;
+            record_provenance(&self.operation, store, earlier)
+                .err()
+                .map(|err| format!("Couldn't record where {} came from: {}", self.operation.target.display(), err))
+        });
+        match self.kind {
+            ActionKind::Unmatched(_) => result,
+            _ => result.map_err(|err| err.context(FailedRule(self.operation.rule.clone()))),
+        }
+    }
+
+    // Entries of archives have no file of their own, and unmatched files weren't sorted.
+    fn records_provenance(&self) -> bool {
+        !matches!(self.kind, ActionKind::Unmatched(_)) && self.operation.operation != OperationType::Archive
+    }
+
+    // The archive the action adds its file to, where it has one.
+    fn archive<'a>(&'a self, config: &'a Config) -> Option<(&'a Path, &'a ArchivePolicy)> {
+        let ActionKind::Rule(_, index) = self.kind else {
+            return None;
+        };
+        let policy = config.rules[index].archive.as_ref()?;
+        let archive = self.operation.target.parent()?;
+        Some((archive, policy)).filter(|_| self.operation.operation == OperationType::Archive)
+    }
+
+    fn create_directory(&self, created: &mut HashSet<PathBuf>) -> Result<()> {
+        let Some(directory) = self.directory().filter(|directory| !created.contains(*directory)) else {
+            return Ok(());
+        };
+        create_dir_all(to_long_path(directory))
+            .map_err(|err| anyhow!("Couldn't create directory {}: {}", directory.display(), err))?;
+        created.insert(directory.to_path_buf());
+        Ok(())
+    }
+}
+
+/// The operations of a run, collected while the files are matched and carried out afterwards.
+///
+/// Destination folders are read once to find the names already taken in them, and created
+/// once before the first operation moving a file into them. Operations sharing a folder, a
+/// source or a destination run in the order they were planned, the others run concurrently.
+#[derive(Debug, Default)]
+pub struct ExecutionPlan {
+    targets: HashSet<PathBuf>,
+    existing: HashMap<PathBuf, Option<HashSet<OsString>>>,
+    /// Files in a folder before the run, counted once for sharding.
+    file_counts: HashMap<PathBuf, usize>,
+    /// Files the run plans to add to a folder that aren't there yet.
+    added: HashMap<PathBuf, usize>,
+    actions: Vec<PlannedAction>,
+    chained: Vec<(usize, PathBuf, PathBuf)>,
+}
+
+impl ExecutionPlan {
+    /// Destinations of the operations planned so far, including skipped dry runs.
+    pub fn targets(&self) -> &HashSet<PathBuf> {
+        &self.targets
+    }
+
+    pub fn is_planned(&self, target: &Path) -> bool {
+        self.targets.contains(target)
+    }
+
+    /// Whether another operation of the run ends at `target` or something is already there.
+    pub fn is_taken(&mut self, target: &Path) -> bool {
+        self.is_planned(target) || self.exists(target)
+    }
+
+    pub fn reserve(&mut self, target: &Path) {
+        if self.targets.insert(target.to_path_buf()) && !self.exists(target) {
+            if let Some(directory) = target.parent() {
+                *self.added.entry(directory.to_path_buf()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Files in `directory`, not counting folders, together with the ones this run plans to
+    /// put there. The folder is only read the first time.
+    pub fn file_count(&mut self, directory: &Path) -> Result<usize> {
+        let existing = match self.file_counts.get(directory) {
+            Some(count) => *count,
+            None => {
+                let count = match directory.read_dir() {
+                    Ok(entries) => entries
+                        .filter_map(|entry| entry.ok())
+                        .filter(|entry| entry.file_type().is_ok_and(|file_type| !file_type.is_dir()))
+                        .count(),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                    Err(err) => return Err(anyhow!("Couldn't read {}: {}", directory.display(), err)),
+                };
+                self.file_counts.insert(directory.to_path_buf(), count);
+                count
+            }
+        };
+        Ok(existing + self.added.get(directory).copied().unwrap_or_default())
+    }
+
+    pub(crate) fn push(&mut self, action: PlannedAction) {
+        self.actions.push(action);
+    }
+
+    /// Records where a rule with `then` puts a file, including dry runs, for the next stage to
+    /// be planned from.
+    pub(crate) fn chain(&mut self, index: usize, file: &Path, target: &Path) {
+        self.chained.push((index, file.to_path_buf(), target.to_path_buf()));
+    }
+
+    /// The index of the rule, the file and where it's put, for each file a rule with `then`
+    /// sorted so far.
+    pub(crate) fn take_chained(&mut self) -> Vec<(usize, PathBuf, PathBuf)> {
+        std::mem::take(&mut self.chained)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // Folders that can't be listed fall back to checking the path itself.
+    fn exists(&mut self, target: &Path) -> bool {
+        let (Some(directory), Some(name)) = (target.parent(), target.file_name()) else {
+            return target.exists();
+        };
+        let names = self.existing.entry(directory.to_path_buf()).or_insert_with(|| {
+            match directory.read_dir() {
+                Ok(entries) => Some(entries.filter_map(|entry| entry.ok()).map(|entry| name_key(&entry.file_name())).collect()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Some(HashSet::new()),
+                Err(_) => None,
+            }
+        });
+        match names {
+            Some(names) => names.contains(&name_key(name)),
+            None => target.exists(),
+        }
+    }
+
+    /// Carries out the planned operations and returns them with their results in the order
+    /// they were planned. Operations left out after an earlier one of their file failed have
+    /// no result, finished ones can come with a warning to show.
+    pub(crate) fn execute(self, config: &Config) -> Vec<(PlannedAction, Option<Result<Option<String>>>)> {
+        let groups = self.groups();
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(self.actions.len()));
+        let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get).min(groups.len());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while let Some(group) = groups.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let done = self.execute_group(group, config);
+                        results.lock().unwrap().extend(done);
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _)| *index);
+        let mut results = results.into_iter().map(|(_, result)| result);
+        self.actions.into_iter()
+            .map(|action| (action, results.next().flatten()))
+            .collect()
+    }
+
+    fn execute_group(&self, group: &[usize], config: &Config) -> Vec<(usize, Option<Result<Option<String>>>)> {
+        let mut created = HashSet::new();
+        let mut failed = HashSet::new();
+        let mut results = vec![];
+        // An archive is written once with all of its entries, when the last of them comes up.
+        let mut archives: HashMap<&Path, Vec<usize>> = HashMap::new();
+        for &index in group {
+            if let Some((archive, _)) = self.actions[index].archive(config) {
+                archives.entry(archive).or_default().push(index);
+            }
+        }
+        for &index in group {
+            let action = &self.actions[index];
+            if let Some((archive, policy)) = action.archive(config) {
+                let entries = &archives[archive];
+                if entries.last() == Some(&index) {
+                    results.extend(self.archive_group(archive, policy, entries, config, &mut created, &mut failed));
+                }
+                continue;
+            }
+            if failed.contains(&action.file) {
+                results.push((index, None));
+                continue;
+            }
+            let result = action.perform(config, &mut created);
+            if result.is_err() {
+                failed.insert(action.file.clone());
+            }
+            results.push((index, Some(result)));
+        }
+        results
+    }
+
+    fn archive_group(
+        &self,
+        archive: &Path,
+        policy: &ArchivePolicy,
+        indices: &[usize],
+        config: &Config,
+        created: &mut HashSet<PathBuf>,
+        failed: &mut HashSet<PathBuf>,
+    ) -> Vec<(usize, Option<Result<Option<String>>>)> {
+        let mut results = vec![];
+        let mut added = vec![];
+        for &index in indices {
+            let action = &self.actions[index];
+            if failed.contains(&action.file) {
+                results.push((index, None));
+                continue;
+            }
+            let ActionKind::Rule(processor, rule) = &action.kind else {
+                continue;
+            };
+            let prepared = action.create_directory(created)
+                .and_then(|_| processor.run_plugins(&config.rules[*rule], &config.plugins))
+                .and_then(|handled| match handled {
+                    true => Ok(None),
+                    false => archive_entry(&action.operation).map(Some),
+                });
+            match prepared {
+                Ok(Some(entry)) => added.push((index, entry)),
+                Ok(None) => results.push((index, Some(Ok(None)))),
+                Err(err) => {
+                    failed.insert(action.file.clone());
+                    results.push((index, Some(Err(err.context(FailedRule(action.operation.rule.clone()))))));
+                }
+            }
+        }
+        if added.is_empty() {
+            return results;
+        }
+
+        let entries = added.iter().map(|(_, entry)| entry.clone()).collect::<Vec<_>>();
+        let written = policy.add(&to_long_path(archive), &entries, &config.copy_options());
+        for (index, (source, _)) in added {
+            let action = &self.actions[index];
+            let result = match &written {
+                Ok(()) => remove_file(&source)
+                    .map_err(|err| anyhow!("Couldn't remove {} after archiving it: {}", action.operation.source.display(), err)),
+                Err(err) => Err(anyhow!("{:#}", err)),
+            };
+            if result.is_err() {
+                failed.insert(action.file.clone());
+            }
+            results.push((index, Some(result.map(|_| None).map_err(|err| err.context(FailedRule(action.operation.rule.clone()))))));
+        }
+        results
+    }
+
+    // Indices of the actions connected through shared paths, each group in planning order.
+    fn groups(&self) -> Vec<Vec<usize>> {
+        let mut parents = (0..self.actions.len()).collect::<Vec<_>>();
+        let mut owners: HashMap<&Path, usize> = HashMap::new();
+        for (index, action) in self.actions.iter().enumerate() {
+            for key in action.keys() {
+                match owners.get(key) {
+                    Some(&owner) => {
+                        let (first, second) = (find(&mut parents, owner), find(&mut parents, index));
+                        parents[second] = first;
+                    }
+                    None => {
+                        owners.insert(key, index);
+                    }
+                }
+            }
+        }
+
+        let mut groups: Vec<Vec<usize>> = vec![];
+        let mut group_of = HashMap::new();
+        for index in 0..self.actions.len() {
+            let root = find(&mut parents, index);
+            let group = *group_of.entry(root).or_insert_with(|| {
+                groups.push(vec![]);
+                groups.len() - 1
+            });
+            groups[group].push(index);
+        }
+        groups
+    }
+}
+
+/// The folder an operation moves its file into, which has to exist before it can run. Archives
+/// are the parent of their entries, the folder they're in is the one to create.
+pub(crate) fn target_directory(target: &Path, operation: OperationType) -> Option<&Path> {
+    let parent = target.parent();
+    match operation {
+        OperationType::Archive => parent.and_then(Path::parent),
+        _ => parent,
+    }
+}
+
+// The file to add and the name it's stored under in its archive.
+fn archive_entry(operation: &FileOperation) -> Result<(PathBuf, String)> {
+    let entry = operation.target.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(anyhow!("No filename found"))?;
+    Ok((to_long_path(&operation.source), entry.to_string()))
+}
+
+fn find(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+fn move_companion(operation: &FileOperation, config: &Config) -> Result<()> {
+    let copy_options = config.copy_options();
+    let (source, target) = (to_long_path(&operation.source), to_long_path(&operation.target));
+    match operation.operation {
+        OperationType::Copy => staged_copy(&source, &target, &copy_options),
+        _ => rename(&source, &target).and_then(|_| sync_parent(&target, &copy_options)),
+    }
+    .map_err(|err| anyhow!("Couldn't move {} to {}: {}", operation.source.display(), operation.target.display(), err))
+}
+
+// Names differing only in case are the same file on the usual Windows and macOS file systems.
+#[cfg(any(windows, target_os = "macos"))]
+fn name_key(name: &OsStr) -> OsString {
+    OsString::from(name.to_string_lossy().to_lowercase())
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn name_key(name: &OsStr) -> OsString {
+    name.to_os_string()
+}
@@ -0,0 +1,879 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{read_dir, remove_dir, remove_file, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+
+use colored::Colorize;
+
+use fs4::available_space;
+use glob::{glob, Pattern};
+use regex::RegexSet;
+
+use crate::directory::{directory_size, empty_directories, ignore_patterns};
+use crate::utils::{cached_regex, directory_range, next_free_name, SubstringRange, STAGING_EXTENSION};
+use crate::workflow::{ActionKind, ExecutionPlan, PlannedAction, UNMATCHED_RULE};
+use crate::{
+    validate_rule_attributes, ConfigurationError, Config, ExplainingObserver, FailedRule, MatchCheck, Ownership, Phase, ProcessingOrder, Processor, Rule, RunLock, StateStore, Timings,
+    UnmatchedFiles,
+};
+
+#[derive(Debug, Clone)]
+pub struct ProcessingOptions {
+    pub dry_run: bool,
+    /// Hold a lock file for the duration of the run so concurrent runs fail early.
+    pub use_lock: bool,
+    pub rule_filter: RuleFilter,
+    /// Process only these files or directories instead of scanning the download folder.
+    pub files: Option<Vec<PathBuf>>,
+    /// Print how long each phase of the run took.
+    pub timing: bool,
+    /// Print every rule checked against every file and why it did or didn't match.
+    pub explain_matches: bool,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        ProcessingOptions {
+            dry_run: false,
+            use_lock: true,
+            rule_filter: RuleFilter::default(),
+            files: None,
+            timing: false,
+            explain_matches: false,
+        }
+    }
+}
+
+impl ProcessingOptions {
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn use_lock(mut self, use_lock: bool) -> Self {
+        self.use_lock = use_lock;
+        self
+    }
+
+    pub fn timing(mut self, timing: bool) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn explain_matches(mut self, explain_matches: bool) -> Self {
+        self.explain_matches = explain_matches;
+        self
+    }
+
+    pub fn rule_filter(mut self, rule_filter: RuleFilter) -> Self {
+        self.rule_filter = rule_filter;
+        self
+    }
+
+    pub fn single_file<P: Into<PathBuf>>(self, file: P) -> Self {
+        self.files(vec![file.into()])
+    }
+
+    pub fn files(mut self, files: Vec<PathBuf>) -> Self {
+        self.files = Some(files);
+        self
+    }
+}
+
+/// Limits a run to a subset of the configured rules.
+///
+/// Empty lists don't restrict anything, so the default filter lets every rule through.
+#[derive(Debug, Clone, Default)]
+pub struct RuleFilter {
+    pub only_tags: Vec<String>,
+    pub skip_tags: Vec<String>,
+    pub only_rules: Vec<String>,
+}
+
+impl RuleFilter {
+    pub fn allows(&self, rule: &Rule) -> bool {
+        (self.only_tags.is_empty() || rule.tags.iter().any(|tag| self.only_tags.contains(tag)))
+            && !rule.tags.iter().any(|tag| self.skip_tags.contains(tag))
+            && (self.only_rules.is_empty() || self.only_rules.contains(&rule.title))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Move,
+    Copy,
+    /// Added to an archive, the original is removed.
+    Archive,
+    /// The destination already holds an identical file, so nothing was done.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileOperation {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub rule: String,
+    pub rule_id: Option<String>,
+    pub operation: OperationType,
+}
+
+/// Receives progress events while the workflow runs.
+///
+/// Every method has an empty default implementation, so implementors only need to
+/// override the events they care about.
+pub trait WorkflowObserver {
+    fn on_file_discovered(&mut self, _file: &Path) {}
+
+    /// Called for every condition of a rule checked against a file, whether it passed or not.
+    fn on_rule_checked(&mut self, _file: &Path, _rule: &Rule, _check: &MatchCheck) {}
+
+    fn on_rule_matched(&mut self, _file: &Path, _rule: &Rule) {}
+
+    fn on_operation_planned(&mut self, _operation: &FileOperation) {}
+
+    fn on_operation_completed(&mut self, _operation: &FileOperation) {}
+
+    fn on_error(&mut self, _file: &Path, _error: &anyhow::Error) {}
+}
+
+pub(crate) struct SilentObserver;
+
+impl WorkflowObserver for SilentObserver {}
+
+/// Keeps track of how much work a run has done against the configured limits.
+#[derive(Debug, Default)]
+pub struct RunBudget {
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+    files: usize,
+    bytes: u64,
+    rule_usage: HashMap<String, (usize, u64)>,
+    rule_matches: HashMap<String, usize>,
+    deferred: BTreeSet<PathBuf>,
+    limited: BTreeSet<PathBuf>,
+}
+
+impl RunBudget {
+    pub fn new(config: &Config) -> RunBudget {
+        RunBudget {
+            max_files: config.max_files_per_run,
+            max_bytes: config.max_bytes_per_run,
+            ..RunBudget::default()
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.max_files.is_some_and(|max_files| self.files >= max_files)
+    }
+
+    pub fn allows(&self, rule: &Rule, size: u64) -> bool {
+        let (rule_files, rule_bytes) = self.rule_usage.get(&rule.title).copied().unwrap_or_default();
+        !self.is_exhausted()
+            && self.max_bytes.is_none_or(|max_bytes| self.bytes + size <= max_bytes)
+            && rule.max_files_per_run.is_none_or(|max_files| rule_files < max_files)
+            && rule.max_bytes_per_run.is_none_or(|max_bytes| rule_bytes + size <= max_bytes)
+    }
+
+    pub fn consume(&mut self, rule: &Rule, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+        let usage = self.rule_usage.entry(rule.title.clone()).or_default();
+        usage.0 += 1;
+        usage.1 += size;
+    }
+
+    pub fn defer(&mut self, file: &Path) {
+        self.deferred.insert(file.to_path_buf());
+    }
+
+    /// Counts a match of the rule, or returns false when the rule's `max_matches_per_run` is
+    /// already used up and the file is to be left where it is.
+    pub fn record_match(&mut self, rule: &Rule, file: &Path) -> bool {
+        let matches = self.rule_matches.entry(rule.title.clone()).or_default();
+        if rule.max_matches_per_run.is_some_and(|max_matches| *matches >= max_matches) {
+            self.limited.insert(file.to_path_buf());
+            return false;
+        }
+        *matches += 1;
+        true
+    }
+
+    /// Whether a rule matched the file but had no matches left for this run.
+    pub fn is_limited(&self, file: &Path) -> bool {
+        self.limited.contains(file)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileFailure {
+    pub file: PathBuf,
+    pub rule: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug)]
+pub struct WorkflowContext {
+    pub config: Config,
+    pub options: ProcessingOptions,
+    pub operations: Vec<FileOperation>,
+    /// Files left for a later run because a `max_*_per_run` limit was reached or they were
+    /// still being written.
+    pub deferred: Vec<PathBuf>,
+    pub failures: Vec<FileFailure>,
+    /// Files skipped because they couldn't be read or their destination couldn't be written.
+    pub permission_denied: Vec<PathBuf>,
+    /// Files no rule matched, also the ones swept into the `unmatched` holding folder.
+    pub unmatched: Vec<PathBuf>,
+    /// Empty directories removed from the download folder, or that would be on a dry run.
+    pub removed_directories: Vec<PathBuf>,
+    pub timings: Timings,
+}
+
+impl WorkflowContext {
+    /// Whether the run found anything to move or copy, or at least tried to.
+    pub fn has_work(&self) -> bool {
+        !self.failures.is_empty()
+            || !self.permission_denied.is_empty()
+            || self.operations.iter().any(|operation| operation.operation != OperationType::Skipped)
+    }
+
+    pub fn print_error_summary(&self) {
+        if !self.failures.is_empty() {
+            println!("{}", format!("{} files failed:", self.failures.len()).bold().red());
+            for failure in &self.failures {
+                match &failure.rule {
+                    Some(rule) => println!("  {} [{}]: {}", failure.file.display(), rule, failure.error),
+                    None => println!("  {}: {}", failure.file.display(), failure.error),
+                }
+            }
+        }
+        if self.config.unmatched == UnmatchedFiles::Report && !self.unmatched.is_empty() {
+            println!("{}", format!("{} files matched no rule:", self.unmatched.len()).bold().yellow());
+            for file in &self.unmatched {
+                println!("  {}", file.display());
+            }
+        }
+        if !self.permission_denied.is_empty() {
+            println!("{}", format!("{} files skipped, permission denied:", self.permission_denied.len()).bold().red());
+            for file in &self.permission_denied {
+                println!("  {}", file.display());
+            }
+            println!("Make sure the user running fsort can read these files and write to their destination folders,");
+            println!("for example with `chmod u+rw <file>` or by running fsort as the owner of the files.");
+        }
+    }
+}
+
+/// Runs the whole sorting workflow against an already constructed configuration.
+///
+/// Nothing is read from disk apart from the download folder, so the configuration can be
+/// built in code with [`Config::new`] and [`Rule::new`].
+pub fn process_with_config(config: Config, options: ProcessingOptions) -> Result<WorkflowContext> {
+    process_with_observer(config, options, None)
+}
+
+/// Same as [`process_with_config`], but reports progress to the given observer.
+pub fn process_with_observer(
+    mut config: Config,
+    options: ProcessingOptions,
+    observer: Option<Box<dyn WorkflowObserver>>,
+) -> Result<WorkflowContext> {
+    let mut observer = observer.unwrap_or_else(|| Box::new(SilentObserver));
+    if options.explain_matches {
+        observer = Box::new(ExplainingObserver::new(observer));
+    }
+    let _lock = match options.use_lock {
+        true => Some(RunLock::acquire()?),
+        false => None,
+    };
+    let run_started = Local::now();
+    let mut timings = Timings::default();
+    let started = Instant::now();
+    let unreadable = prepare_configuration(&mut config, &options)?;
+    timings.record(Phase::Scan, started);
+
+    let mut context = WorkflowContext {
+        config,
+        options,
+        operations: vec![],
+        deferred: vec![],
+        failures: vec![],
+        permission_denied: unreadable,
+        unmatched: vec![],
+        removed_directories: vec![],
+        timings,
+    };
+    let mut budget = RunBudget::new(&context.config);
+    let mut plan = ExecutionPlan::default();
+
+    if context.options.use_lock && !context.options.dry_run {
+        remove_staging_leftovers(&context.config)?;
+    }
+
+    let mut state = match (&context.config.quarantine, context.options.dry_run) {
+        (Some(_), false) => Some(StateStore::load()?),
+        _ => None,
+    };
+
+    if !context.options.dry_run {
+        let files = context.config.files.iter()
+            .filter(|file| state.as_ref().is_none_or(|state| !state.is_quarantined(file)))
+            .collect::<Vec<_>>();
+        check_free_space(&context.config, &files)?;
+    }
+
+    let started = Instant::now();
+    let unsettled = match &context.config.stability_check {
+        Some(check) => check.unsettled(&context.config.files),
+        None => vec![],
+    };
+    context.timings.record(Phase::Scan, started);
+
+    // Operations are only kept once the file's whole plan has been carried out.
+    let mut planned = vec![];
+    let companions = context.config.companion_files().context(ConfigurationError)?;
+    for file in &context.config.files {
+        if companions.contains(file) {
+            continue;
+        }
+        if state.as_ref().is_some_and(|state| state.is_quarantined(file)) {
+            continue;
+        }
+        if budget.is_exhausted() {
+            budget.defer(file);
+            continue;
+        }
+        if unsettled.contains(file) {
+            info!("{} is still being written, deferred.", file.display().to_string().bold());
+            budget.defer(file);
+            continue;
+        }
+        if !is_readable(file) {
+            println!("{} {}: permission denied", "Skipped".bold().red(), file.display());
+            context.permission_denied.push(file.clone());
+            continue;
+        }
+        observer.on_file_discovered(file);
+        match context.config.process(file, context.options.dry_run, observer.as_mut(), &mut budget, &mut plan, &mut context.timings) {
+            Ok(operations) => {
+                if operations.is_empty() && !budget.deferred.contains(file) && !budget.is_limited(file) {
+                    context.unmatched.push(file.clone());
+                }
+                planned.push((file.clone(), operations));
+            }
+            Err(err) => report_failure(&mut context.failures, &mut context.permission_denied, &context.config, &mut state, file, err),
+        }
+    }
+
+    // Only files found in the download folder are swept, not the ones a command hands over
+    // such as those `reorganize` takes from the roots.
+    let sweep = match (&context.config.unmatched, &context.options.files) {
+        (UnmatchedFiles::MoveTo(directory), None) => Some(directory),
+        _ => None,
+    };
+    if let Some(directory) = sweep {
+        // The holding folder can be in the download folder or a root, neither it nor the files
+        // already in it are swept into it again.
+        for file in context.unmatched.iter().filter(|file| !directory.starts_with(file) && !file.starts_with(directory)) {
+            match plan_unmatched(&context.config, file, directory, context.options.dry_run, &mut plan) {
+                Ok(operation) => planned.push((file.clone(), vec![operation])),
+                Err(err) => {
+                    println!("{} {}: {:#}", "Failed".bold().red(), file.display(), err);
+                    context.failures.push(FileFailure {
+                        file: file.clone(),
+                        rule: None,
+                        error: format!("{:#}", err),
+                    });
+                }
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let sorted = plan.take_chained();
+    let failed = execute_plan(plan, &context.config, observer.as_mut(), &mut context.failures, &mut context.permission_denied, &mut state);
+    context.timings.record(Phase::Action, started);
+    for (file, operations) in planned.into_iter().filter(|(file, _)| !failed.contains(file)) {
+        if let Some(state) = &mut state {
+            state.clear_failures(&file);
+        }
+        context.operations.extend(operations);
+    }
+    let mut chained: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+    for (index, _, target) in sorted.into_iter().filter(|(_, file, _)| !failed.contains(file)) {
+        chained.entry(index).or_default().push(target);
+    }
+    let chained = chained.into_iter()
+        .filter_map(|(index, files)| {
+            let rule = &context.config.rules[index];
+            Some(ChainedStage { rule: rule.then.as_deref()?.clone(), files, dry_run: context.options.dry_run || rule.dry_run })
+        })
+        .collect();
+    run_chained_stages(&mut context, observer.as_mut(), chained)?;
+
+    if let Some(state) = &state {
+        state.save()?;
+    }
+
+    if context.config.cleanup_empty_dirs && context.config.download.is_dir() {
+        context.removed_directories = remove_empty_directories(&context)?;
+    }
+
+    context.deferred = budget.deferred.into_iter().collect();
+    if !context.deferred.is_empty() {
+        info!("{} files deferred to the next run.", context.deferred.len().to_string().bold());
+    }
+
+    if let (Some(manifest), false) = (&context.config.manifest, context.options.dry_run) {
+        match manifest.write(&context, run_started) {
+            Ok(path) => info!("Manifest written to {}", path.display()),
+            Err(err) => println!("{} {:#}", "Couldn't write the manifest:".bold().red(), err),
+        }
+    }
+
+    Ok(context)
+}
+
+// Carries out the plan and returns the files an operation of which failed.
+fn execute_plan(
+    plan: ExecutionPlan,
+    config: &Config,
+    observer: &mut dyn WorkflowObserver,
+    failures: &mut Vec<FileFailure>,
+    permission_denied: &mut Vec<PathBuf>,
+    state: &mut Option<StateStore>,
+) -> HashSet<PathBuf> {
+    let mut failed = HashSet::new();
+    for (action, result) in plan.execute(config) {
+        match result {
+            Some(Ok(warning)) => {
+                if let Some(warning) = warning {
+                    warning!("{}", warning);
+                }
+                observer.on_operation_completed(&action.operation);
+            }
+            Some(Err(err)) => {
+                observer.on_error(&action.file, &err);
+                report_failure(failures, permission_denied, config, state, &action.file, err);
+                failed.insert(action.file);
+            }
+            None => {}
+        }
+    }
+    failed
+}
+
+// The files a rule with `then` put in place, for the rule under it to sort further.
+struct ChainedStage {
+    rule: Rule,
+    files: Vec<PathBuf>,
+    /// Whether an earlier stage only reported what it would do, the files then aren't there yet.
+    dry_run: bool,
+}
+
+/// Sorts the files that rules with `then` put in place further. A stage is planned from where
+/// the one before it put the files, and carried out once that one has been. In dry runs the
+/// stage is planned from where the files would be. Failures of later stages leave the file
+/// where the earlier stage put it and aren't counted towards quarantining it.
+fn run_chained_stages(context: &mut WorkflowContext, observer: &mut dyn WorkflowObserver, mut pending: Vec<ChainedStage>) -> Result<()> {
+    while !pending.is_empty() {
+        let mut next = vec![];
+        for ChainedStage { rule, files, dry_run } in pending {
+            let stage = context.config.chained_stage(&rule);
+            let mut plan = ExecutionPlan::default();
+            let mut budget = RunBudget::default();
+            let mut planned = vec![];
+            for file in &files {
+                match stage.process(file, dry_run, observer, &mut budget, &mut plan, &mut context.timings) {
+                    Ok(operations) => planned.push((file.clone(), operations)),
+                    Err(err) => report_failure(&mut context.failures, &mut context.permission_denied, &stage, &mut None, file, err),
+                }
+            }
+            let started = Instant::now();
+            let sorted = plan.take_chained();
+            let failed = execute_plan(plan, &stage, observer, &mut context.failures, &mut context.permission_denied, &mut None);
+            context.timings.record(Phase::Action, started);
+            for (_, operations) in planned.into_iter().filter(|(file, _)| !failed.contains(file)) {
+                for operation in operations {
+                    merge_stage_operation(&mut context.operations, operation);
+                }
+            }
+            if let Some(then) = rule.then.as_deref() {
+                let files = sorted.into_iter()
+                    .filter(|(_, file, _)| !failed.contains(file))
+                    .map(|(_, _, target)| target)
+                    .collect::<Vec<_>>();
+                next.push(ChainedStage { rule: then.clone(), files, dry_run: dry_run || rule.dry_run });
+            }
+        }
+        pending = next;
+    }
+    Ok(())
+}
+
+// A file a later stage moved on is reported once, from where it was found to where it ended
+// up under the last rule, so the run's operations only point at files that are there. Copies
+// leave the earlier file in place and are reported on their own.
+fn merge_stage_operation(operations: &mut Vec<FileOperation>, operation: FileOperation) {
+    let earlier = operations.iter_mut()
+        .find(|earlier| earlier.operation != OperationType::Skipped && earlier.target == operation.source);
+    match (earlier, operation.operation) {
+        (_, OperationType::Skipped) => {}
+        (Some(earlier), OperationType::Move | OperationType::Archive) => {
+            if operation.operation == OperationType::Archive {
+                earlier.operation = OperationType::Archive;
+            }
+            earlier.target = operation.target;
+            earlier.rule = operation.rule;
+            earlier.rule_id = operation.rule_id;
+        }
+        _ => operations.push(operation),
+    }
+}
+
+// Planned like a rule's move, a taken name in the holding folder gets a counter.
+fn plan_unmatched(config: &Config, file: &Path, directory: &Path, dry_run: bool, plan: &mut ExecutionPlan) -> Result<FileOperation> {
+    let filename = file.file_name().ok_or(anyhow!("No filename found"))?;
+    let mut processor = Processor::new(file).with_copy_options(config.copy_options());
+    processor.target = directory.join(filename);
+    if plan.is_taken(&processor.target) {
+        processor.target = next_free_name(&processor.target, plan.targets(), config.max_filename_length)?;
+    }
+    info!("{} matched no rule, moving it to {}", file.display(), processor.target.display().to_string().bold());
+    let operation = FileOperation {
+        source: file.to_path_buf(),
+        target: processor.target.clone(),
+        rule: String::from(UNMATCHED_RULE),
+        rule_id: None,
+        operation: OperationType::Move,
+    };
+    plan.reserve(&operation.target);
+    if !dry_run {
+        plan.push(PlannedAction {
+            file: file.to_path_buf(),
+            operation: operation.clone(),
+            kind: ActionKind::Unmatched(processor),
+        });
+    }
+    Ok(operation)
+}
+
+// Files that can't be read or written are reported apart from other failures, which count
+// towards quarantining the file.
+fn report_failure(
+    failures: &mut Vec<FileFailure>,
+    permission_denied: &mut Vec<PathBuf>,
+    config: &Config,
+    state: &mut Option<StateStore>,
+    file: &Path,
+    err: anyhow::Error,
+) {
+    if is_permission_denied(&err) {
+        println!("{} {}: {:#}", "Skipped".bold().red(), file.display(), err);
+        permission_denied.push(file.to_path_buf());
+        return;
+    }
+    println!("{} {}: {:#}", "Failed".bold().red(), file.display(), err);
+    // Not being able to quarantine the file doesn't stop the rest of the run.
+    if let (Some(policy), Some(state)) = (&config.quarantine, state) {
+        if let Err(quarantine_err) = policy.handle_failure(state, file, &err) {
+            println!("{} {}: {:#}", "Couldn't quarantine".bold().yellow(), file.display(), quarantine_err);
+        }
+    }
+    let rule = err.downcast_ref::<FailedRule>().map(|failed_rule| failed_rule.0.clone());
+    let error = match rule {
+        Some(_) => err.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>().join(": "),
+        None => format!("{:#}", err),
+    };
+    failures.push(FileFailure {
+        file: file.to_path_buf(),
+        rule,
+        error,
+    });
+}
+
+fn is_readable(file: &Path) -> bool {
+    let result = match file.is_dir() {
+        true => read_dir(file).map(|_| ()),
+        false => File::open(file).map(|_| ()),
+    };
+    !result.is_err_and(|err| err.kind() == ErrorKind::PermissionDenied)
+}
+
+fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|cause| cause.kind() == ErrorKind::PermissionDenied)
+}
+
+/// Makes sure every destination file system has room for the files that are going to be copied.
+///
+/// Moves within a file system don't take up extra space, so only copies and checksum-verified
+/// transfers are counted. Files whose destination can't be worked out are left for the run
+/// itself to report.
+fn check_free_space(config: &Config, files: &[&PathBuf]) -> Result<()> {
+    let mut required: HashMap<String, (PathBuf, u64)> = HashMap::new();
+    for file in files {
+        let Some(filename) = file.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        for rule in config.rules.iter().filter(|rule| rule.copy || rule.checksum.is_some()) {
+            if file.is_dir() != rule.match_directories {
+                continue;
+            }
+            let Ok(Some(target)) = config.explain(rule, filename) else {
+                continue;
+            };
+            let size = match rule.match_directories {
+                true => directory_size(file)?,
+                false => file.metadata()?.len(),
+            };
+            let probe = existing_ancestor(&target);
+            required.entry(file_system_id(&probe)?).or_insert((probe, 0)).1 += size;
+        }
+    }
+
+    let mut shortfalls = vec![];
+    for (probe, size) in required.values() {
+        let available = available_space(probe)
+            .map_err(|err| anyhow!("Couldn't check free space on {}: {}", probe.display(), err))?;
+        if *size > available {
+            shortfalls.push(format!(
+                "{}: {} needed, {} available, {} short",
+                probe.display(), format_size(*size), format_size(available), format_size(size - available)
+            ));
+        }
+    }
+    if !shortfalls.is_empty() {
+        return Err(anyhow!("Not enough free space for the planned copies:\n  {}", shortfalls.join("\n  ")));
+    }
+    Ok(())
+}
+
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+#[cfg(unix)]
+fn file_system_id(path: &Path) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(path.metadata()?.dev().to_string())
+}
+
+#[cfg(not(unix))]
+fn file_system_id(path: &Path) -> Result<String> {
+    let prefix = path.components().next()
+        .ok_or(anyhow!("Couldn't find the drive of {}", path.display()))?;
+    Ok(prefix.as_os_str().to_string_lossy().to_uppercase())
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Deletes half-copied files left behind in rule destinations by an interrupted run.
+///
+/// Only called while the run lock is held, so no other run can be copying at the same time.
+fn remove_staging_leftovers(config: &Config) -> Result<()> {
+    let mut destinations = BTreeSet::new();
+    for rule in &config.rules {
+        let directory = rule.directory.clone().unwrap_or_else(|| PathBuf::from(&rule.title));
+        destinations.insert(config.root.resolve(&rule.root_reference())?.join(directory));
+    }
+    for destination in destinations.iter().filter(|destination| destination.is_dir()) {
+        let leftovers = PathBuf::from(Pattern::escape(&destination.to_string_lossy()))
+            .join("**")
+            .join(format!("*.{}", STAGING_EXTENSION));
+        for leftover in glob(&leftovers.to_string_lossy())?.flatten() {
+            info!("Removing unfinished copy {}", leftover.display());
+            remove_file(&leftover)
+                .map_err(|err| anyhow!("Couldn't remove {}: {}", leftover.display(), err))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_empty_directories(context: &WorkflowContext) -> Result<Vec<PathBuf>> {
+    // Nothing was moved on a dry run, so the planned moves are counted as gone already.
+    let moved = match context.options.dry_run {
+        true => context.operations.iter()
+            .filter(|operation| matches!(operation.operation, OperationType::Move | OperationType::Archive))
+            .map(|operation| operation.source.clone())
+            .collect(),
+        false => HashSet::new(),
+    };
+    let ignored = ignore_patterns(&context.config.download)?;
+    let directories = empty_directories(&context.config.download, &moved, &ignored, context.config.include_hidden)?;
+    if !context.options.dry_run {
+        for directory in &directories {
+            remove_dir(directory)
+                .map_err(|err| anyhow!("Couldn't remove {}: {}", directory.display(), err))?;
+        }
+    }
+    if !directories.is_empty() {
+        let verb = if context.options.dry_run { "Would remove" } else { "Removed" };
+        info!("{} {} empty directories.", verb, directories.len().to_string().bold());
+        for directory in &directories {
+            info!("  {}", directory.display());
+        }
+    }
+    Ok(directories)
+}
+
+// Returns the files in the download folder that couldn't be read.
+fn prepare_configuration(configuration: &mut Config, options: &ProcessingOptions) -> Result<Vec<PathBuf>> {
+    let unreadable = match &options.files {
+        Some(files) => {
+            if let Some(missing) = files.iter().find(|file| !file.exists()) {
+                return Err(anyhow!("Couldn't find {}", missing.display()));
+            }
+            configuration.files = files.clone();
+            vec![]
+        }
+        None => configuration.get_files()
+            .map_err(|err| anyhow!("Couldn't read the download folder: {}", err))
+            .context(ConfigurationError)?,
+    };
+
+    if let Some(order) = configuration.processing_order {
+        sort_files(&mut configuration.files, order);
+    }
+
+    // Rules left out of this run are still checked, so a broken rule doesn't go unnoticed
+    // until the day it becomes active.
+    prepare_rules(configuration).context(ConfigurationError)?;
+
+    let today = Local::now().date_naive();
+    if options.explain_matches {
+        for rule in &configuration.rules {
+            if !rule.is_active(today) {
+                println!("{} is inactive and isn't checked", rule.title.blue());
+            } else if !options.rule_filter.allows(rule) {
+                println!("{} is filtered out of this run and isn't checked", rule.title.blue());
+            }
+        }
+    }
+    configuration.rules.retain(|rule| rule.is_active(today) && options.rule_filter.allows(rule));
+    configuration.rule_patterns = Some(rule_pattern_set(configuration)?);
+    Ok(unreadable)
+}
+
+fn sort_files(files: &mut [PathBuf], order: ProcessingOrder) {
+    let modified = |file: &PathBuf| file.metadata().and_then(|metadata| metadata.modified()).ok();
+    let size = |file: &PathBuf| file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+    files.sort_by(|first, second| first.file_name().cmp(&second.file_name()));
+    match order {
+        ProcessingOrder::Name => {}
+        ProcessingOrder::MtimeAsc => files.sort_by_cached_key(modified),
+        ProcessingOrder::MtimeDesc => files.sort_by_cached_key(|file| Reverse(modified(file))),
+        ProcessingOrder::SizeDesc => files.sort_by_cached_key(|file| Reverse(size(file))),
+    }
+}
+
+// Resolves links and `..` as far as the path exists, so nesting is checked on the real folders.
+fn normalized(path: &Path) -> PathBuf {
+    let existing = existing_ancestor(path);
+    match existing.canonicalize() {
+        Ok(canonical) => match path.strip_prefix(&existing) {
+            Ok(rest) if !rest.as_os_str().is_empty() => canonical.join(rest),
+            _ => canonical,
+        },
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Builds the match patterns of every rule and checks that their settings are usable.
+pub fn prepare_rules(configuration: &mut Config) -> Result<()> {
+    for mapping in &mut configuration.rules {
+        prepare_stages(mapping)?;
+    }
+    configuration.rule_patterns = Some(rule_pattern_set(configuration)?);
+
+    validate_rules(configuration)
+}
+
+fn rule_pattern_set(configuration: &Config) -> Result<RegexSet> {
+    Ok(RegexSet::new(configuration.rules.iter().map(|rule| &rule.old_pattern))?)
+}
+
+// Rules chained with `then` take their parent's title and root unless they have their own.
+fn prepare_stages(rule: &mut Rule) -> Result<()> {
+    rule.make_patterns()?;
+    cached_regex(&rule.old_pattern)?;
+    rule.ownership = Ownership::from_rule(rule).map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+    let (title, root) = (rule.title.clone(), rule.root.clone());
+    if let Some(chained) = rule.then.as_deref_mut() {
+        if chained.title.is_empty() {
+            chained.title = format!("{} (then)", title);
+        }
+        if chained.root.is_none() {
+            chained.root = root;
+        }
+        prepare_stages(chained)?;
+    }
+    Ok(())
+}
+
+fn validate_rules(configuration: &Config) -> Result<()> {
+    let roots = configuration.root.0.iter().map(|root| normalized(&root.path)).collect::<Vec<_>>();
+    for (idx, root) in roots.iter().enumerate() {
+        if let Some(outer) = roots.iter().enumerate().find(|(other, outer)| *other != idx && root.starts_with(outer)) {
+            return Err(anyhow!("Root {} is inside root {}", root.display(), outer.1.display()));
+        }
+    }
+
+    let download = normalized(&configuration.download);
+    for rule in configuration.rules.iter().flat_map(Rule::stages) {
+        configuration.plugins.validate(rule).map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+        validate_rule_attributes(rule).map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+        if let Some(range) = rule.directory.as_ref().and_then(|directory| directory_range(directory.to_str()?)) {
+            range.parse::<SubstringRange>().map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+        }
+        if let Some(function) = &rule.function {
+            function.validate().map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+        }
+        if rule.match_directories && rule.checksum.is_some() {
+            return Err(anyhow!("Rule '{}': checksums can't be used with match_directories", rule.title));
+        }
+        if rule.archive.is_some() {
+            let conflicting = [
+                ("match_directories", rule.match_directories),
+                ("copy", rule.copy),
+                ("checksum", rule.checksum.is_some()),
+                ("companions", !rule.companions.is_empty()),
+                ("sidecar", rule.sidecar.is_some()),
+                ("max_files_per_directory", rule.max_files_per_directory.is_some()),
+                ("then", rule.then.is_some()),
+            ];
+            if let Some((key, _)) = conflicting.iter().find(|(_, is_set)| *is_set) {
+                return Err(anyhow!("Rule '{}': archive can't be used with {}", rule.title, key));
+            }
+        }
+        let root = configuration.root.resolve(&rule.root_reference())
+            .map_err(|err| anyhow!("Rule '{}': {}", rule.title, err))?;
+        let destination = normalized(&root.join(rule.directory.clone().unwrap_or_else(|| PathBuf::from(&rule.title))));
+        if destination.starts_with(&download) {
+            return Err(anyhow!(
+                "Rule '{}': its destination {} is inside the download folder {}",
+                rule.title, destination.display(), download.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
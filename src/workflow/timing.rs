@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+
+const SLOWEST_RULES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Reading the download folder and ordering the files.
+    Scan,
+    /// Testing rule patterns and plugin match hooks against filenames.
+    Match,
+    /// Building the destination: folders, processors, renaming, sharding and conflicts.
+    PathGeneration,
+    /// Moving, copying and checking the files.
+    Action,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [Phase::Scan, Phase::Match, Phase::PathGeneration, Phase::Action];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Scan => "scan",
+            Phase::Match => "match",
+            Phase::PathGeneration => "path generation",
+            Phase::Action => "action",
+        }
+    }
+}
+
+/// Time a run spent in each phase, and in matching each rule.
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    pub phases: HashMap<Phase, Duration>,
+    pub rule_matching: HashMap<String, Duration>,
+}
+
+impl Timings {
+    pub fn record(&mut self, phase: Phase, started: Instant) {
+        *self.phases.entry(phase).or_default() += started.elapsed();
+    }
+
+    pub fn record_match(&mut self, rule: &str, started: Instant) {
+        let elapsed = started.elapsed();
+        *self.phases.entry(Phase::Match).or_default() += elapsed;
+        *self.rule_matching.entry(rule.to_string()).or_default() += elapsed;
+    }
+
+    pub fn print(&self) {
+        println!("{}", "Timing:".bold());
+        for phase in Phase::ALL {
+            println!("  {:<16} {:>10.3?}", phase.name(), self.phases.get(&phase).copied().unwrap_or_default());
+        }
+        println!("  {:<16} {:>10.3?}", "total", self.phases.values().sum::<Duration>());
+
+        let mut rules = self.rule_matching.iter().collect::<Vec<_>>();
+        rules.sort_by(|(first_rule, first), (second_rule, second)| second.cmp(first).then(first_rule.cmp(second_rule)));
+        if !rules.is_empty() {
+            println!("{}", "Slowest rules to match:".bold());
+        }
+        for (rule, duration) in rules.into_iter().take(SLOWEST_RULES) {
+            println!("  {:>10.3?}  {}", duration, rule);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use colored::Colorize;
+
+use crate::{FileOperation, Rule, WorkflowObserver};
+
+/// One condition a rule checked against a file, such as its pattern or a sidecar field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchCheck {
+    pub condition: &'static str,
+    pub passed: bool,
+    /// What was compared, such as the pattern and the filename.
+    pub detail: String,
+}
+
+impl MatchCheck {
+    pub fn new<S: Into<String>>(condition: &'static str, passed: bool, detail: S) -> MatchCheck {
+        MatchCheck {
+            condition,
+            passed,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Prints every rule checked against every file for `--explain-matches`, passing all events
+/// on to the observer it wraps.
+pub struct ExplainingObserver {
+    inner: Box<dyn WorkflowObserver>,
+}
+
+impl ExplainingObserver {
+    pub fn new(inner: Box<dyn WorkflowObserver>) -> ExplainingObserver {
+        ExplainingObserver { inner }
+    }
+}
+
+impl WorkflowObserver for ExplainingObserver {
+    fn on_file_discovered(&mut self, file: &Path) {
+        println!("{} {}", "Checking".bold(), file.display());
+        self.inner.on_file_discovered(file);
+    }
+
+    fn on_rule_checked(&mut self, file: &Path, rule: &Rule, check: &MatchCheck) {
+        let result = match check.passed {
+            true => "passed".green(),
+            false => "failed".red(),
+        };
+        println!("  {} {} {}: {}", rule.title.blue(), check.condition, result, check.detail);
+        self.inner.on_rule_checked(file, rule, check);
+    }
+
+    fn on_rule_matched(&mut self, file: &Path, rule: &Rule) {
+        self.inner.on_rule_matched(file, rule);
+    }
+
+    fn on_operation_planned(&mut self, operation: &FileOperation) {
+        self.inner.on_operation_planned(operation);
+    }
+
+    fn on_operation_completed(&mut self, operation: &FileOperation) {
+        self.inner.on_operation_completed(operation);
+    }
+
+    fn on_error(&mut self, file: &Path, error: &anyhow::Error) {
+        self.inner.on_error(file, error);
+    }
+}